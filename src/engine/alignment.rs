@@ -0,0 +1,416 @@
+use crate::engine::{WarpMarker, fft::Fft};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Alignment {
+    pub offset_frames: i64,
+    pub warp_markers: Vec<WarpMarker>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TakeAligner {
+    pub frame_size: usize,
+    pub hop_size: usize,
+    pub bands: usize,
+    pub max_offset_seconds: f64,
+    pub max_warp_seconds: f64,
+    pub marker_spacing_seconds: f64,
+}
+
+impl Default for TakeAligner {
+    fn default() -> Self {
+        TakeAligner {
+            frame_size: 1024,
+            hop_size: 256,
+            bands: 24,
+            max_offset_seconds: 0.5,
+            max_warp_seconds: 0.15,
+            marker_spacing_seconds: 0.05,
+        }
+    }
+}
+
+impl TakeAligner {
+    const LOWEST_BAND_FREQUENCY: f64 = 80.0;
+    const HIGHEST_BAND_FREQUENCY: f64 = 8000.0;
+    // Keeps the path on the diagonal where both sides look alike, e.g. shared silence
+    const STEP_PENALTY: f64 = 0.05;
+
+    // Both inputs are mono and start where their clips sit on the timeline. The take is
+    // first shifted by the best global offset, then warped by DTW within a narrow band.
+    pub fn align(
+        &self,
+        reference: &[f64],
+        reference_start: u64,
+        take: &[f64],
+        take_start: u64,
+        sample_rate: u32,
+    ) -> Alignment {
+        let hop = self.hop_size.max(1);
+        let reference_features = self.features(reference, sample_rate);
+        let take_features = self.features(take, sample_rate);
+
+        let Some(lag) = self.global_lag(
+            &reference_features,
+            &take_features,
+            (take_start as f64 - reference_start as f64) / hop as f64,
+            sample_rate,
+        ) else {
+            return Alignment {
+                offset_frames: 0,
+                warp_markers: Vec::new(),
+            };
+        };
+
+        let new_start = (reference_start as i64 + lag * hop as i64).max(0);
+        let warp_markers = self
+            .warp_path(&reference_features, &take_features, lag, sample_rate)
+            .map(|path| self.markers(&path, new_start - reference_start as i64, sample_rate))
+            .unwrap_or_default();
+
+        Alignment {
+            offset_frames: new_start - take_start as i64,
+            warp_markers,
+        }
+    }
+
+    // Log energies in log-spaced bands, normalised so that level differences between
+    // takes don't matter. Silent frames stay all zero.
+    fn features(&self, samples: &[f64], sample_rate: u32) -> Vec<Vec<f64>> {
+        let fft = Fft::new(self.frame_size.next_power_of_two());
+        let window = Fft::hann_window(fft.size());
+        let hop = self.hop_size.max(1);
+        let bin_width = sample_rate as f64 / fft.size() as f64;
+
+        let highest = Self::HIGHEST_BAND_FREQUENCY.min(sample_rate as f64 / 2.0);
+        let edges: Vec<usize> = (0..=self.bands)
+            .map(|band| {
+                let position = band as f64 / self.bands as f64;
+                let frequency = Self::LOWEST_BAND_FREQUENCY
+                    * (highest / Self::LOWEST_BAND_FREQUENCY).powf(position);
+                ((frequency / bin_width).round() as usize).min(fft.size() / 2)
+            })
+            .collect();
+
+        (0..samples.len().div_ceil(hop))
+            .map(|frame| {
+                let start = frame * hop;
+                let end = (start + fft.size()).min(samples.len());
+                let magnitudes = fft.magnitudes(&samples[start..end], &window);
+
+                let mut feature: Vec<f64> = edges
+                    .windows(2)
+                    .map(|edge| {
+                        let energy: f64 = magnitudes[edge[0]..edge[1].max(edge[0] + 1)]
+                            .iter()
+                            .map(|m| m * m)
+                            .sum();
+                        (1.0 + energy).ln()
+                    })
+                    .collect();
+
+                let norm = feature.iter().map(|f| f * f).sum::<f64>().sqrt();
+                if norm > 1e-9 {
+                    feature.iter_mut().for_each(|f| *f /= norm);
+                }
+                feature
+            })
+            .collect()
+    }
+
+    fn distance(a: &[f64], b: &[f64]) -> f64 {
+        let silent = |feature: &[f64]| feature.iter().all(|f| *f == 0.0);
+
+        match (silent(a), silent(b)) {
+            (true, true) => 0.0,
+            (true, false) | (false, true) => 1.0,
+            (false, false) => 1.0 - a.iter().zip(b).map(|(a, b)| a * b).sum::<f64>(),
+        }
+    }
+
+    // Lag in hops such that take frame `i` sounds together with reference frame `i + lag`
+    fn global_lag(
+        &self,
+        reference: &[Vec<f64>],
+        take: &[Vec<f64>],
+        current_lag: f64,
+        sample_rate: u32,
+    ) -> Option<i64> {
+        let hop = self.hop_size.max(1) as f64;
+        let search = (self.max_offset_seconds * sample_rate as f64 / hop).round() as i64;
+        let current_lag = current_lag.round() as i64;
+
+        (current_lag - search..=current_lag + search)
+            .filter_map(|lag| {
+                let (total, count) = take
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(i, take_frame)| {
+                        let j = usize::try_from(i as i64 + lag).ok()?;
+                        let reference_frame = reference.get(j)?;
+                        let both_silent =
+                            take_frame.iter().chain(reference_frame).all(|f| *f == 0.0);
+                        (!both_silent).then(|| 1.0 - Self::distance(take_frame, reference_frame))
+                    })
+                    .fold((0.0, 0usize), |(total, count), similarity| {
+                        (total + similarity, count + 1)
+                    });
+
+                (count > 0).then(|| (lag, total / count as f64))
+            })
+            .max_by(|a, b| {
+                a.1.total_cmp(&b.1)
+                    .then_with(|| (b.0 - current_lag).abs().cmp(&(a.0 - current_lag).abs()))
+            })
+            .map(|(lag, _)| lag)
+    }
+
+    // DTW restricted to a band around the diagonal `j = i + lag`. Returns, for each take
+    // frame on the path, the (average) reference frame it lines up with.
+    fn warp_path(
+        &self,
+        reference: &[Vec<f64>],
+        take: &[Vec<f64>],
+        lag: i64,
+        sample_rate: u32,
+    ) -> Option<Vec<(usize, f64)>> {
+        let hop = self.hop_size.max(1) as f64;
+        let band = (self.max_warp_seconds * sample_rate as f64 / hop)
+            .round()
+            .max(1.0) as i64;
+        let width = (2 * band + 1) as usize;
+
+        let first = (-(lag + band)).max(0) as usize;
+        let last = (reference.len() as i64 - lag + band).min(take.len() as i64);
+        if last <= first as i64 {
+            return None;
+        }
+        let rows = last as usize - first;
+
+        let reference_index = |row: usize, column: usize| -> Option<usize> {
+            let j = (first + row) as i64 + lag + column as i64 - band;
+            usize::try_from(j).ok().filter(|j| *j < reference.len())
+        };
+
+        let mut cost = vec![f64::INFINITY; rows * width];
+        for row in 0..rows {
+            for column in 0..width {
+                let Some(j) = reference_index(row, column) else {
+                    continue;
+                };
+                let local = Self::distance(&take[first + row], &reference[j]);
+
+                // Free start anywhere in the band
+                let best_previous = if row == 0 {
+                    0.0
+                } else {
+                    let diagonal = cost[(row - 1) * width + column];
+                    let up = cost
+                        .get((row - 1) * width + column + 1)
+                        .filter(|_| column + 1 < width)
+                        .map_or(f64::INFINITY, |c| c + Self::STEP_PENALTY);
+                    let left = column.checked_sub(1).map_or(f64::INFINITY, |left| {
+                        cost[row * width + left] + Self::STEP_PENALTY
+                    });
+                    diagonal.min(up).min(left)
+                };
+
+                cost[row * width + column] = local + best_previous;
+            }
+        }
+
+        let (mut row, mut column) = (rows - 1, Self::argmin(&cost[(rows - 1) * width..])?);
+        let mut path = vec![(row, column)];
+
+        while row > 0 {
+            let candidates = [
+                Some((row - 1, column, cost[(row - 1) * width + column])),
+                (column + 1 < width)
+                    .then(|| (row - 1, column + 1, cost[(row - 1) * width + column + 1])),
+                (column > 0).then(|| (row, column - 1, cost[row * width + column - 1])),
+            ];
+            let Some((previous_row, previous_column, _)) = candidates
+                .into_iter()
+                .flatten()
+                .filter(|(_, _, cost)| cost.is_finite())
+                .min_by(|a, b| a.2.total_cmp(&b.2))
+            else {
+                break;
+            };
+            row = previous_row;
+            column = previous_column;
+            path.push((row, column));
+        }
+        path.reverse();
+
+        let mut averaged: Vec<(usize, f64, usize)> = Vec::new();
+        for (row, column) in path {
+            let j = reference_index(row, column)? as f64;
+            match averaged.last_mut() {
+                Some((last_row, sum, count)) if *last_row == first + row => {
+                    *sum += j;
+                    *count += 1;
+                }
+                _ => averaged.push((first + row, j, 1)),
+            }
+        }
+
+        Some(
+            averaged
+                .into_iter()
+                .map(|(i, sum, count)| (i, sum / count as f64))
+                .collect(),
+        )
+    }
+
+    fn argmin(values: &[f64]) -> Option<usize> {
+        values
+            .iter()
+            .enumerate()
+            .filter(|(_, value)| value.is_finite())
+            .min_by(|a, b| a.1.total_cmp(b.1))
+            .map(|(index, _)| index)
+    }
+
+    // `origin` is where the moved take starts, in frames from the start of the reference
+    fn markers(&self, path: &[(usize, f64)], origin: i64, sample_rate: u32) -> Vec<WarpMarker> {
+        let hop = self.hop_size.max(1);
+        let spacing = ((self.marker_spacing_seconds * sample_rate as f64 / hop as f64).round()
+            as usize)
+            .max(1);
+
+        let mut markers: Vec<WarpMarker> = Vec::new();
+        let last = path.len().saturating_sub(1);
+
+        for (index, &(i, j)) in path.iter().enumerate() {
+            if index % spacing != 0 && index != last {
+                continue;
+            }
+
+            let timeline = j * hop as f64 - origin as f64;
+            if timeline < 0.0 {
+                continue;
+            }
+
+            let marker = WarpMarker {
+                source_frame: (i * hop) as u64,
+                timeline_frame: timeline.round() as u64,
+            };
+            let increasing = markers.last().is_none_or(|previous| {
+                marker.source_frame > previous.source_frame
+                    && marker.timeline_frame > previous.timeline_frame
+            });
+            if increasing {
+                markers.push(marker);
+            }
+        }
+
+        markers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 44100;
+
+    // Sung syllables at different pitches, each with a short fade in and out
+    fn phrase(syllables: &[(f64, f64, f64)], seconds: f64) -> Vec<f64> {
+        let frames = (seconds * SAMPLE_RATE as f64) as usize;
+        (0..frames)
+            .map(|i| {
+                let t = i as f64 / SAMPLE_RATE as f64;
+                syllables
+                    .iter()
+                    .filter(|(start, end, _)| t >= *start && t < *end)
+                    .map(|(start, end, frequency)| {
+                        let envelope = ((t - start) / 0.01).min((end - t) / 0.01).min(1.0);
+                        (1..=3)
+                            .map(|harmonic| {
+                                let harmonic = harmonic as f64;
+                                0.3 / harmonic
+                                    * (2.0 * std::f64::consts::PI * frequency * harmonic * t).sin()
+                            })
+                            .sum::<f64>()
+                            * envelope
+                    })
+                    .sum()
+            })
+            .collect()
+    }
+
+    fn seconds_to_frames(seconds: f64) -> f64 {
+        seconds * SAMPLE_RATE as f64
+    }
+
+    #[test]
+    fn test_global_offset() {
+        let syllables = [(0.05, 0.25, 220.0), (0.3, 0.5, 330.0), (0.55, 0.8, 262.0)];
+        let reference = phrase(&syllables, 1.0);
+        let late = syllables.map(|(start, end, frequency)| (start + 0.1, end + 0.1, frequency));
+        let take = phrase(&late, 1.1);
+
+        let alignment = TakeAligner::default().align(&reference, 44100, &take, 44100, SAMPLE_RATE);
+
+        let expected = -seconds_to_frames(0.1);
+        assert!(
+            (alignment.offset_frames as f64 - expected).abs() <= 256.0,
+            "{}",
+            alignment.offset_frames
+        );
+    }
+
+    #[test]
+    fn test_offset_follows_timeline_positions() {
+        let syllables = [(0.05, 0.25, 220.0), (0.3, 0.5, 330.0)];
+        let reference = phrase(&syllables, 0.6);
+
+        let alignment =
+            TakeAligner::default().align(&reference, 10_000, &reference, 20_000, SAMPLE_RATE);
+
+        assert!((alignment.offset_frames + 10_000).abs() <= 256);
+    }
+
+    #[test]
+    fn test_warp_markers_follow_late_syllable() {
+        let reference = phrase(&[(0.05, 0.3, 220.0), (0.4, 0.7, 330.0)], 0.8);
+        let take = phrase(&[(0.05, 0.3, 220.0), (0.45, 0.75, 330.0)], 0.8);
+
+        let alignment = TakeAligner::default().align(&reference, 0, &take, 0, SAMPLE_RATE);
+        let start = alignment.offset_frames as f64;
+
+        assert!(!alignment.warp_markers.is_empty());
+        for pair in alignment.warp_markers.windows(2) {
+            assert!(pair[1].source_frame > pair[0].source_frame);
+            assert!(pair[1].timeline_frame > pair[0].timeline_frame);
+        }
+
+        let checked: Vec<&WarpMarker> = alignment
+            .warp_markers
+            .iter()
+            .filter(|marker| {
+                let source = marker.source_frame as f64;
+                source > seconds_to_frames(0.5) && source < seconds_to_frames(0.7)
+            })
+            .collect();
+        assert!(!checked.is_empty());
+
+        for marker in checked {
+            let aligned = start + marker.timeline_frame as f64;
+            let expected = marker.source_frame as f64 - seconds_to_frames(0.05);
+            assert!(
+                (aligned - expected).abs() <= 2.0 * 256.0,
+                "{marker:?} landed at {aligned}, expected {expected}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_silence_only_offsets_nothing() {
+        let silence = vec![0.0; 4410];
+
+        let alignment = TakeAligner::default().align(&silence, 0, &silence, 1000, SAMPLE_RATE);
+
+        assert_eq!(alignment.offset_frames, 0);
+    }
+}
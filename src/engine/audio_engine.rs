@@ -1,28 +1,85 @@
-use cpal::{
-    BuildStreamError, Device, SampleFormat, SampleRate, Stream, StreamError, SupportedStreamConfig,
-    traits::{DeviceTrait, HostTrait, StreamTrait},
+use cpal::{SampleFormat, SampleRate};
+
+use crate::engine::{
+    Backend, ConfigRange, ConfigRequest, CpalBackend, DeviceInfo, LatencyMeter, LatencyProfile,
+    PitchCorrection, Recording, StreamSettings, Timeline, TrackId, TransportCommand,
+    TransportEvent, TransportState, error::AudioError,
+};
+use std::{
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+        mpsc::{Receiver, Sender},
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
 };
 
-use crate::engine::{Timeline, error::AudioError};
-use std::sync::{Arc, Mutex};
+// Allocates the chunks a recording is written into while it runs, so the input callback
+// never has to
+struct ChunkFeeder {
+    running: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
 
-pub struct AudioEngine {
-    timeline: Arc<Mutex<Timeline>>,
-    input_device: Option<Device>,
-    output_device: Option<Device>,
-    output_stream: Option<Stream>,
-    input_stream: Option<Stream>,
-    config: SupportedStreamConfig,
+impl ChunkFeeder {
+    const INTERVAL: Duration = Duration::from_millis(100);
+
+    fn spawn(timeline: Arc<Mutex<Timeline>>) -> Self {
+        let running = Arc::new(AtomicBool::new(true));
+        let thread = {
+            let running = running.clone();
+            thread::spawn(move || {
+                while running.load(Ordering::Acquire) {
+                    let Ok(needed) = timeline
+                        .lock()
+                        .map(|timeline| timeline.recording_chunks_needed())
+                    else {
+                        break;
+                    };
+                    // Allocated without the lock held, so the callbacks keep running
+                    if let Some((count, samples)) = needed {
+                        let chunks = (0..count)
+                            .map(|_| Recording::allocate_chunk(samples))
+                            .collect();
+                        let Ok(mut timeline) = timeline.lock() else {
+                            break;
+                        };
+                        timeline.add_recording_chunks(chunks);
+                    }
+                    thread::park_timeout(Self::INTERVAL);
+                }
+            })
+        };
+
+        ChunkFeeder {
+            running,
+            thread: Some(thread),
+        }
+    }
 }
 
-macro_rules! create_output_callback {
-    ($type:ty, $timeline:expr, $channels:expr) => {
-        move |data: &mut [$type], _| {
-            if let Ok(mut timeline) = $timeline.lock() {
-                timeline.process(data, $channels);
-            }
+impl Drop for ChunkFeeder {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Release);
+        if let Some(thread) = self.thread.take() {
+            thread.thread().unpark();
+            let _ = thread.join();
         }
-    };
+    }
+}
+
+pub struct AudioEngine<B: Backend = CpalBackend> {
+    backend: B,
+    timeline: Arc<Mutex<Timeline>>,
+    transport: Sender<TransportCommand>,
+    request: ConfigRequest,
+    // None takes as many input channels as the device has
+    input_channels: Option<u16>,
+    settings: StreamSettings,
+    latency: Arc<LatencyMeter>,
+    monitoring: bool,
+    feeder: Option<ChunkFeeder>,
 }
 
 impl AudioEngine {
@@ -31,150 +88,447 @@ impl AudioEngine {
         sample_format: SampleFormat,
         sample_rate: SampleRate,
     ) -> Result<Self, AudioError> {
-        let host = cpal::default_host();
-        let output_device = host.default_output_device().unwrap();
-        let input_device = host.default_input_device().unwrap();
+        Self::with_backend(CpalBackend::new()?, channels, sample_format, sample_rate)
+    }
+}
 
-        let mut supported_output_configs = output_device.supported_output_configs().unwrap();
+impl<B: Backend> AudioEngine<B> {
+    const FADE_OUT_POLL: Duration = Duration::from_millis(1);
+    // Longer than any buffer, in case the callbacks have stalled
+    const FADE_OUT_TIMEOUT: Duration = Duration::from_millis(500);
 
-        let supported_config = supported_output_configs.find(|c| {
-            c.channels() == channels
-                && c.sample_format() == sample_format
-                && c.min_sample_rate() < sample_rate
-                && c.max_sample_rate() > sample_rate
-        });
+    pub fn with_backend(
+        backend: B,
+        channels: u16,
+        sample_format: SampleFormat,
+        sample_rate: SampleRate,
+    ) -> Result<Self, AudioError> {
+        let request = ConfigRequest {
+            channels,
+            sample_format,
+            sample_rate: sample_rate.0,
+            buffer_size: None,
+        };
+        let settings = Self::output_settings(&backend, &request)?;
 
-        let config = supported_config
-            .expect("Config is not supported")
-            .with_sample_rate(sample_rate);
+        let timeline = Timeline::new(settings.sample_rate);
 
         Ok(AudioEngine {
-            timeline: Arc::new(Mutex::new(Timeline::new(sample_rate.0))),
-            output_device: Some(output_device),
-            input_device: Some(input_device),
-            output_stream: None,
-            input_stream: None,
-            config,
+            backend,
+            transport: timeline.transport_sender(),
+            timeline: Arc::new(Mutex::new(timeline)),
+            request,
+            input_channels: None,
+            settings,
+            latency: Arc::default(),
+            monitoring: false,
+            feeder: None,
         })
     }
 
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+
+    pub fn backend_mut(&mut self) -> &mut B {
+        &mut self.backend
+    }
+
     pub fn start_playing(&mut self) -> Result<(), AudioError> {
-        let output_device = self
-            .output_device
-            .as_ref()
-            .ok_or(AudioError::OutputDeviceNotFound)?;
-
-        let timeline_clone = self.timeline.clone();
-        let config = self.config.config();
-        let channels = config.channels;
-
-        let stream = match self.config.sample_format() {
-            SampleFormat::U8 => output_device.build_output_stream(
-                &config,
-                create_output_callback!(u8, timeline_clone, channels),
-                Self::error_callback,
-                None,
-            ),
-            SampleFormat::I16 => output_device.build_output_stream(
-                &config,
-                create_output_callback!(i16, timeline_clone, channels),
-                Self::error_callback,
-                None,
-            ),
-            SampleFormat::I32 => output_device.build_output_stream(
-                &config,
-                create_output_callback!(i32, timeline_clone, channels),
-                Self::error_callback,
-                None,
-            ),
-            SampleFormat::F32 => output_device.build_output_stream(
-                &config,
-                create_output_callback!(f32, timeline_clone, channels),
-                Self::error_callback,
-                None,
-            ),
-            SampleFormat::F64 => output_device.build_output_stream(
-                &config,
-                create_output_callback!(f64, timeline_clone, channels),
-                Self::error_callback,
-                None,
-            ),
-            _ => Err(BuildStreamError::StreamConfigNotSupported),
-        }
-        .map_err(AudioError::StreamConfigNotSupported)?;
-
-        stream.play().map_err(AudioError::PlayStreamError)?;
-
-        self.set_output_stream(stream);
+        self.start_output_stream()?;
+        self.send(TransportCommand::Play);
+        Ok(())
+    }
 
+    pub fn pause(&mut self) -> Result<(), AudioError> {
+        if self.is_recording() {
+            return Err(AudioError::RecordingInProgress);
+        }
+        self.send(TransportCommand::Pause);
         Ok(())
     }
 
-    pub fn stop_playing(&mut self) {
-        self.output_stream = None;
-        if let Ok(mut timeline) = self.timeline.lock() {
-            timeline.reset_playhead();
+    pub fn resume(&mut self) -> Result<(), AudioError> {
+        self.start_playing()
+    }
+
+    pub fn seek(&mut self, samples: u64) -> Result<(), AudioError> {
+        if self.is_recording() {
+            return Err(AudioError::RecordingInProgress);
+        }
+        self.send(TransportCommand::Seek(samples));
+        Ok(())
+    }
+
+    // A recording is finished here rather than in the output callback. The output keeps
+    // running until the stop has faded out and is then released, unless it is monitoring.
+    pub fn stop_playing(&mut self) -> Result<(), AudioError> {
+        if !self.monitoring {
+            self.stop_input_stream();
+        }
+        self.timeline
+            .lock()
+            .map_err(|_| AudioError::TimelineUnavailable)?
+            .stop_recording()?;
+        self.feeder = None;
+        self.send(TransportCommand::Stop);
+
+        if self.monitoring {
+            return Ok(());
         }
+        if self.backend.is_output_running() {
+            self.wait_for_fade_out()?;
+            self.backend.stop_output();
+        }
+
+        // Whatever the callback didn't get to, with the output closed nothing else will
+        self.timeline
+            .lock()
+            .map_err(|_| AudioError::TimelineUnavailable)?
+            .apply_commands();
+
+        Ok(())
+    }
+
+    fn wait_for_fade_out(&mut self) -> Result<(), AudioError> {
+        let step = self.buffer_latency().unwrap_or(Self::FADE_OUT_POLL);
+        let deadline = Instant::now() + Self::FADE_OUT_TIMEOUT;
+
+        while Instant::now() < deadline {
+            {
+                let timeline = self
+                    .timeline
+                    .lock()
+                    .map_err(|_| AudioError::TimelineUnavailable)?;
+                if !timeline.transport_state().is_rolling() && !timeline.is_fading_out() {
+                    break;
+                }
+            }
+            self.backend.wait(step)?;
+        }
+
+        Ok(())
     }
 
     pub fn is_playing(&self) -> bool {
-        self.output_stream.is_some()
+        self.transport_state().is_rolling()
     }
 
-    pub fn start_recording(&mut self) -> Result<(), AudioError> {
-        let input_device = self
-            .input_device
-            .as_ref()
-            .ok_or(AudioError::OutputDeviceNotFound)?;
+    pub fn transport_state(&self) -> TransportState {
+        self.timeline
+            .lock()
+            .map_or(TransportState::Stopped, |timeline| {
+                timeline.transport_state()
+            })
+    }
 
-        let config = self.config.config();
+    fn send(&self, command: TransportCommand) {
+        let _ = self.transport.send(command);
+    }
 
-        let stream = match self.config.sample_format() {
-            SampleFormat::F32 => input_device.build_input_stream(
-                &config,
-                move |_data: &[f32], _| {},
-                Self::error_callback,
-                None,
-            ),
-            _ => Err(BuildStreamError::StreamConfigNotSupported),
+    fn start_output_stream(&mut self) -> Result<(), AudioError> {
+        if self.backend.is_output_running() {
+            return Ok(());
         }
-        .map_err(AudioError::StreamConfigNotSupported)?;
 
-        stream.play().map_err(AudioError::PlayStreamError)?;
+        self.timeline
+            .lock()
+            .map_err(|_| AudioError::TimelineUnavailable)?
+            .set_output_channels(self.settings.channels)?;
+        self.latency.reset();
+        self.backend
+            .start_output(self.settings, self.timeline.clone(), self.latency.clone())
+    }
+
+    pub fn start_recording(&mut self) -> Result<(), AudioError> {
+        if self.is_recording() {
+            return Err(AudioError::RecordingInProgress);
+        }
+        // The playhead only moves while the output is running
+        self.start_output_stream()?;
+        let input_channels = self.start_input_stream()?;
+
+        let started = match self.timeline.lock() {
+            Ok(mut timeline) => timeline.start_recording(input_channels),
+            Err(_) => Err(AudioError::TimelineUnavailable),
+        };
+        match started {
+            Ok(()) => self.feeder = Some(ChunkFeeder::spawn(self.timeline.clone())),
+            Err(_) if !self.monitoring => self.stop_input_stream(),
+            Err(_) => {}
+        }
+        started
+    }
+
+    // Stops the transport along with the recording, the same way `stop_playing` does
+    pub fn stop_recording(&mut self) -> Result<(), AudioError> {
+        self.stop_playing()
+    }
 
-        self.set_input_stream(stream);
+    pub fn is_recording(&self) -> bool {
+        self.timeline
+            .lock()
+            .is_ok_and(|timeline| timeline.is_recording())
+    }
 
+    // Keeps the input open so the armed track can be heard without recording, whether it is
+    // heard depends on the track's monitor mode
+    pub fn start_monitoring(&mut self) -> Result<(), AudioError> {
+        self.start_output_stream()?;
+        self.start_input_stream()?;
+        self.monitoring = true;
         Ok(())
     }
 
-    pub fn stop_recording(&mut self) {
-        self.input_stream = None;
+    pub fn stop_monitoring(&mut self) {
+        self.monitoring = false;
+        if !self.is_recording() {
+            self.stop_input_stream();
+        }
+    }
+
+    pub fn is_monitoring(&self) -> bool {
+        self.monitoring
+    }
+
+    // Returns the channel count of the input, which may already be open
+    fn start_input_stream(&mut self) -> Result<u16, AudioError> {
+        let settings = self.input_settings()?;
+        if self.backend.is_input_running() {
+            return Ok(settings.channels);
+        }
+
+        self.timeline
+            .lock()
+            .map_err(|_| AudioError::TimelineUnavailable)?
+            .start_monitoring(settings.channels);
+        self.backend
+            .start_input(settings, self.timeline.clone(), self.latency.clone())?;
+
+        Ok(settings.channels)
+    }
+
+    fn stop_input_stream(&mut self) {
+        self.backend.stop_input();
         if let Ok(mut timeline) = self.timeline.lock() {
-            timeline.reset_playhead();
+            timeline.stop_monitoring();
         }
     }
 
-    pub fn is_recording(&self) -> bool {
-        self.input_stream.is_some()
+    pub fn list_devices(&self) -> Result<Vec<DeviceInfo>, AudioError> {
+        self.backend.devices()
+    }
+
+    pub fn output_device_name(&self) -> Option<String> {
+        self.backend.output_device_name()
+    }
+
+    pub fn input_device_name(&self) -> Option<String> {
+        self.backend.input_device_name()
+    }
+
+    // The running stream, if any, is rebuilt on the new device with the same config
+    pub fn set_output_device(&mut self, name: &str) -> Result<(), AudioError> {
+        if self.is_recording() {
+            return Err(AudioError::RecordingInProgress);
+        }
+
+        let previous = self.backend.output_device_name();
+        self.backend.set_output_device(name)?;
+        let settings = match Self::output_settings(&self.backend, &self.request) {
+            Ok(settings) => settings,
+            Err(err) => {
+                if let Some(previous) = previous {
+                    let _ = self.backend.set_output_device(&previous);
+                }
+                return Err(err);
+            }
+        };
+
+        let was_running = self.backend.is_output_running();
+        let was_monitoring = self.backend.is_input_running();
+        self.backend.stop_output();
+        if settings.sample_rate != self.settings.sample_rate {
+            // The input has to follow the output rate
+            self.stop_input_stream();
+            self.timeline
+                .lock()
+                .map_err(|_| AudioError::TimelineUnavailable)?
+                .set_sample_rate(settings.sample_rate)?;
+        }
+        self.settings = settings;
+        if was_running {
+            self.start_output_stream()?;
+        }
+        if was_monitoring {
+            self.start_input_stream()?;
+        }
+
+        Ok(())
+    }
+
+    pub fn set_input_device(&mut self, name: &str) -> Result<(), AudioError> {
+        if self.is_recording() {
+            return Err(AudioError::RecordingInProgress);
+        }
+
+        self.backend.set_input_device(name)?;
+        if self.backend.is_input_running() {
+            self.stop_input_stream();
+            self.start_input_stream()?;
+        }
+
+        Ok(())
+    }
+
+    pub fn input_channels(&self) -> Option<u16> {
+        self.input_channels
+    }
+
+    // A running input is reopened with the new channel count
+    pub fn set_input_channels(&mut self, channels: Option<u16>) -> Result<(), AudioError> {
+        if self.is_recording() {
+            return Err(AudioError::RecordingInProgress);
+        }
+        if channels == Some(0) {
+            return Err(AudioError::InvalidChannelCount(0));
+        }
+
+        self.input_channels = channels;
+        if self.backend.is_input_running() {
+            self.stop_input_stream();
+            self.start_input_stream()?;
+        }
+
+        Ok(())
     }
 
-    pub fn set_output_device() {}
+    // Corrects a copy of the clip without the timeline locked, so the callbacks keep running
+    // through the render, then swaps it in
+    pub fn set_pitch_correction(
+        &mut self,
+        track_id: TrackId,
+        clip_index: usize,
+        pitch_correction: PitchCorrection,
+    ) -> Result<(), AudioError> {
+        let mut clip = self
+            .timeline
+            .lock()
+            .map_err(|_| AudioError::TimelineUnavailable)?
+            .clip(track_id, clip_index)?
+            .clone();
 
-    pub fn set_input_device() {}
+        clip.set_pitch_correction(pitch_correction)?;
+
+        self.timeline
+            .lock()
+            .map_err(|_| AudioError::TimelineUnavailable)?
+            .replace_clip(track_id, clip_index, clip)?;
+
+        Ok(())
+    }
 
     pub fn timeline(&self) -> Arc<Mutex<Timeline>> {
         self.timeline.clone()
     }
 
-    fn set_output_stream(&mut self, stream: Stream) {
-        self.output_stream = Some(stream);
+    pub fn stream_settings(&self) -> StreamSettings {
+        self.settings
     }
 
-    fn set_input_stream(&mut self, stream: Stream) {
-        self.input_stream = Some(stream);
+    // None lets the driver pick. Running streams are reopened with the new size, the input
+    // too so monitoring gets the lower latency.
+    pub fn set_buffer_size(&mut self, buffer_size: Option<u32>) -> Result<(), AudioError> {
+        if self.is_recording() {
+            return Err(AudioError::RecordingInProgress);
+        }
+
+        let request = ConfigRequest {
+            buffer_size,
+            ..self.request
+        };
+        let settings = Self::output_settings(&self.backend, &request)?;
+
+        let previous = std::mem::replace(&mut self.request, request);
+        if settings != self.settings {
+            self.settings = settings;
+            if self.backend.is_output_running() {
+                self.backend.stop_output();
+                self.start_output_stream()?;
+            }
+        }
+        if previous.buffer_size != buffer_size && self.backend.is_input_running() {
+            self.stop_input_stream();
+            self.start_input_stream()?;
+        }
+
+        Ok(())
     }
 
-    fn error_callback(err: StreamError) {
-        eprintln!("{err:?}");
+    pub fn set_latency_profile(&mut self, profile: LatencyProfile) -> Result<(), AudioError> {
+        self.set_buffer_size(Some(profile.buffer_size()))
+    }
+
+    // Measured once the stream runs, otherwise what the buffer size alone adds
+    pub fn output_latency(&self) -> Option<Duration> {
+        self.latency.output().or_else(|| self.buffer_latency())
+    }
+
+    pub fn input_latency(&self) -> Option<Duration> {
+        self.latency.input().or_else(|| self.buffer_latency())
+    }
+
+    fn buffer_latency(&self) -> Option<Duration> {
+        self.settings
+            .buffer_size
+            .map(|frames| Duration::from_secs_f64(frames as f64 / self.settings.sample_rate as f64))
+    }
+
+    pub fn subscribe(&self) -> Result<Receiver<TransportEvent>, AudioError> {
+        let mut timeline = self
+            .timeline
+            .lock()
+            .map_err(|_| AudioError::TimelineUnavailable)?;
+        Ok(timeline.subscribe())
+    }
+
+    fn output_settings(backend: &B, request: &ConfigRequest) -> Result<StreamSettings, AudioError> {
+        let configs: Vec<ConfigRange> = backend.output_configs()?;
+        request.negotiate(&configs, B::OUTPUT_FORMATS)
+    }
+
+    // Input is recorded straight onto the timeline, so it has to run at the output rate. Its
+    // channels are requested separately from the output's, by default all the device has at
+    // that rate.
+    fn input_settings(&self) -> Result<StreamSettings, AudioError> {
+        let sample_rate = self.settings.sample_rate;
+        let configs: Vec<ConfigRange> = self.backend.input_configs()?;
+        let channels = self.input_channels.unwrap_or_else(|| {
+            configs
+                .iter()
+                .filter(|config| {
+                    (config.min_sample_rate..=config.max_sample_rate).contains(&sample_rate)
+                })
+                .map(|config| config.channels)
+                .max()
+                .unwrap_or(self.request.channels)
+        });
+        let request = ConfigRequest {
+            channels,
+            sample_format: SampleFormat::F32,
+            sample_rate,
+            ..self.request
+        };
+        let settings = request.negotiate(&configs, B::INPUT_FORMATS)?;
+
+        if settings.sample_rate != request.sample_rate {
+            return Err(AudioError::UnsupportedConfig {
+                channels: request.channels,
+                sample_format: request.sample_format,
+                sample_rate: request.sample_rate,
+            });
+        }
+
+        Ok(settings)
     }
 }
@@ -0,0 +1,99 @@
+use std::{
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use cpal::SampleFormat;
+
+use crate::engine::{AudioError, ConfigRange, DeviceInfo, StreamSettings, Timeline};
+
+// Where the engine gets its devices and streams from. A running output stream pulls
+// `Timeline::process` once per buffer, a running input stream pushes into `Timeline::record`.
+pub trait Backend {
+    // Formats the stream callbacks are built for, most preferred first
+    const OUTPUT_FORMATS: &'static [SampleFormat];
+    const INPUT_FORMATS: &'static [SampleFormat];
+
+    fn devices(&self) -> Result<Vec<DeviceInfo>, AudioError>;
+
+    fn output_device_name(&self) -> Option<String>;
+
+    fn input_device_name(&self) -> Option<String>;
+
+    // A stream that is already running stays on the old device until it is restarted
+    fn set_output_device(&mut self, name: &str) -> Result<(), AudioError>;
+
+    fn set_input_device(&mut self, name: &str) -> Result<(), AudioError>;
+
+    fn output_configs(&self) -> Result<Vec<ConfigRange>, AudioError>;
+
+    fn input_configs(&self) -> Result<Vec<ConfigRange>, AudioError>;
+
+    fn start_output(
+        &mut self,
+        settings: StreamSettings,
+        timeline: Arc<Mutex<Timeline>>,
+        latency: Arc<LatencyMeter>,
+    ) -> Result<(), AudioError>;
+
+    fn start_input(
+        &mut self,
+        settings: StreamSettings,
+        timeline: Arc<Mutex<Timeline>>,
+        latency: Arc<LatencyMeter>,
+    ) -> Result<(), AudioError>;
+
+    fn stop_output(&mut self);
+
+    fn stop_input(&mut self);
+
+    fn is_output_running(&self) -> bool;
+
+    fn is_input_running(&self) -> bool;
+
+    // Blocks for about `duration` while the running streams carry on
+    fn wait(&mut self, duration: Duration) -> Result<(), AudioError>;
+}
+
+// Set from the stream callbacks: how long until rendered audio is heard, and how long ago
+// recorded audio was captured. Zero until the first callback.
+#[derive(Debug, Default)]
+pub struct LatencyMeter {
+    output_micros: AtomicU64,
+    input_micros: AtomicU64,
+}
+
+impl LatencyMeter {
+    pub fn set_output(&self, latency: Duration) {
+        self.output_micros
+            .store(latency.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn set_input(&self, latency: Duration) {
+        self.input_micros
+            .store(latency.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn output(&self) -> Option<Duration> {
+        Self::measured(&self.output_micros)
+    }
+
+    pub fn input(&self) -> Option<Duration> {
+        Self::measured(&self.input_micros)
+    }
+
+    pub fn reset(&self) {
+        self.output_micros.store(0, Ordering::Relaxed);
+        self.input_micros.store(0, Ordering::Relaxed);
+    }
+
+    fn measured(micros: &AtomicU64) -> Option<Duration> {
+        match micros.load(Ordering::Relaxed) {
+            0 => None,
+            micros => Some(Duration::from_micros(micros)),
+        }
+    }
+}
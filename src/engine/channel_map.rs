@@ -0,0 +1,237 @@
+use std::f64::consts::FRAC_1_SQRT_2;
+
+use crate::engine::AudioError;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChannelMap {
+    source_channels: u16,
+    output_channels: u16,
+    gains: Vec<f64>,
+}
+
+impl ChannelMap {
+    pub fn new(source_channels: u16, output_channels: u16) -> Self {
+        ChannelMap {
+            source_channels,
+            output_channels,
+            gains: vec![0.0; source_channels as usize * output_channels as usize],
+        }
+    }
+
+    pub fn default_for(source_channels: u16, output_channels: u16) -> Self {
+        let mut map = Self::new(source_channels, output_channels);
+        for source in 0..source_channels {
+            for output in 0..output_channels {
+                let gain = Self::default_gain(source, output, source_channels, output_channels);
+                let index = map.index(source, output);
+                map.gains[index] = gain;
+            }
+        }
+        map
+    }
+
+    // Mono is copied to every output. Up to 7.1 in the WAVE channel order, surround sources
+    // are folded into stereo with the centre and surrounds at -3 dB and the LFE left out, and
+    // a mono output sums that stereo fold. Otherwise source channels wrap around the outputs
+    // so nothing is dropped.
+    pub fn default_gain(
+        source: u16,
+        output: u16,
+        source_channels: u16,
+        output_channels: u16,
+    ) -> f64 {
+        if source >= source_channels || output >= output_channels {
+            return 0.0;
+        }
+
+        if source_channels == 1 {
+            return 1.0;
+        }
+        if output_channels <= 2
+            && let Some((left, right)) = Self::stereo_downmix(source, source_channels)
+        {
+            return match (output_channels, output) {
+                (1, _) => left + right,
+                (_, 0) => left,
+                _ => right,
+            };
+        }
+
+        if output_channels == 1 || source % output_channels == output {
+            1.0
+        } else {
+            0.0
+        }
+    }
+
+    // Left and right gains for a source channel, for the layouts with a standard fold-down
+    fn stereo_downmix(source: u16, source_channels: u16) -> Option<(f64, f64)> {
+        const LEFT: (f64, f64) = (1.0, 0.0);
+        const RIGHT: (f64, f64) = (0.0, 1.0);
+        const CENTRE: (f64, f64) = (FRAC_1_SQRT_2, FRAC_1_SQRT_2);
+        const LFE: (f64, f64) = (0.0, 0.0);
+        const SURROUND_LEFT: (f64, f64) = (FRAC_1_SQRT_2, 0.0);
+        const SURROUND_RIGHT: (f64, f64) = (0.0, FRAC_1_SQRT_2);
+
+        let layout: &[(f64, f64)] = match source_channels {
+            2 => &[LEFT, RIGHT],
+            3 => &[LEFT, RIGHT, CENTRE],
+            4 => &[LEFT, RIGHT, SURROUND_LEFT, SURROUND_RIGHT],
+            5 => &[LEFT, RIGHT, CENTRE, SURROUND_LEFT, SURROUND_RIGHT],
+            6 => &[LEFT, RIGHT, CENTRE, LFE, SURROUND_LEFT, SURROUND_RIGHT],
+            7 => &[
+                LEFT,
+                RIGHT,
+                CENTRE,
+                LFE,
+                CENTRE,
+                SURROUND_LEFT,
+                SURROUND_RIGHT,
+            ],
+            8 => &[
+                LEFT,
+                RIGHT,
+                CENTRE,
+                LFE,
+                SURROUND_LEFT,
+                SURROUND_RIGHT,
+                SURROUND_LEFT,
+                SURROUND_RIGHT,
+            ],
+            _ => return None,
+        };
+        layout.get(source as usize).copied()
+    }
+
+    pub fn source_channels(&self) -> u16 {
+        self.source_channels
+    }
+
+    pub fn output_channels(&self) -> u16 {
+        self.output_channels
+    }
+
+    pub fn gain(&self, source: u16, output: u16) -> f64 {
+        if source >= self.source_channels || output >= self.output_channels {
+            return 0.0;
+        }
+        self.gains[self.index(source, output)]
+    }
+
+    pub fn set_gain(&mut self, source: u16, output: u16, gain: f64) -> Result<(), AudioError> {
+        if source >= self.source_channels {
+            return Err(AudioError::ChannelOutOfRange(source));
+        }
+        if output >= self.output_channels {
+            return Err(AudioError::ChannelOutOfRange(output));
+        }
+
+        let index = self.index(source, output);
+        self.gains[index] = gain;
+        Ok(())
+    }
+
+    pub fn route(&mut self, source: u16, output: u16) -> Result<(), AudioError> {
+        self.set_gain(source, output, 1.0)
+    }
+
+    fn index(&self, source: u16, output: u16) -> usize {
+        source as usize * self.output_channels as usize + output as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_mono_to_stereo() {
+        let map = ChannelMap::default_for(1, 2);
+
+        assert_eq!(map.gain(0, 0), 1.0);
+        assert_eq!(map.gain(0, 1), 1.0);
+    }
+
+    #[test]
+    fn test_default_stereo_to_mono() {
+        let map = ChannelMap::default_for(2, 1);
+
+        assert_eq!(map.gain(0, 0), 1.0);
+        assert_eq!(map.gain(1, 0), 1.0);
+    }
+
+    #[test]
+    fn test_default_quad_to_stereo_folds_surrounds() {
+        let map = ChannelMap::default_for(4, 2);
+
+        assert_eq!(map.gain(0, 0), 1.0);
+        assert_eq!(map.gain(1, 1), 1.0);
+        assert_eq!(map.gain(2, 0), FRAC_1_SQRT_2);
+        assert_eq!(map.gain(3, 1), FRAC_1_SQRT_2);
+        assert_eq!(map.gain(0, 1), 0.0);
+        assert_eq!(map.gain(3, 0), 0.0);
+    }
+
+    #[test]
+    fn test_default_5_1_to_stereo() {
+        let map = ChannelMap::default_for(6, 2);
+
+        // Centre at -3 dB on both sides, LFE dropped, surrounds at -3 dB on their own side
+        assert_eq!((map.gain(0, 0), map.gain(0, 1)), (1.0, 0.0));
+        assert_eq!((map.gain(1, 0), map.gain(1, 1)), (0.0, 1.0));
+        assert_eq!(
+            (map.gain(2, 0), map.gain(2, 1)),
+            (FRAC_1_SQRT_2, FRAC_1_SQRT_2)
+        );
+        assert_eq!((map.gain(3, 0), map.gain(3, 1)), (0.0, 0.0));
+        assert_eq!((map.gain(4, 0), map.gain(4, 1)), (FRAC_1_SQRT_2, 0.0));
+        assert_eq!((map.gain(5, 0), map.gain(5, 1)), (0.0, FRAC_1_SQRT_2));
+
+        let mono = ChannelMap::default_for(6, 1);
+        assert_eq!(mono.gain(2, 0), 2.0 * FRAC_1_SQRT_2);
+        assert_eq!(mono.gain(3, 0), 0.0);
+    }
+
+    #[test]
+    fn test_default_unknown_layout_wraps() {
+        let map = ChannelMap::default_for(10, 2);
+
+        assert_eq!(map.gain(8, 0), 1.0);
+        assert_eq!(map.gain(9, 1), 1.0);
+        assert_eq!(map.gain(9, 0), 0.0);
+    }
+
+    #[test]
+    fn test_default_stereo_to_surround() {
+        let map = ChannelMap::default_for(2, 6);
+
+        assert_eq!(map.gain(0, 0), 1.0);
+        assert_eq!(map.gain(1, 1), 1.0);
+        for output in 2..6 {
+            assert_eq!(map.gain(0, output), 0.0);
+            assert_eq!(map.gain(1, output), 0.0);
+        }
+    }
+
+    #[test]
+    fn test_set_gain() -> Result<(), AudioError> {
+        let mut map = ChannelMap::new(6, 2);
+        map.set_gain(2, 0, 0.5)?;
+        map.route(5, 1)?;
+
+        assert_eq!(map.gain(2, 0), 0.5);
+        assert_eq!(map.gain(5, 1), 1.0);
+        assert_eq!(map.gain(2, 1), 0.0);
+        assert!(matches!(
+            map.set_gain(6, 0, 1.0),
+            Err(AudioError::ChannelOutOfRange(6))
+        ));
+        assert!(matches!(
+            map.set_gain(0, 2, 1.0),
+            Err(AudioError::ChannelOutOfRange(2))
+        ));
+        assert_eq!(map.gain(6, 0), 0.0);
+
+        Ok(())
+    }
+}
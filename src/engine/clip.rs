@@ -1,44 +1,539 @@
-use std::{fs::File, io::BufReader, ops::AddAssign, path::Path};
+use std::{
+    fs::File,
+    io::BufReader,
+    ops::{AddAssign, Range},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
 use hound::WavReader;
 
-use crate::engine::{AudioError, FromF64Sample, Resampler, Scale, utils::Utils};
+use crate::engine::{
+    AudioError, ChannelMap, FromF64Sample, Peak, PeakPyramid, PitchCorrection, PitchPoint,
+    PitchTracker, Resampler, ResamplerQuality, Scale, SourceStamp, TimeStretcher, Varispeed,
+    WarpMap, WarpMarker, utils::Utils,
+};
+
+#[derive(Debug, Clone)]
+pub enum ClipSource {
+    File(PathBuf),
+    Memory(Arc<[f64]>),
+}
+
+// Where the clip was put, at the rate it was put there. Rate changes convert from here
+// rather than from the previous rate, so rounding doesn't build up.
+#[derive(Debug, Clone)]
+struct Placement {
+    sample_rate: u32,
+    start_time_in_samples: u64,
+    region: Range<u64>,
+    warp_markers: Vec<WarpMarker>,
+}
 
 #[derive(Debug, Clone)]
 pub struct Clip {
-    data: Vec<f64>,
+    // Shared with a memory source while both are at the source rate
+    data: Arc<[f64]>,
+    // The frames of `data` the clip plays, less than all of it once split
+    region: Range<u64>,
     channel: u16,
+    sample_rate: u32,
     start_time_in_samples: u64,
+    placement: Placement,
+    source: ClipSource,
+    source_sample_rate: u32,
+    channel_map: Option<ChannelMap>,
+    peaks: PeakPyramid,
+    // Built from the processed audio, the cache on disk only ever holds the source peaks
+    processed_peaks: Option<PeakPyramid>,
+    pitch_correction: Option<PitchCorrection>,
+    processed: Option<Vec<f64>>,
+    warp_markers: Vec<WarpMarker>,
+    stretch: f64,
+    playback_rate: f64,
 }
 
 impl Clip {
+    pub fn from_samples(
+        data: Vec<f64>,
+        channels: u16,
+        sample_rate: u32,
+        start_time_in_samples: u64,
+    ) -> Self {
+        let data: Arc<[f64]> = data.into();
+        let region = 0..(data.len() / channels.max(1) as usize) as u64;
+        Clip {
+            source: ClipSource::Memory(data.clone()),
+            peaks: PeakPyramid::build(&data, channels, sample_rate),
+            processed_peaks: None,
+            data,
+            region: region.clone(),
+            channel: channels,
+            sample_rate,
+            start_time_in_samples,
+            placement: Placement {
+                sample_rate,
+                start_time_in_samples,
+                region,
+                warp_markers: Vec::new(),
+            },
+            source_sample_rate: sample_rate,
+            channel_map: None,
+            pitch_correction: None,
+            processed: None,
+            warp_markers: Vec::new(),
+            stretch: 1.0,
+            playback_rate: 1.0,
+        }
+    }
+
     pub fn from_path<P: AsRef<Path>>(
         path: P,
         start_time_in_samples: u64,
         timeline_sample_rate: u32,
+        resampler_quality: ResamplerQuality,
     ) -> Result<Self, AudioError> {
-        let reader = hound::WavReader::open(path)?;
-        let spec = reader.spec();
-        let samples = Self::decode_samples_to_f64(reader)?;
+        let path = path.as_ref();
+        let (spec, samples) = Self::decode_file(path)?;
 
-        let data = if spec.sample_rate != timeline_sample_rate {
-            Resampler::resample(
-                samples,
-                timeline_sample_rate,
-                spec.sample_rate,
-                spec.channels,
-            )?
-        } else {
-            samples
-        };
+        let data = Self::derive_data(
+            samples,
+            spec.channels,
+            spec.sample_rate,
+            timeline_sample_rate,
+            resampler_quality,
+        )?;
+
+        let source = ClipSource::File(path.to_path_buf());
+        let peaks = Self::load_or_build_peaks(&source, &data, spec.channels, timeline_sample_rate);
+        let region = 0..(data.len() / spec.channels.max(1) as usize) as u64;
 
         Ok(Clip {
-            data,
+            data: data.into(),
+            region: region.clone(),
             channel: spec.channels,
+            sample_rate: timeline_sample_rate,
             start_time_in_samples,
+            placement: Placement {
+                sample_rate: timeline_sample_rate,
+                start_time_in_samples,
+                region,
+                warp_markers: Vec::new(),
+            },
+            source,
+            source_sample_rate: spec.sample_rate,
+            channel_map: None,
+            peaks,
+            processed_peaks: None,
+            pitch_correction: None,
+            processed: None,
+            warp_markers: Vec::new(),
+            stretch: 1.0,
+            playback_rate: 1.0,
         })
     }
 
+    pub fn with_sample_rate(
+        &self,
+        sample_rate: u32,
+        resampler_quality: ResamplerQuality,
+    ) -> Result<Self, AudioError> {
+        let data: Arc<[f64]> = match &self.source {
+            ClipSource::Memory(samples) if self.source_sample_rate == sample_rate => {
+                samples.clone()
+            }
+            ClipSource::Memory(samples) => Self::derive_data(
+                samples.to_vec(),
+                self.channel,
+                self.source_sample_rate,
+                sample_rate,
+                resampler_quality,
+            )?
+            .into(),
+            ClipSource::File(path) => Self::derive_data(
+                Self::decode_file(path)?.1,
+                self.channel,
+                self.source_sample_rate,
+                sample_rate,
+                resampler_quality,
+            )?
+            .into(),
+        };
+
+        let placement = &self.placement;
+        let scale_frame = |frame: u64| {
+            Resampler::output_frames(frame as usize, sample_rate, placement.sample_rate) as u64
+        };
+        let start_time_in_samples = scale_frame(placement.start_time_in_samples);
+        let frames = (data.len() / self.channel.max(1) as usize) as u64;
+        let region = scale_frame(placement.region.start).min(frames)
+            ..scale_frame(placement.region.end).min(frames);
+        let warp_markers = placement
+            .warp_markers
+            .iter()
+            .map(|marker| WarpMarker {
+                source_frame: scale_frame(marker.source_frame),
+                timeline_frame: scale_frame(marker.timeline_frame),
+            })
+            .collect();
+
+        let peaks = Self::load_or_build_peaks(&self.source, &data, self.channel, sample_rate);
+
+        let mut clip = Clip {
+            data,
+            region,
+            channel: self.channel,
+            sample_rate,
+            start_time_in_samples,
+            placement: self.placement.clone(),
+            source: self.source.clone(),
+            source_sample_rate: self.source_sample_rate,
+            channel_map: self.channel_map.clone(),
+            peaks,
+            processed_peaks: None,
+            pitch_correction: self.pitch_correction.clone(),
+            processed: None,
+            warp_markers,
+            stretch: self.stretch,
+            playback_rate: self.playback_rate,
+        };
+        clip.update_processed()?;
+
+        Ok(clip)
+    }
+
+    pub fn pitch_correction(&self) -> Option<&PitchCorrection> {
+        self.pitch_correction.as_ref()
+    }
+
+    pub fn set_pitch_correction(
+        &mut self,
+        pitch_correction: PitchCorrection,
+    ) -> Result<(), AudioError> {
+        pitch_correction.validate()?;
+        self.pitch_correction = Some(pitch_correction);
+        self.update_processed()
+    }
+
+    pub fn clear_pitch_correction(&mut self) -> Result<(), AudioError> {
+        self.pitch_correction = None;
+        self.update_processed()
+    }
+
+    pub fn warp_markers(&self) -> &[WarpMarker] {
+        &self.warp_markers
+    }
+
+    pub fn set_warp_markers(&mut self, warp_markers: Vec<WarpMarker>) -> Result<(), AudioError> {
+        WarpMap::new(&warp_markers, self.stretch, self.source_frames())
+            .ok_or(AudioError::InvalidWarpMarkers)?;
+
+        self.warp_markers = warp_markers;
+        self.place();
+        self.update_processed()
+    }
+
+    // Applies markers measured on the rendered audio on top of the current warp, so that an
+    // earlier stretch or warp is kept rather than replaced. Past the last new marker the
+    // current warp carries on, shifted along with it.
+    pub fn warp_rendered(&mut self, markers: &[WarpMarker]) -> Result<(), AudioError> {
+        let Some(last) = markers.last() else {
+            return Ok(());
+        };
+        let warp_map = WarpMap::new(&self.warp_markers, self.stretch, self.source_frames())
+            .ok_or(AudioError::InvalidWarpMarkers)?;
+
+        // Rendered frames are after varispeed, the warp map works on the frames before it
+        let warped = |frame: u64| frame as f64 * self.playback_rate;
+        let unstretched = |frame: f64| (frame / self.stretch).max(0.0).round() as u64;
+        let shift = warped(last.timeline_frame) - warped(last.source_frame);
+
+        let composed = markers.iter().filter_map(|marker| {
+            let source = warp_map.source_position(warped(marker.source_frame))?;
+            Some(WarpMarker {
+                source_frame: source.max(0.0).round() as u64,
+                timeline_frame: unstretched(warped(marker.timeline_frame)),
+            })
+        });
+        let carried = self
+            .warp_markers
+            .iter()
+            .filter(|marker| {
+                marker.timeline_frame as f64 * self.stretch > warped(last.source_frame)
+            })
+            .map(|marker| WarpMarker {
+                source_frame: marker.source_frame,
+                timeline_frame: unstretched(marker.timeline_frame as f64 * self.stretch + shift),
+            });
+
+        let mut merged: Vec<WarpMarker> = Vec::with_capacity(markers.len());
+        for marker in composed.chain(carried) {
+            let increasing = merged.last().is_none_or(|previous| {
+                marker.source_frame > previous.source_frame
+                    && marker.timeline_frame > previous.timeline_frame
+            });
+            if increasing {
+                merged.push(marker);
+            }
+        }
+
+        self.set_warp_markers(merged)
+    }
+
+    pub fn clear_warp_markers(&mut self) -> Result<(), AudioError> {
+        self.warp_markers.clear();
+        self.place();
+        self.update_processed()
+    }
+
+    pub fn clear_warp(&mut self) -> Result<(), AudioError> {
+        self.warp_markers.clear();
+        self.stretch = 1.0;
+        self.place();
+        self.update_processed()
+    }
+
+    pub fn stretch(&self) -> f64 {
+        self.stretch
+    }
+
+    pub fn set_stretch(&mut self, stretch: f64) -> Result<(), AudioError> {
+        if !stretch.is_finite() || stretch <= 0.0 {
+            return Err(AudioError::InvalidStretch(stretch));
+        }
+
+        self.stretch = stretch;
+        self.update_processed()
+    }
+
+    pub fn playback_rate(&self) -> f64 {
+        self.playback_rate
+    }
+
+    // Varispeed: unlike stretching, the pitch follows the rate
+    pub fn set_playback_rate(&mut self, playback_rate: f64) -> Result<(), AudioError> {
+        Varispeed::validate_rate(playback_rate)?;
+
+        self.playback_rate = playback_rate;
+        self.update_processed()
+    }
+
+    // The source data is left alone: pitch correction, time-stretching and varispeed are
+    // rendered in that order into a separate buffer that playback reads instead
+    fn update_processed(&mut self) -> Result<(), AudioError> {
+        let corrected = self
+            .pitch_correction
+            .as_ref()
+            .map(|correction| correction.render(self.source_data(), self.channel, self.sample_rate))
+            .transpose()?;
+
+        let warped = WarpMap::new(&self.warp_markers, self.stretch, self.source_frames())
+            .filter(|warp_map| !warp_map.is_identity())
+            .map(|warp_map| {
+                let source = corrected.as_deref().unwrap_or(self.source_data());
+                TimeStretcher::default().render(source, self.channel, &warp_map)
+            })
+            .or(corrected);
+
+        self.processed = if self.playback_rate != 1.0 {
+            let source = warped.unwrap_or_else(|| self.source_data().to_vec());
+            Some(Resampler::resample_by_ratio(
+                source,
+                1.0 / self.playback_rate,
+                self.channel,
+                ResamplerQuality::default(),
+            )?)
+        } else {
+            warped
+        };
+        self.processed_peaks = self
+            .processed
+            .as_ref()
+            .map(|processed| PeakPyramid::build(processed, self.channel, self.sample_rate));
+
+        Ok(())
+    }
+
+    fn source_frames(&self) -> u64 {
+        self.region.end - self.region.start
+    }
+
+    fn source_data(&self) -> &[f64] {
+        let channels = self.channel.max(1) as usize;
+        &self.data[self.region.start as usize * channels..self.region.end as usize * channels]
+    }
+
+    pub fn render(&self) -> &[f64] {
+        self.processed.as_deref().unwrap_or(self.source_data())
+    }
+
+    // Splits at a frame of the rendered audio, counted from the clip start. Both halves play
+    // their part of the same source with the same processing, which is rendered again for
+    // each of them.
+    pub fn split_at(&self, frame: u64) -> Result<(Clip, Clip), AudioError> {
+        let invalid = AudioError::InvalidSplitPosition(frame);
+        if frame == 0 || frame >= self.duration_in_samples() {
+            return Err(invalid);
+        }
+
+        // Back through the varispeed and the warp to the source frame that plays there
+        let warp_map = WarpMap::new(&self.warp_markers, self.stretch, self.source_frames())
+            .ok_or(AudioError::InvalidWarpMarkers)?;
+        let warped = frame as f64 * self.playback_rate;
+        let source_frame = warp_map
+            .source_position(warped)
+            .map(|position| position.round() as u64)
+            .filter(|position| *position > 0 && *position < self.source_frames())
+            .ok_or(invalid)?;
+        let timeline_frame = warped / self.stretch;
+
+        let mut first = self.clone();
+        first.region = self.region.start..self.region.start + source_frame;
+        first.warp_markers = self
+            .warp_markers
+            .iter()
+            .filter(|marker| marker.source_frame < source_frame)
+            .copied()
+            .collect();
+        // The audio after the last marker keeps its length, so the segment the split falls
+        // in is pinned one frame before the new end
+        let pinned = source_frame - 1;
+        if !self.warp_markers.is_empty()
+            && first
+                .warp_markers
+                .last()
+                .is_none_or(|marker| marker.source_frame < pinned)
+        {
+            let position = warp_map.timeline_position(pinned as f64) / self.stretch;
+            first.warp_markers.push(WarpMarker {
+                source_frame: pinned,
+                timeline_frame: position.round() as u64,
+            });
+        }
+
+        let mut second = self.clone();
+        second.region = self.region.start + source_frame..self.region.end;
+        second.start_time_in_samples = self.start_time_in_samples + frame;
+        second.warp_markers = self
+            .warp_markers
+            .iter()
+            .filter(|marker| marker.source_frame > source_frame)
+            .map(|marker| WarpMarker {
+                source_frame: marker.source_frame - source_frame,
+                timeline_frame: (marker.timeline_frame as f64 - timeline_frame)
+                    .max(1.0)
+                    .round() as u64,
+            })
+            .collect();
+        second.pitch_correction = self
+            .pitch_correction
+            .as_ref()
+            .map(|correction| correction.shifted(source_frame as f64 / self.sample_rate as f64));
+
+        for half in [&mut first, &mut second] {
+            half.place();
+            half.update_processed()?;
+        }
+
+        Ok((first, second))
+    }
+
+    // Of the audio as it plays, with any processing applied
+    pub fn peaks(&self, range: Range<u64>, pixels: usize) -> Vec<Vec<Peak>> {
+        if let Some(processed_peaks) = &self.processed_peaks {
+            return processed_peaks.peaks(self.render(), range, pixels);
+        }
+
+        // The source peaks cover all of the data, the region is somewhere inside it
+        let end = (self.region.start + range.end).min(self.region.end);
+        let start = (self.region.start + range.start).min(end);
+        self.peaks.peaks(&self.data, start..end, pixels)
+    }
+
+    pub fn pitch_track(&self) -> Result<Vec<PitchPoint>, AudioError> {
+        self.pitch_track_with(&PitchTracker::default())
+    }
+
+    pub fn pitch_track_with(&self, tracker: &PitchTracker) -> Result<Vec<PitchPoint>, AudioError> {
+        tracker.track(&self.mixdown(), self.sample_rate)
+    }
+
+    pub fn mixdown(&self) -> Vec<f64> {
+        let channels = self.channel.max(1) as usize;
+        self.render()
+            .chunks_exact(channels)
+            .map(|frame| frame.iter().sum::<f64>() / channels as f64)
+            .collect()
+    }
+
+    pub fn peak_file_path(&self) -> Option<PathBuf> {
+        Self::source_peak_file_path(&self.source)
+    }
+
+    pub fn save_peaks(&self) -> Result<(), AudioError> {
+        if let (ClipSource::File(source), Some(path)) = (&self.source, self.peak_file_path()) {
+            self.peaks.save(path, SourceStamp::of(source)?)?;
+        }
+        Ok(())
+    }
+
+    fn source_peak_file_path(source: &ClipSource) -> Option<PathBuf> {
+        match source {
+            ClipSource::File(path) => {
+                let mut peak_file = path.clone().into_os_string();
+                peak_file.push(".pk");
+                Some(peak_file.into())
+            }
+            ClipSource::Memory(_) => None,
+        }
+    }
+
+    fn load_or_build_peaks(
+        source: &ClipSource,
+        data: &[f64],
+        channels: u16,
+        sample_rate: u32,
+    ) -> PeakPyramid {
+        let frames = data.len() as u64 / channels.max(1) as u64;
+
+        let cached = match source {
+            ClipSource::File(path) => SourceStamp::of(path).ok().and_then(|stamp| {
+                let peak_file = Self::source_peak_file_path(source)?;
+                PeakPyramid::load(peak_file, channels, sample_rate, frames, stamp).ok()
+            }),
+            ClipSource::Memory(_) => None,
+        };
+
+        cached.unwrap_or_else(|| PeakPyramid::build(data, channels, sample_rate))
+    }
+
+    fn derive_data(
+        samples: Vec<f64>,
+        channels: u16,
+        source_sample_rate: u32,
+        sample_rate: u32,
+        resampler_quality: ResamplerQuality,
+    ) -> Result<Vec<f64>, AudioError> {
+        if source_sample_rate == sample_rate {
+            return Ok(samples);
+        }
+
+        Resampler::resample(
+            samples,
+            sample_rate,
+            source_sample_rate,
+            channels,
+            resampler_quality,
+        )
+    }
+
+    fn decode_file(path: &Path) -> Result<(hound::WavSpec, Vec<f64>), AudioError> {
+        let reader = hound::WavReader::open(path)?;
+        let spec = reader.spec();
+        let samples = Self::decode_samples_to_f64(reader)?;
+        Ok((spec, samples))
+    }
+
     fn read_samples_as_f64<T>(reader: WavReader<BufReader<File>>, scale: Scale) -> Vec<f64>
     where
         T: hound::Sample + Into<f64> + Default + Copy,
@@ -56,7 +551,8 @@ impl Clip {
 
         let samples: Vec<f64> = match spec.sample_format {
             hound::SampleFormat::Int => match spec.bits_per_sample {
-                8 => Self::read_samples_as_f64::<i8>(reader, Scale::I8),
+                // 8-bit WAV is unsigned, hound hands it back with the 128 offset removed
+                8 => Self::read_samples_as_f64::<i8>(reader, Scale::U8),
                 16 => Self::read_samples_as_f64::<i16>(reader, Scale::I16),
                 24 => Self::read_samples_as_f64::<i32>(reader, Scale::I24),
                 32 => Self::read_samples_as_f64::<i32>(reader, Scale::I32),
@@ -72,13 +568,27 @@ impl Clip {
     }
 
     pub fn duration_in_samples(&self) -> u64 {
-        self.sample_count() as u64 / self.channel as u64
+        (self.render().len() / self.channel.max(1) as usize) as u64
     }
 
     pub fn start_time_in_samples(&self) -> u64 {
         self.start_time_in_samples
     }
 
+    pub fn set_start_time_in_samples(&mut self, start_time_in_samples: u64) {
+        self.start_time_in_samples = start_time_in_samples;
+        self.place();
+    }
+
+    fn place(&mut self) {
+        self.placement = Placement {
+            sample_rate: self.sample_rate,
+            start_time_in_samples: self.start_time_in_samples,
+            region: self.region.clone(),
+            warp_markers: self.warp_markers.clone(),
+        };
+    }
+
     pub fn end_time_in_samples(&self) -> u64 {
         self.start_time_in_samples + self.duration_in_samples()
     }
@@ -92,7 +602,49 @@ impl Clip {
     }
 
     pub fn sample_count(&self) -> usize {
-        self.data.len()
+        self.source_data().len()
+    }
+
+    pub fn channels(&self) -> u16 {
+        self.channel
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    pub fn source(&self) -> &ClipSource {
+        &self.source
+    }
+
+    pub fn source_sample_rate(&self) -> u32 {
+        self.source_sample_rate
+    }
+
+    pub fn channel_map(&self) -> Option<&ChannelMap> {
+        self.channel_map.as_ref()
+    }
+
+    pub fn set_channel_map(&mut self, channel_map: ChannelMap) -> Result<(), AudioError> {
+        if channel_map.source_channels() != self.channel {
+            return Err(AudioError::ChannelMapMismatch {
+                expected: self.channel,
+                actual: channel_map.source_channels(),
+            });
+        }
+        self.channel_map = Some(channel_map);
+        Ok(())
+    }
+
+    pub fn clear_channel_map(&mut self) {
+        self.channel_map = None;
+    }
+
+    fn gain(&self, source: u16, output: u16, output_channels: u16) -> f64 {
+        match &self.channel_map {
+            Some(channel_map) => channel_map.gain(source, output),
+            None => ChannelMap::default_gain(source, output, self.channel, output_channels),
+        }
     }
 
     fn write_to_frame<T>(
@@ -121,36 +673,470 @@ impl Clip {
         T: FromF64Sample + AddAssign + Clone,
     {
         let frame_within_clip = playhead_position - self.start_time_in_samples;
+        let source_channels = self.channel as usize;
 
-        if self.is_mono()
-            && let Some(sample) = self.data.get(frame_within_clip as usize)
-        {
-            let sample_t = T::from_f64_sample(sample * volume as f64);
-            for channel in 0..output_channels as usize {
-                Self::write_to_frame(
-                    buffer,
-                    frame_index,
-                    channel,
-                    sample_t.clone(),
-                    output_channels,
-                );
-            }
+        let Some(frame) = (frame_within_clip as usize)
+            .checked_mul(source_channels)
+            .and_then(|idx| self.render().get(idx..idx + source_channels))
+        else {
+            return;
+        };
+
+        for output in 0..output_channels {
+            let mixed: f64 = frame
+                .iter()
+                .enumerate()
+                .map(|(source, sample)| sample * self.gain(source as u16, output, output_channels))
+                .sum();
+
+            Self::write_to_frame(
+                buffer,
+                frame_index,
+                output as usize,
+                T::from_f64_sample(mixed * volume as f64),
+                output_channels,
+            );
         }
+    }
+}
 
-        if self.is_stereo()
-            && let Some(idx) = frame_within_clip.checked_mul(2).map(|idx| idx as usize)
-            && let (Some(left), Some(right)) = (self.data.get(idx), self.data.get(idx + 1))
-        {
-            let left_t = T::from_f64_sample(left * volume as f64);
-            let right_t = T::from_f64_sample(right * volume as f64);
-
-            if output_channels == 1 {
-                let mono_sample = T::from_f64_sample((left + right) * volume as f64);
-                Self::write_to_frame(buffer, frame_index, 0, mono_sample, output_channels);
-            } else {
-                Self::write_to_frame(buffer, frame_index, 0, left_t, output_channels);
-                Self::write_to_frame(buffer, frame_index, 1, right_t, output_channels);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_path(name: &str) -> String {
+        format!("{}/{}", env!("CARGO_MANIFEST_DIR"), name)
+    }
+
+    fn decode(name: &str) -> Result<(hound::WavSpec, Vec<f64>), AudioError> {
+        let reader = WavReader::open(sample_path(name))?;
+        let spec = reader.spec();
+        Ok((spec, Clip::decode_samples_to_f64(reader)?))
+    }
+
+    fn raw_samples<T: hound::Sample>(name: &str) -> Result<Vec<T>, AudioError> {
+        let reader = WavReader::open(sample_path(name))?;
+        Ok(reader.into_samples::<T>().collect::<Result<_, _>>()?)
+    }
+
+    fn render(clip: &Clip, output_channels: u16, frames: usize) -> Vec<f64> {
+        let mut buffer = vec![0.0; frames * output_channels as usize];
+        for frame in 0..frames {
+            clip.process_sample(&mut buffer, 1.0, output_channels, frame as u64, frame);
+        }
+        buffer
+    }
+
+    #[test]
+    fn test_process_mono_to_stereo() {
+        let clip = Clip::from_samples(vec![0.5, -0.25], 1, 44100, 0);
+
+        assert_eq!(render(&clip, 2, 2), vec![0.5, 0.5, -0.25, -0.25]);
+    }
+
+    #[test]
+    fn test_process_stereo_to_mono() {
+        let clip = Clip::from_samples(vec![0.5, 0.25], 2, 44100, 0);
+
+        assert_eq!(render(&clip, 1, 1), vec![0.75]);
+    }
+
+    #[test]
+    fn test_process_quad_to_stereo_is_not_silent() {
+        let clip = Clip::from_samples(vec![0.1, 0.2, 0.3, 0.4], 4, 44100, 0);
+        let output = render(&clip, 2, 1);
+
+        let surround = std::f64::consts::FRAC_1_SQRT_2;
+        assert!((output[0] - (0.1 + 0.3 * surround)).abs() < f64::EPSILON);
+        assert!((output[1] - (0.2 + 0.4 * surround)).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn test_process_stereo_fills_surround_front() {
+        let clip = Clip::from_samples(vec![0.5, -0.5], 2, 44100, 0);
+
+        assert_eq!(render(&clip, 6, 1), vec![0.5, -0.5, 0.0, 0.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn test_process_with_channel_map() -> Result<(), AudioError> {
+        let mut clip = Clip::from_samples(vec![0.1, 0.2, 0.3, 0.4, 0.5, 0.6], 6, 44100, 0);
+        let mut channel_map = ChannelMap::new(6, 2);
+        channel_map.set_gain(2, 0, 0.5)?;
+        channel_map.route(5, 1)?;
+        clip.set_channel_map(channel_map)?;
+
+        let output = render(&clip, 2, 1);
+
+        assert!((output[0] - 0.15).abs() < f64::EPSILON);
+        assert!((output[1] - 0.6).abs() < f64::EPSILON);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_channel_map_mismatch() {
+        let mut clip = Clip::from_samples(vec![0.0; 4], 2, 44100, 0);
+
+        assert!(matches!(
+            clip.set_channel_map(ChannelMap::new(4, 2)),
+            Err(AudioError::ChannelMapMismatch {
+                expected: 2,
+                actual: 4
+            })
+        ));
+    }
+
+    #[test]
+    fn test_with_sample_rate_from_file() -> Result<(), AudioError> {
+        let clip = Clip::from_path(
+            sample_path("sample-i16-stereo.wav"),
+            4000,
+            8000,
+            ResamplerQuality::Fast,
+        )?;
+        let resampled = clip.with_sample_rate(16000, ResamplerQuality::Fast)?;
+
+        assert_eq!(resampled.sample_rate(), 16000);
+        assert_eq!(resampled.source_sample_rate(), 8000);
+        assert_eq!(resampled.start_time_in_samples(), 8000);
+        assert_eq!(
+            resampled.duration_in_samples(),
+            clip.duration_in_samples() * 2
+        );
+
+        let restored = resampled.with_sample_rate(8000, ResamplerQuality::Fast)?;
+
+        assert_eq!(restored.start_time_in_samples(), 4000);
+        assert_eq!(restored.data, clip.data);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_with_sample_rate_from_memory() -> Result<(), AudioError> {
+        let mut clip = Clip::from_samples(vec![0.25; 200], 2, 44100, 441);
+        clip.set_channel_map(ChannelMap::new(2, 2))?;
+
+        let resampled = clip.with_sample_rate(48000, ResamplerQuality::Balanced)?;
+
+        assert_eq!(resampled.start_time_in_samples(), 480);
+        assert_eq!(resampled.duration_in_samples(), 109);
+        assert_eq!(resampled.channel_map(), clip.channel_map());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_repeated_rate_changes_keep_positions() -> Result<(), AudioError> {
+        let mut clip = Clip::from_samples(vec![0.25; 2000], 1, 44100, 1001);
+        clip.set_warp_markers(vec![WarpMarker {
+            source_frame: 333,
+            timeline_frame: 401,
+        }])?;
+
+        let mut converted = clip.clone();
+        for rate in [48000, 22050, 96000, 32000, 44100] {
+            converted = converted.with_sample_rate(rate, ResamplerQuality::Fast)?;
+        }
+
+        assert_eq!(converted.start_time_in_samples(), 1001);
+        assert_eq!(converted.warp_markers(), clip.warp_markers());
+
+        // At the source rate the samples are shared with the source, not copied
+        let ClipSource::Memory(source) = converted.source() else {
+            panic!("memory clip lost its source");
+        };
+        assert!(Arc::ptr_eq(source, &converted.data));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_split_shares_the_source() -> Result<(), AudioError> {
+        let clip = Clip::from_path("sample-f32-stereo.wav", 100, 44100, ResamplerQuality::Fast)?;
+        let frame = clip.duration_in_samples() / 3;
+
+        let (first, second) = clip.split_at(frame)?;
+
+        assert!(Arc::ptr_eq(&first.data, &clip.data) && Arc::ptr_eq(&second.data, &clip.data));
+        assert!(matches!(second.source(), ClipSource::File(_)));
+        assert_eq!(second.start_time_in_samples(), 100 + frame);
+        assert_eq!(
+            [first.render(), second.render()].concat(),
+            clip.render().to_vec()
+        );
+        assert!(matches!(
+            clip.split_at(clip.duration_in_samples()),
+            Err(AudioError::InvalidSplitPosition(_))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_split_keeps_processing() -> Result<(), AudioError> {
+        let data: Vec<f64> = (0..8820)
+            .map(|i| (2.0 * std::f64::consts::PI * 220.0 * i as f64 / 44100.0).sin())
+            .collect();
+        let mut clip = Clip::from_samples(data, 1, 44100, 0);
+        clip.set_stretch(2.0)?;
+        clip.set_playback_rate(0.5)?;
+
+        let (first, second) = clip.split_at(clip.duration_in_samples() / 2)?;
+
+        for half in [&first, &second] {
+            assert_eq!(half.stretch(), 2.0);
+            assert_eq!(half.playback_rate(), 0.5);
+            assert_eq!(half.source_frames(), 4410);
+            assert!(half.duration_in_samples().abs_diff(17640) <= 2);
+        }
+        assert_eq!(second.region, 4410..8820);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_stretch_changes_duration() -> Result<(), AudioError> {
+        let data: Vec<f64> = (0..8820)
+            .map(|i| (2.0 * std::f64::consts::PI * 220.0 * i as f64 / 44100.0).sin())
+            .collect();
+        let mut clip = Clip::from_samples(data.clone(), 1, 44100, 1000);
+
+        clip.set_stretch(1.5)?;
+        assert_eq!(clip.duration_in_samples(), 13230);
+        assert_eq!(clip.end_time_in_samples(), 14230);
+        assert_eq!(clip.sample_count(), data.len());
+
+        let mut buffer = vec![0.0f64; 1];
+        clip.process_sample(&mut buffer, 1.0, 1, 14000, 0);
+        assert!(buffer[0] != 0.0);
+
+        clip.set_warp_markers(vec![WarpMarker {
+            source_frame: 4410,
+            timeline_frame: 2205,
+        }])?;
+        // 2205 + 4410 frames, both stretched
+        assert_eq!(clip.duration_in_samples(), 9923);
+
+        assert!(matches!(
+            clip.set_stretch(0.0),
+            Err(AudioError::InvalidStretch(_))
+        ));
+        assert!(matches!(
+            clip.set_warp_markers(vec![
+                WarpMarker {
+                    source_frame: 100,
+                    timeline_frame: 200,
+                },
+                WarpMarker {
+                    source_frame: 200,
+                    timeline_frame: 200,
+                },
+            ]),
+            Err(AudioError::InvalidWarpMarkers)
+        ));
+
+        clip.clear_warp()?;
+        assert_eq!(clip.duration_in_samples(), 8820);
+        assert_eq!(clip.render(), data.as_slice());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_peaks_whole_clip() {
+        let clip = Clip::from_samples(vec![0.5, -0.25, -1.0, 0.75], 2, 44100, 0);
+        let peaks = clip.peaks(0..2, 1);
+
+        assert_eq!(peaks.len(), 2);
+        assert_eq!(peaks[0][0].min, -1.0);
+        assert_eq!(peaks[0][0].max, 0.5);
+        assert_eq!(peaks[1][0].min, -0.25);
+        assert_eq!(peaks[1][0].max, 0.75);
+        assert!(clip.peak_file_path().is_none());
+    }
+
+    #[test]
+    fn test_peaks_follow_processing() -> Result<(), AudioError> {
+        let mut clip = Clip::from_samples(vec![0.5; 1000], 1, 1000, 0);
+        clip.set_stretch(2.0)?;
+
+        // The stretched clip is twice as long, so is its waveform
+        let peaks = clip.peaks(0..2000, 4);
+        assert!(peaks[0].iter().all(|peak| peak.max > 0.4));
+
+        clip.clear_warp()?;
+        assert_eq!(clip.peaks(1000..2000, 4), vec![Vec::new()]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_peak_file_path() -> Result<(), AudioError> {
+        let clip = Clip::from_path(
+            sample_path("sample-u8-stereo.wav"),
+            0,
+            8000,
+            ResamplerQuality::Fast,
+        )?;
+
+        assert_eq!(
+            clip.peak_file_path(),
+            Some(PathBuf::from(sample_path("sample-u8-stereo.wav.pk")))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_cached_peaks_rebuilt_after_edit() -> Result<(), anyhow::Error> {
+        let path = std::env::temp_dir().join(format!("zari-edited-{}.wav", std::process::id()));
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: 8000,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let write = |sample: i16| -> Result<(), anyhow::Error> {
+            let mut writer = hound::WavWriter::create(&path, spec)?;
+            for _ in 0..800 {
+                writer.write_sample(sample)?;
             }
+            writer.finalize()?;
+            Ok(())
+        };
+
+        write(i16::MAX / 2)?;
+        Clip::from_path(&path, 0, 8000, ResamplerQuality::Fast)?.save_peaks()?;
+
+        // Rewritten in place at the same length, the old cache must not be used
+        write(i16::MAX / 4)?;
+        File::options()
+            .write(true)
+            .open(&path)?
+            .set_modified(std::time::SystemTime::now() + std::time::Duration::from_secs(1))?;
+        let clip = Clip::from_path(&path, 0, 8000, ResamplerQuality::Fast);
+        let peak_file = PathBuf::from(format!("{}.pk", path.display()));
+        std::fs::remove_file(&path)?;
+        std::fs::remove_file(peak_file)?;
+
+        let peaks = clip?.peaks(0..800, 1);
+        assert!((peaks[0][0].max - 0.25).abs() < 0.01);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_pitch_track_stereo() -> Result<(), AudioError> {
+        let data: Vec<f64> = (0..22050)
+            .flat_map(|i| {
+                let sample = (2.0 * std::f64::consts::PI * 330.0 * i as f64 / 44100.0).sin();
+                [sample, sample]
+            })
+            .collect();
+        let clip = Clip::from_samples(data, 2, 44100, 1000);
+        let track = clip.pitch_track()?;
+
+        assert!(!track.is_empty());
+        assert!(track[0].time_seconds < 0.05);
+        for point in track {
+            let frequency = point.frequency.unwrap();
+            assert!((frequency - 330.0).abs() < 1.0);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_u8_from_f64_sample() {
+        assert_eq!(u8::from_f64_sample(0.0), 128);
+        assert_eq!(u8::from_f64_sample(-1.0), 0);
+        assert_eq!(u8::from_f64_sample(1.0), u8::MAX);
+        assert_eq!(u8::from_f64_sample(2.0), u8::MAX);
+        assert_eq!(u8::from_f64_sample(-2.0), 0);
+    }
+
+    #[test]
+    fn test_u8_roundtrip_every_value() {
+        for raw in u8::MIN..=u8::MAX {
+            let signed = (raw as i16 - 128) as i8;
+            let sample = Utils::convert_sample_to_f64(signed, Scale::U8);
+            assert_eq!(u8::from_f64_sample(sample), raw);
+        }
+    }
+
+    #[test]
+    fn test_decode_u8_file_roundtrip() -> Result<(), AudioError> {
+        let (spec, decoded) = decode("sample-u8-stereo.wav")?;
+        let raw = raw_samples::<i8>("sample-u8-stereo.wav")?;
+
+        assert_eq!(spec.bits_per_sample, 8);
+        assert_eq!(decoded.len(), raw.len());
+
+        for (sample, raw) in decoded.iter().zip(raw) {
+            assert!((-1.0..1.0).contains(sample));
+            assert_eq!(u8::from_f64_sample(*sample), (raw as i16 + 128) as u8);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_u8_file_has_no_dc_offset() -> Result<(), AudioError> {
+        let (_, u8_decoded) = decode("sample-u8-stereo.wav")?;
+        let (_, i16_decoded) = decode("sample-i16-stereo.wav")?;
+
+        let mean = |samples: &[f64]| samples.iter().sum::<f64>() / samples.len() as f64;
+
+        assert!((mean(&u8_decoded) - mean(&i16_decoded)).abs() < 1.0 / 128.0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_i16_file_roundtrip() -> Result<(), AudioError> {
+        let (spec, decoded) = decode("sample-i16-stereo.wav")?;
+        let raw = raw_samples::<i16>("sample-i16-stereo.wav")?;
+
+        assert_eq!(spec.bits_per_sample, 16);
+        assert_eq!(decoded.len(), raw.len());
+
+        for (sample, raw) in decoded.iter().zip(raw) {
+            assert_eq!(i16::from_f64_sample(*sample), raw.max(-i16::MAX));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_i24_file_roundtrip() -> Result<(), AudioError> {
+        let (spec, decoded) = decode("sample-i24-stereo.wav")?;
+        let raw = raw_samples::<i32>("sample-i24-stereo.wav")?;
+
+        assert_eq!(spec.bits_per_sample, 24);
+        assert_eq!(decoded.len(), raw.len());
+
+        let i24_max = ((1 << 23) - 1) as f64;
+        for (sample, raw) in decoded.iter().zip(raw) {
+            assert_eq!((sample * i24_max).round() as i32, raw.max(-(1 << 23) + 1));
         }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_f32_file_roundtrip() -> Result<(), AudioError> {
+        let (spec, decoded) = decode("sample-f32-stereo.wav")?;
+        let raw = raw_samples::<f32>("sample-f32-stereo.wav")?;
+
+        assert_eq!(spec.bits_per_sample, 32);
+        assert_eq!(decoded.len(), raw.len());
+
+        for (sample, raw) in decoded.iter().zip(raw) {
+            assert_eq!(f32::from_f64_sample(*sample), raw.clamp(-1.0, 1.0));
+        }
+
+        Ok(())
     }
 }
@@ -0,0 +1,264 @@
+use std::f64::consts::FRAC_PI_2;
+
+use crate::engine::Resampler;
+
+// Which take plays over `start..end` on the timeline
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompSegment {
+    pub take: usize,
+    pub start: u64,
+    pub end: u64,
+}
+
+// The comp lane: sorted, non-overlapping segments, with a crossfade centred on every switch
+// between adjacent segments and a short fade where a segment borders on nothing
+#[derive(Debug, Clone, PartialEq)]
+pub struct Comp {
+    segments: Vec<CompSegment>,
+    crossfade_frames: u64,
+    // The segments at the rate they were last edited at, once the rate has changed. Rate
+    // changes convert from here rather than from the previous rate, so rounding doesn't
+    // build up.
+    placement: Option<(u32, Vec<CompSegment>)>,
+}
+
+impl Comp {
+    pub fn new(crossfade_frames: u64) -> Self {
+        Comp {
+            segments: Vec::new(),
+            crossfade_frames,
+            placement: None,
+        }
+    }
+
+    pub fn segments(&self) -> &[CompSegment] {
+        &self.segments
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.segments.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.segments.clear();
+        self.placement = None;
+    }
+
+    pub fn crossfade_frames(&self) -> u64 {
+        self.crossfade_frames
+    }
+
+    pub fn set_crossfade_frames(&mut self, crossfade_frames: u64) {
+        self.crossfade_frames = crossfade_frames;
+    }
+
+    // Whatever was selected over the range before is replaced
+    pub fn select(&mut self, take: usize, start: u64, end: u64) {
+        if end <= start {
+            return;
+        }
+
+        self.placement = None;
+        let mut segments = Vec::with_capacity(self.segments.len() + 2);
+        for segment in self.segments.drain(..) {
+            if segment.start < start {
+                segments.push(CompSegment {
+                    end: segment.end.min(start),
+                    ..segment
+                });
+            }
+            if segment.end > end {
+                segments.push(CompSegment {
+                    start: segment.start.max(end),
+                    ..segment
+                });
+            }
+        }
+        segments.push(CompSegment { take, start, end });
+        segments.sort_by_key(|segment| segment.start);
+
+        // Touching segments of the same take would otherwise crossfade with themselves
+        for segment in segments {
+            match self.segments.last_mut() {
+                Some(last) if last.take == segment.take && last.end == segment.start => {
+                    last.end = segment.end;
+                }
+                _ => self.segments.push(segment),
+            }
+        }
+    }
+
+    // Drops the selection of a removed take and renumbers the ones after it
+    pub fn remove_take(&mut self, take: usize) {
+        self.placement = None;
+        self.segments.retain(|segment| segment.take != take);
+        self.segments
+            .iter_mut()
+            .filter(|segment| segment.take > take)
+            .for_each(|segment| segment.take -= 1);
+    }
+
+    // Segment positions follow a sample rate change, rounded like clip positions. The
+    // crossfade is set separately.
+    pub fn with_sample_rate(&self, sample_rate: u32, previous_sample_rate: u32) -> Self {
+        let (placed_rate, placed) = self
+            .placement
+            .clone()
+            .unwrap_or_else(|| (previous_sample_rate, self.segments.clone()));
+        let scale = |frames: u64| {
+            Resampler::output_frames(frames as usize, sample_rate, placed_rate) as u64
+        };
+
+        Comp {
+            segments: placed
+                .iter()
+                .map(|segment| CompSegment {
+                    start: scale(segment.start),
+                    end: scale(segment.end),
+                    ..*segment
+                })
+                .collect(),
+            crossfade_frames: self.crossfade_frames,
+            placement: Some((placed_rate, placed)),
+        }
+    }
+
+    // The takes that sound at a timeline position and their gains
+    pub fn sources_at(&self, position: u64) -> [Option<(usize, f64)>; 2] {
+        let index = self
+            .segments
+            .partition_point(|segment| segment.end <= position);
+        let Some(segment) = self
+            .segments
+            .get(index)
+            .filter(|segment| segment.start <= position)
+        else {
+            return [None, None];
+        };
+
+        let fade = self.crossfade_frames;
+        let half = fade / 2;
+        let previous = index
+            .checked_sub(1)
+            .map(|index| &self.segments[index])
+            .filter(|previous| previous.end == segment.start);
+        let next = self
+            .segments
+            .get(index + 1)
+            .filter(|next| next.start == segment.end);
+
+        if let Some(previous) = previous
+            && position < segment.start + half
+        {
+            let x = (position + half - segment.start) as f64 / (2 * half) as f64;
+            return [
+                Some((previous.take, Self::fade_out(x))),
+                Some((segment.take, Self::fade_in(x))),
+            ];
+        }
+        if let Some(next) = next
+            && position + half >= segment.end
+            && half > 0
+        {
+            let x = (position + half - segment.end) as f64 / (2 * half) as f64;
+            return [
+                Some((segment.take, Self::fade_out(x))),
+                Some((next.take, Self::fade_in(x))),
+            ];
+        }
+
+        let mut gain = 1.0;
+        if previous.is_none() && position < segment.start + fade {
+            gain *= Self::fade_in((position - segment.start) as f64 / fade as f64);
+        }
+        if next.is_none() && position + fade >= segment.end && fade > 0 {
+            gain *= Self::fade_in((segment.end - position) as f64 / fade as f64);
+        }
+
+        [Some((segment.take, gain)), None]
+    }
+
+    // Equal power, the takes are different performances rather than copies of each other
+    pub fn fade_in(x: f64) -> f64 {
+        (x.clamp(0.0, 1.0) * FRAC_PI_2).sin()
+    }
+
+    pub fn fade_out(x: f64) -> f64 {
+        (x.clamp(0.0, 1.0) * FRAC_PI_2).cos()
+    }
+}
+
+impl Default for Comp {
+    fn default() -> Self {
+        // 10 ms at 44.1 kHz
+        Comp::new(441)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn segment(take: usize, start: u64, end: u64) -> CompSegment {
+        CompSegment { take, start, end }
+    }
+
+    #[test]
+    fn test_select_replaces_range() {
+        let mut comp = Comp::new(0);
+        comp.select(0, 0, 1000);
+        comp.select(1, 400, 600);
+        assert_eq!(
+            comp.segments(),
+            [
+                segment(0, 0, 400),
+                segment(1, 400, 600),
+                segment(0, 600, 1000)
+            ]
+        );
+
+        comp.select(0, 400, 600);
+        assert_eq!(comp.segments(), [segment(0, 0, 1000)]);
+
+        comp.select(2, 900, 1200);
+        comp.remove_take(1);
+        assert_eq!(comp.segments(), [segment(0, 0, 900), segment(1, 900, 1200)]);
+    }
+
+    #[test]
+    fn test_crossfade_at_switch() {
+        let mut comp = Comp::new(100);
+        comp.select(0, 0, 1000);
+        comp.select(1, 1000, 2000);
+
+        assert_eq!(comp.sources_at(500), [Some((0, 1.0)), None]);
+        assert_eq!(comp.sources_at(2000), [None, None]);
+
+        let [Some((0, a)), Some((1, b))] = comp.sources_at(1000) else {
+            panic!("expected a crossfade");
+        };
+        assert!((a * a + b * b - 1.0).abs() < 1e-9);
+        assert!((a - b).abs() < 1e-9);
+
+        let [Some((0, a)), Some((1, b))] = comp.sources_at(950) else {
+            panic!("expected a crossfade");
+        };
+        assert!((a - 1.0).abs() < 1e-9 && b.abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_fades_at_outer_edges() {
+        let mut comp = Comp::new(100);
+        comp.select(0, 1000, 2000);
+
+        assert_eq!(comp.sources_at(1000), [Some((0, 0.0)), None]);
+        let [Some((0, gain)), None] = comp.sources_at(1050) else {
+            panic!("expected a single source");
+        };
+        assert!(gain > 0.0 && gain < 1.0);
+        let [Some((0, gain)), None] = comp.sources_at(1999) else {
+            panic!("expected a single source");
+        };
+        assert!(gain < 0.05);
+    }
+}
@@ -0,0 +1,336 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use cpal::{
+    BuildStreamError, Device, Host, I24, SampleFormat, SizedSample, Stream, StreamConfig,
+    StreamError, StreamInstant,
+    traits::{DeviceTrait, HostTrait, StreamTrait},
+};
+
+use crate::engine::{
+    AudioError, Backend, ConfigRange, DeviceInfo, FromF64Sample, LatencyMeter, StreamSettings,
+    Timeline, ToF64Sample, TransportEvent,
+};
+
+pub struct CpalBackend {
+    host: Host,
+    input_device: Option<Device>,
+    output_device: Option<Device>,
+    output_stream: Option<Stream>,
+    input_stream: Option<Stream>,
+}
+
+// Calls `Self::$build::<T>(..)` with the sample type that matches the format
+macro_rules! with_sample_type {
+    ($format:expr, $build:ident($($arg:expr),*)) => {
+        match $format {
+            SampleFormat::I8 => Self::$build::<i8>($($arg),*),
+            SampleFormat::I16 => Self::$build::<i16>($($arg),*),
+            SampleFormat::I24 => Self::$build::<I24>($($arg),*),
+            SampleFormat::I32 => Self::$build::<i32>($($arg),*),
+            SampleFormat::I64 => Self::$build::<i64>($($arg),*),
+            SampleFormat::U8 => Self::$build::<u8>($($arg),*),
+            SampleFormat::U16 => Self::$build::<u16>($($arg),*),
+            SampleFormat::U32 => Self::$build::<u32>($($arg),*),
+            SampleFormat::U64 => Self::$build::<u64>($($arg),*),
+            SampleFormat::F32 => Self::$build::<f32>($($arg),*),
+            SampleFormat::F64 => Self::$build::<f64>($($arg),*),
+            _ => Err(BuildStreamError::StreamConfigNotSupported),
+        }
+    };
+}
+
+// The callback should come round once per buffer; a gap of more than two buffers means the
+// device ran out of audio in between
+struct XrunDetector {
+    sample_rate: u32,
+    previous: Option<(StreamInstant, usize)>,
+}
+
+impl XrunDetector {
+    fn new(sample_rate: u32) -> Self {
+        XrunDetector {
+            sample_rate,
+            previous: None,
+        }
+    }
+
+    fn check(&mut self, callback: StreamInstant, frames: usize) -> bool {
+        let late = self.previous.is_some_and(|(previous, previous_frames)| {
+            let expected =
+                Duration::from_secs_f64(previous_frames as f64 / self.sample_rate as f64);
+            callback
+                .duration_since(&previous)
+                .is_some_and(|elapsed| elapsed > expected * 2)
+        });
+        self.previous = Some((callback, frames));
+        late
+    }
+}
+
+impl CpalBackend {
+    // Every format cpal offers, most preferred first
+    const FORMATS: &[SampleFormat] = &[
+        SampleFormat::F32,
+        SampleFormat::I32,
+        SampleFormat::I24,
+        SampleFormat::I16,
+        SampleFormat::F64,
+        SampleFormat::I64,
+        SampleFormat::U32,
+        SampleFormat::U16,
+        SampleFormat::U8,
+        SampleFormat::I8,
+        SampleFormat::U64,
+    ];
+
+    // Playback works without an input, recording reports the missing device
+    pub fn new() -> Result<Self, AudioError> {
+        let host = cpal::default_host();
+        let output_device = host
+            .default_output_device()
+            .ok_or(AudioError::OutputDeviceNotFound)?;
+        let input_device = host.default_input_device();
+
+        Ok(CpalBackend {
+            host,
+            input_device,
+            output_device: Some(output_device),
+            output_stream: None,
+            input_stream: None,
+        })
+    }
+
+    fn error_callback(timeline: &Arc<Mutex<Timeline>>) -> impl FnMut(StreamError) + Send + 'static {
+        let timeline = timeline.clone();
+        move |err| {
+            let event = match err {
+                StreamError::DeviceNotAvailable => TransportEvent::DeviceDisconnected,
+                StreamError::BackendSpecific { err } => {
+                    TransportEvent::StreamError(err.to_string())
+                }
+            };
+            if let Ok(mut timeline) = timeline.lock() {
+                timeline.notify(event);
+            }
+        }
+    }
+
+    fn build_output_stream<T>(
+        device: &Device,
+        config: &StreamConfig,
+        timeline: Arc<Mutex<Timeline>>,
+        latency: Arc<LatencyMeter>,
+    ) -> Result<Stream, BuildStreamError>
+    where
+        T: SizedSample + FromF64Sample,
+    {
+        let channels = config.channels;
+        let mut xruns = XrunDetector::new(config.sample_rate.0);
+        let error_callback = Self::error_callback(&timeline);
+
+        device.build_output_stream(
+            config,
+            move |data: &mut [T], info: &cpal::OutputCallbackInfo| {
+                let timestamp = info.timestamp();
+                if let Some(output) = timestamp.playback.duration_since(&timestamp.callback) {
+                    latency.set_output(output);
+                }
+                let xrun = xruns.check(timestamp.callback, data.len() / channels as usize);
+                if let Ok(mut timeline) = timeline.lock() {
+                    if xrun {
+                        timeline.notify(TransportEvent::Xrun);
+                    }
+                    timeline.process(data, channels);
+                }
+            },
+            error_callback,
+            None,
+        )
+    }
+
+    fn build_input_stream<T>(
+        device: &Device,
+        config: &StreamConfig,
+        timeline: Arc<Mutex<Timeline>>,
+        latency: Arc<LatencyMeter>,
+    ) -> Result<Stream, BuildStreamError>
+    where
+        T: SizedSample + ToF64Sample,
+    {
+        let error_callback = Self::error_callback(&timeline);
+
+        device.build_input_stream(
+            config,
+            move |data: &[T], info: &cpal::InputCallbackInfo| {
+                let timestamp = info.timestamp();
+                if let Some(input) = timestamp.callback.duration_since(&timestamp.capture) {
+                    latency.set_input(input);
+                }
+                if let Ok(mut timeline) = timeline.lock() {
+                    timeline.record(data);
+                }
+            },
+            error_callback,
+            None,
+        )
+    }
+}
+
+impl Backend for CpalBackend {
+    const OUTPUT_FORMATS: &'static [SampleFormat] = Self::FORMATS;
+    const INPUT_FORMATS: &'static [SampleFormat] = Self::FORMATS;
+
+    fn devices(&self) -> Result<Vec<DeviceInfo>, AudioError> {
+        let default_input = self
+            .host
+            .default_input_device()
+            .and_then(|device| device.name().ok());
+        let default_output = self
+            .host
+            .default_output_device()
+            .and_then(|device| device.name().ok());
+
+        // A device whose name can't be read can't be selected either, so it is left out
+        // rather than failing the whole list
+        Ok(self
+            .host
+            .devices()?
+            .filter_map(|device| {
+                DeviceInfo::from_device(
+                    &device,
+                    default_input.as_deref(),
+                    default_output.as_deref(),
+                )
+                .ok()
+            })
+            .collect())
+    }
+
+    fn output_device_name(&self) -> Option<String> {
+        self.output_device
+            .as_ref()
+            .and_then(|device| device.name().ok())
+    }
+
+    fn input_device_name(&self) -> Option<String> {
+        self.input_device
+            .as_ref()
+            .and_then(|device| device.name().ok())
+    }
+
+    fn set_output_device(&mut self, name: &str) -> Result<(), AudioError> {
+        let device = self
+            .host
+            .output_devices()?
+            .find(|device| device.name().is_ok_and(|device_name| device_name == name))
+            .ok_or_else(|| AudioError::DeviceNotFound(name.to_string()))?;
+        self.output_device = Some(device);
+        Ok(())
+    }
+
+    fn set_input_device(&mut self, name: &str) -> Result<(), AudioError> {
+        let device = self
+            .host
+            .input_devices()?
+            .find(|device| device.name().is_ok_and(|device_name| device_name == name))
+            .ok_or_else(|| AudioError::DeviceNotFound(name.to_string()))?;
+        self.input_device = Some(device);
+        Ok(())
+    }
+
+    fn output_configs(&self) -> Result<Vec<ConfigRange>, AudioError> {
+        let device = self
+            .output_device
+            .as_ref()
+            .ok_or(AudioError::OutputDeviceNotFound)?;
+        Ok(device
+            .supported_output_configs()?
+            .map(ConfigRange::from)
+            .collect())
+    }
+
+    fn input_configs(&self) -> Result<Vec<ConfigRange>, AudioError> {
+        let device = self
+            .input_device
+            .as_ref()
+            .ok_or(AudioError::InputDeviceNotFound)?;
+        Ok(device
+            .supported_input_configs()?
+            .map(ConfigRange::from)
+            .collect())
+    }
+
+    fn start_output(
+        &mut self,
+        settings: StreamSettings,
+        timeline: Arc<Mutex<Timeline>>,
+        latency: Arc<LatencyMeter>,
+    ) -> Result<(), AudioError> {
+        let output_device = self
+            .output_device
+            .as_ref()
+            .ok_or(AudioError::OutputDeviceNotFound)?;
+
+        let config = settings.stream_config();
+        let stream = with_sample_type!(
+            settings.sample_format,
+            build_output_stream(output_device, &config, timeline, latency)
+        )
+        .map_err(AudioError::StreamConfigNotSupported)?;
+
+        stream.play().map_err(AudioError::PlayStreamError)?;
+
+        self.output_stream = Some(stream);
+
+        Ok(())
+    }
+
+    fn start_input(
+        &mut self,
+        settings: StreamSettings,
+        timeline: Arc<Mutex<Timeline>>,
+        latency: Arc<LatencyMeter>,
+    ) -> Result<(), AudioError> {
+        let input_device = self
+            .input_device
+            .as_ref()
+            .ok_or(AudioError::InputDeviceNotFound)?;
+
+        let config = settings.stream_config();
+        let stream = with_sample_type!(
+            settings.sample_format,
+            build_input_stream(input_device, &config, timeline, latency)
+        )
+        .map_err(AudioError::StreamConfigNotSupported)?;
+
+        stream.play().map_err(AudioError::PlayStreamError)?;
+
+        self.input_stream = Some(stream);
+
+        Ok(())
+    }
+
+    fn stop_output(&mut self) {
+        self.output_stream = None;
+    }
+
+    fn stop_input(&mut self) {
+        self.input_stream = None;
+    }
+
+    fn is_output_running(&self) -> bool {
+        self.output_stream.is_some()
+    }
+
+    fn is_input_running(&self) -> bool {
+        self.input_stream.is_some()
+    }
+
+    fn wait(&mut self, duration: Duration) -> Result<(), AudioError> {
+        std::thread::sleep(duration);
+        Ok(())
+    }
+}
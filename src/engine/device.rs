@@ -0,0 +1,364 @@
+use cpal::{
+    BufferSize, Device, SampleFormat, SampleRate, StreamConfig, SupportedBufferSize,
+    SupportedStreamConfigRange, traits::DeviceTrait,
+};
+
+use crate::engine::AudioError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConfigRange {
+    pub channels: u16,
+    pub sample_format: SampleFormat,
+    pub min_sample_rate: u32,
+    pub max_sample_rate: u32,
+    pub buffer_size: SupportedBufferSize,
+}
+
+impl From<SupportedStreamConfigRange> for ConfigRange {
+    fn from(range: SupportedStreamConfigRange) -> Self {
+        ConfigRange {
+            channels: range.channels(),
+            sample_format: range.sample_format(),
+            min_sample_rate: range.min_sample_rate().0,
+            max_sample_rate: range.max_sample_rate().0,
+            buffer_size: *range.buffer_size(),
+        }
+    }
+}
+
+// What the caller would like; negotiation settles on the closest thing the device offers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConfigRequest {
+    pub channels: u16,
+    pub sample_format: SampleFormat,
+    pub sample_rate: u32,
+    pub buffer_size: Option<u32>,
+}
+
+// Small buffers while a singer listens back live, large ones when many tracks are mixed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LatencyProfile {
+    Tracking,
+    Mixing,
+}
+
+impl LatencyProfile {
+    pub fn buffer_size(self) -> u32 {
+        match self {
+            LatencyProfile::Tracking => 128,
+            LatencyProfile::Mixing => 1024,
+        }
+    }
+}
+
+// The config a stream was actually opened with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StreamSettings {
+    pub channels: u16,
+    pub sample_format: SampleFormat,
+    pub sample_rate: u32,
+    pub buffer_size: Option<u32>,
+}
+
+impl StreamSettings {
+    pub fn stream_config(&self) -> StreamConfig {
+        StreamConfig {
+            channels: self.channels,
+            sample_rate: SampleRate(self.sample_rate),
+            buffer_size: self
+                .buffer_size
+                .map_or(BufferSize::Default, BufferSize::Fixed),
+        }
+    }
+}
+
+impl ConfigRequest {
+    // `formats` are the ones the stream can be opened with, most preferred first. Having at
+    // least the requested channels matters most, then the rate, then the format.
+    pub fn negotiate(
+        &self,
+        configs: &[ConfigRange],
+        formats: &[SampleFormat],
+    ) -> Result<StreamSettings, AudioError> {
+        if self.channels == 0 {
+            return Err(AudioError::InvalidChannelCount(self.channels));
+        }
+        if self.sample_rate == 0 {
+            return Err(AudioError::InvalidSampleRate(self.sample_rate));
+        }
+        if self.buffer_size == Some(0) {
+            return Err(AudioError::InvalidBufferSize(0));
+        }
+
+        let format_rank = |format: SampleFormat| {
+            if format == self.sample_format {
+                Some(0)
+            } else {
+                formats
+                    .iter()
+                    .position(|supported| *supported == format)
+                    .map(|index| index + 1)
+            }
+        };
+
+        configs
+            .iter()
+            .filter(|config| {
+                config.channels > 0 && config.min_sample_rate <= config.max_sample_rate
+            })
+            .filter_map(|config| {
+                let rank = format_rank(config.sample_format)?;
+                let sample_rate = self
+                    .sample_rate
+                    .clamp(config.min_sample_rate, config.max_sample_rate);
+                let cost = (
+                    config.channels < self.channels,
+                    sample_rate.abs_diff(self.sample_rate),
+                    config.channels.abs_diff(self.channels),
+                    rank,
+                );
+                Some((cost, config, sample_rate))
+            })
+            .min_by_key(|(cost, _, _)| *cost)
+            .map(|(_, config, sample_rate)| StreamSettings {
+                channels: config.channels,
+                sample_format: config.sample_format,
+                sample_rate,
+                buffer_size: match (self.buffer_size, config.buffer_size) {
+                    (Some(frames), SupportedBufferSize::Range { min, max }) => {
+                        Some(frames.max(min).min(max))
+                    }
+                    _ => None,
+                },
+            })
+            .ok_or(AudioError::UnsupportedConfig {
+                channels: self.channels,
+                sample_format: self.sample_format,
+                sample_rate: self.sample_rate,
+            })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceInfo {
+    pub name: String,
+    pub is_default_input: bool,
+    pub is_default_output: bool,
+    pub input_configs: Vec<ConfigRange>,
+    pub output_configs: Vec<ConfigRange>,
+}
+
+impl DeviceInfo {
+    pub fn from_device(
+        device: &Device,
+        default_input: Option<&str>,
+        default_output: Option<&str>,
+    ) -> Result<Self, AudioError> {
+        let name = device.name()?;
+
+        // A device that only works in one direction fails to list configs for the other
+        let input_configs = device
+            .supported_input_configs()
+            .map(|configs| configs.map(ConfigRange::from).collect())
+            .unwrap_or_default();
+        let output_configs = device
+            .supported_output_configs()
+            .map(|configs| configs.map(ConfigRange::from).collect())
+            .unwrap_or_default();
+
+        Ok(DeviceInfo {
+            is_default_input: default_input == Some(name.as_str()),
+            is_default_output: default_output == Some(name.as_str()),
+            name,
+            input_configs,
+            output_configs,
+        })
+    }
+
+    pub fn is_input(&self) -> bool {
+        !self.input_configs.is_empty()
+    }
+
+    pub fn is_output(&self) -> bool {
+        !self.output_configs.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FORMATS: &[SampleFormat] = &[SampleFormat::F32, SampleFormat::I16];
+
+    fn range(channels: u16, sample_format: SampleFormat, min: u32, max: u32) -> ConfigRange {
+        ConfigRange {
+            channels,
+            sample_format,
+            min_sample_rate: min,
+            max_sample_rate: max,
+            buffer_size: SupportedBufferSize::Range { min: 64, max: 4096 },
+        }
+    }
+
+    // What a typical USB interface reports
+    fn mock_device() -> Vec<ConfigRange> {
+        vec![
+            range(2, SampleFormat::I16, 44100, 48000),
+            range(2, SampleFormat::F32, 44100, 48000),
+            range(4, SampleFormat::F32, 44100, 48000),
+            range(1, SampleFormat::F32, 8000, 96000),
+        ]
+    }
+
+    fn request(channels: u16, sample_format: SampleFormat, sample_rate: u32) -> ConfigRequest {
+        ConfigRequest {
+            channels,
+            sample_format,
+            sample_rate,
+            buffer_size: None,
+        }
+    }
+
+    #[test]
+    fn test_rate_bounds_are_inclusive() -> Result<(), AudioError> {
+        for rate in [44100, 48000] {
+            let settings =
+                request(2, SampleFormat::F32, rate).negotiate(&mock_device(), FORMATS)?;
+            assert_eq!(
+                settings,
+                StreamSettings {
+                    channels: 2,
+                    sample_format: SampleFormat::F32,
+                    sample_rate: rate,
+                    buffer_size: None,
+                }
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_picks_closest_config() -> Result<(), AudioError> {
+        // Only the mono config reaches 96 kHz, but a stereo request keeps its channels
+        let settings = request(2, SampleFormat::F32, 96000).negotiate(&mock_device(), FORMATS)?;
+        assert_eq!((settings.channels, settings.sample_rate), (2, 48000));
+        let settings = request(1, SampleFormat::F32, 96000).negotiate(&mock_device(), FORMATS)?;
+        assert_eq!((settings.channels, settings.sample_rate), (1, 96000));
+
+        // Enough channels beat a rate in range
+        let settings = request(2, SampleFormat::F32, 50000).negotiate(&mock_device(), FORMATS)?;
+        assert_eq!((settings.channels, settings.sample_rate), (2, 48000));
+
+        // More channels are preferred over fewer
+        let settings = request(3, SampleFormat::F32, 48000).negotiate(&mock_device(), FORMATS)?;
+        assert_eq!(settings.channels, 4);
+
+        // The requested format wins when offered, otherwise the preferred one
+        let settings = request(2, SampleFormat::I16, 48000).negotiate(&mock_device(), FORMATS)?;
+        assert_eq!(settings.sample_format, SampleFormat::I16);
+        let settings = request(2, SampleFormat::I32, 48000).negotiate(&mock_device(), FORMATS)?;
+        assert_eq!(settings.sample_format, SampleFormat::F32);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_buffer_size_is_clamped() -> Result<(), AudioError> {
+        let mut configs = mock_device();
+        let request = ConfigRequest {
+            buffer_size: Some(16),
+            ..request(2, SampleFormat::F32, 48000)
+        };
+        assert_eq!(request.negotiate(&configs, FORMATS)?.buffer_size, Some(64));
+
+        configs
+            .iter_mut()
+            .for_each(|config| config.buffer_size = SupportedBufferSize::Unknown);
+        assert_eq!(request.negotiate(&configs, FORMATS)?.buffer_size, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_latency_profiles() -> Result<(), AudioError> {
+        for (profile, frames) in [
+            (LatencyProfile::Tracking, 128),
+            (LatencyProfile::Mixing, 1024),
+        ] {
+            let request = ConfigRequest {
+                buffer_size: Some(profile.buffer_size()),
+                ..request(2, SampleFormat::F32, 48000)
+            };
+            assert_eq!(
+                request.negotiate(&mock_device(), FORMATS)?.buffer_size,
+                Some(frames)
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_negotiation_errors() {
+        assert!(matches!(
+            request(2, SampleFormat::F32, 48000).negotiate(&[], FORMATS),
+            Err(AudioError::UnsupportedConfig { .. })
+        ));
+        assert!(matches!(
+            request(2, SampleFormat::U8, 48000)
+                .negotiate(&[range(2, SampleFormat::U8, 48000, 48000)], FORMATS),
+            Ok(StreamSettings {
+                sample_format: SampleFormat::U8,
+                ..
+            })
+        ));
+        assert!(matches!(
+            request(2, SampleFormat::F32, 48000)
+                .negotiate(&[range(2, SampleFormat::I8, 48000, 48000)], FORMATS),
+            Err(AudioError::UnsupportedConfig { .. })
+        ));
+        assert!(matches!(
+            request(0, SampleFormat::F32, 48000).negotiate(&mock_device(), FORMATS),
+            Err(AudioError::InvalidChannelCount(0))
+        ));
+        assert!(matches!(
+            request(2, SampleFormat::F32, 0).negotiate(&mock_device(), FORMATS),
+            Err(AudioError::InvalidSampleRate(0))
+        ));
+    }
+
+    #[test]
+    fn test_config_range_from_cpal() {
+        let range = SupportedStreamConfigRange::new(
+            2,
+            SampleRate(44100),
+            SampleRate(96000),
+            SupportedBufferSize::Unknown,
+            SampleFormat::F32,
+        );
+
+        assert_eq!(
+            ConfigRange::from(range),
+            ConfigRange {
+                channels: 2,
+                sample_format: SampleFormat::F32,
+                min_sample_rate: 44100,
+                max_sample_rate: 96000,
+                buffer_size: SupportedBufferSize::Unknown,
+            }
+        );
+    }
+
+    #[test]
+    fn test_device_info_directions() {
+        let info = DeviceInfo {
+            name: "Interface".into(),
+            is_default_input: false,
+            is_default_output: true,
+            input_configs: Vec::new(),
+            output_configs: mock_device(),
+        };
+
+        assert!(info.is_output());
+        assert!(!info.is_input());
+    }
+}
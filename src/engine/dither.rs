@@ -0,0 +1,154 @@
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DitherMode {
+    #[default]
+    Off,
+    // Triangular noise of one step peak, leaves the quantization error uncorrelated
+    Tpdf,
+    // TPDF with first-order error feedback, pushing the noise up towards the top of the band
+    NoiseShaped,
+}
+
+// Rounds samples onto the grid of an integer format before the final conversion, so the
+// quantization error turns into steady noise instead of distortion that follows the signal
+#[derive(Debug, Clone)]
+pub struct Dither {
+    mode: DitherMode,
+    seed: u64,
+    errors: Vec<f64>,
+}
+
+impl Dither {
+    const SEED: u64 = 0x9e37_79b9_7f4a_7c15;
+
+    pub fn new(mode: DitherMode) -> Self {
+        Dither {
+            mode,
+            seed: Self::SEED,
+            errors: Vec::new(),
+        }
+    }
+
+    pub fn mode(&self) -> DitherMode {
+        self.mode
+    }
+
+    pub fn set_mode(&mut self, mode: DitherMode) {
+        self.mode = mode;
+        self.errors.clear();
+    }
+
+    // `step` is the smallest step of the target format, zero for float formats
+    pub fn process(&mut self, buffer: &mut [f64], channels: usize, step: f64) {
+        if self.mode == DitherMode::Off || step <= 0.0 || channels == 0 {
+            return;
+        }
+
+        let shaped = self.mode == DitherMode::NoiseShaped;
+        self.errors.resize(channels, 0.0);
+
+        for frame in buffer.chunks_exact_mut(channels) {
+            for (sample, error) in frame.iter_mut().zip(self.errors.iter_mut()) {
+                let wanted = if shaped { *sample - *error } else { *sample };
+                let noise = (Self::uniform(&mut self.seed) + Self::uniform(&mut self.seed)) * step;
+                let quantized = ((wanted + noise) / step).round() * step;
+
+                if shaped {
+                    *error = quantized - wanted;
+                }
+                *sample = quantized;
+            }
+        }
+    }
+
+    // xorshift64*, uniform in -0.5..0.5
+    fn uniform(seed: &mut u64) -> f64 {
+        *seed ^= *seed >> 12;
+        *seed ^= *seed << 25;
+        *seed ^= *seed >> 27;
+        let value = seed.wrapping_mul(0x2545_f491_4f6c_dd1d);
+        (value >> 11) as f64 / (1u64 << 53) as f64 - 0.5
+    }
+}
+
+impl Default for Dither {
+    fn default() -> Self {
+        Dither::new(DitherMode::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STEP: f64 = 1.0 / i16::MAX as f64;
+
+    // A sine well below one step, as at the end of a quiet fade
+    fn quiet_sine(frames: usize) -> Vec<f64> {
+        (0..frames)
+            .map(|frame| 0.4 * STEP * (frame as f64 * 0.05).sin())
+            .collect()
+    }
+
+    fn correlation(a: &[f64], b: &[f64]) -> f64 {
+        a.iter().zip(b).map(|(a, b)| a * b).sum::<f64>() / a.len() as f64
+    }
+
+    #[test]
+    fn test_off_leaves_samples() {
+        let mut dither = Dither::default();
+        let mut buffer = quiet_sine(100);
+        let original = buffer.clone();
+
+        dither.process(&mut buffer, 1, STEP);
+        assert_eq!(buffer, original);
+
+        // Float formats have no grid to dither onto
+        dither.set_mode(DitherMode::Tpdf);
+        dither.process(&mut buffer, 1, 0.0);
+        assert_eq!(buffer, original);
+    }
+
+    #[test]
+    fn test_tpdf_keeps_signal_below_one_step() {
+        let sine = quiet_sine(100_000);
+
+        // Plain rounding loses everything below half a step
+        let rounded: Vec<f64> = sine.iter().map(|x| (x / STEP).round() * STEP).collect();
+        assert!(rounded.iter().all(|sample| *sample == 0.0));
+
+        let mut dithered = sine.clone();
+        Dither::new(DitherMode::Tpdf).process(&mut dithered, 1, STEP);
+
+        assert!(
+            dithered
+                .iter()
+                .all(|sample| (sample / STEP - (sample / STEP).round()).abs() < 1e-9)
+        );
+        // On average the signal survives, at the level it went in
+        let gain = correlation(&dithered, &sine) / correlation(&sine, &sine);
+        assert!((gain - 1.0).abs() < 0.1, "gain {gain}");
+    }
+
+    #[test]
+    fn test_noise_shaping_moves_error_up() {
+        let sine = quiet_sine(100_000);
+        let error = |mode| {
+            let mut buffer = sine.clone();
+            Dither::new(mode).process(&mut buffer, 1, STEP);
+            buffer
+                .iter()
+                .zip(&sine)
+                .map(|(out, sample)| out - sample)
+                .collect::<Vec<f64>>()
+        };
+        // Correlation between neighbouring errors: zero for white noise, negative once the
+        // noise sits mostly in the high frequencies
+        let neighbours = |error: &[f64]| correlation(&error[1..], &error[..error.len() - 1]);
+
+        let flat = error(DitherMode::Tpdf);
+        let shaped = error(DitherMode::NoiseShaped);
+
+        assert!(neighbours(&flat).abs() < 0.05 * correlation(&flat, &flat));
+        assert!(neighbours(&shaped) < -0.3 * correlation(&shaped, &shaped));
+    }
+}
@@ -1,10 +1,16 @@
-use crate::engine::TrackId;
+use crate::engine::{MusicalPosition, TrackId};
 
 #[derive(Debug, thiserror::Error)]
 pub enum AudioError {
     #[error("Audio file error: {0}")]
     FileError(#[from] hound::Error),
 
+    #[error("Peak file error: {0}")]
+    PeakFileError(#[from] std::io::Error),
+
+    #[error("Invalid peak file")]
+    InvalidPeakFile,
+
     #[error("Resampling failed: {0}")]
     ResampleError(#[from] rubato::ResampleError),
 
@@ -14,18 +20,103 @@ pub enum AudioError {
     #[error("Track not found: {0}")]
     TrackNotFound(TrackId),
 
+    #[error("Clip not found: {0}")]
+    ClipNotFound(usize),
+
+    #[error("Invalid stretch factor: {0}")]
+    InvalidStretch(f64),
+
+    #[error("Warp markers must increase in both source and timeline position")]
+    InvalidWarpMarkers,
+
+    #[error("Invalid playback rate: {0}")]
+    InvalidPlaybackRate(f64),
+
+    #[error("Invalid tempo: {0} bpm")]
+    InvalidTempo(f64),
+
+    #[error("Invalid time signature: {numerator}/{denominator}")]
+    InvalidTimeSignature { numerator: u32, denominator: u32 },
+
+    #[error("Invalid musical position: {0}")]
+    InvalidMusicalPosition(MusicalPosition),
+
+    #[error("Invalid split position: {0}")]
+    InvalidSplitPosition(u64),
+
+    #[error("Invalid loop range: {start}..{end}")]
+    InvalidLoopRange { start: u64, end: u64 },
+
+    #[error("Invalid punch range: {start}..{end}")]
+    InvalidPunchRange { start: u64, end: u64 },
+
+    #[error("Invalid pitch range: {min} Hz to {max} Hz")]
+    InvalidPitchRange { min: f64, max: f64 },
+
+    #[error("Invalid note curve: points must be finite and in time order")]
+    InvalidNoteCurve,
+
+    #[error("Take not found: {0}")]
+    TakeNotFound(usize),
+
+    #[error("Invalid comp range: {start}..{end}")]
+    InvalidCompRange { start: u64, end: u64 },
+
     #[error("Invalid volume: {0} (must be between 0.0 and 1.0)")]
     InvalidVolume(f32),
 
     #[error("Invalid sample rate: {0}")]
     InvalidSampleRate(u32),
 
+    #[error("Invalid channel count: {0}")]
+    InvalidChannelCount(u16),
+
+    #[error("Invalid buffer size: {0}")]
+    InvalidBufferSize(u32),
+
+    #[error("Channel out of range: {0}")]
+    ChannelOutOfRange(u16),
+
+    #[error("Channel map expects {actual} source channels, clip has {expected}")]
+    ChannelMapMismatch { expected: u16, actual: u16 },
+
+    #[error("No track is armed for recording")]
+    NoArmedTrack,
+
+    #[error("Recording is in progress")]
+    RecordingInProgress,
+
+    #[error("Timeline is unavailable, a thread panicked while holding it")]
+    TimelineUnavailable,
+
     #[error("Unsupported bits per sample: {0}")]
     UnsupportedBitsPerSample(u16),
 
     #[error("Output device not found")]
     OutputDeviceNotFound,
 
+    #[error("Input device not found")]
+    InputDeviceNotFound,
+
+    #[error("Device not found: {0}")]
+    DeviceNotFound(String),
+
+    #[error("Device list unavailable: {0}")]
+    DevicesError(#[from] cpal::DevicesError),
+
+    #[error("Device name unavailable: {0}")]
+    DeviceNameError(#[from] cpal::DeviceNameError),
+
+    #[error("Supported stream configs unavailable: {0}")]
+    SupportedStreamConfigsError(#[from] cpal::SupportedStreamConfigsError),
+
+    #[error("No supported config for {channels} channels of {sample_format} at {sample_rate} Hz")]
+    UnsupportedConfig {
+        channels: u16,
+        sample_format: cpal::SampleFormat,
+        sample_rate: u32,
+    },
+
     #[error("Stream config not supported: {0}")]
     StreamConfigNotSupported(#[from] cpal::BuildStreamError),
 
@@ -0,0 +1,67 @@
+use std::{fs::File, io::BufWriter};
+
+use cpal::I24;
+use hound::{WavSpec, WavWriter};
+
+use crate::engine::{AudioError, DitherMode, FromF64Sample};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ExportFormat {
+    #[default]
+    Float32,
+    Int16,
+    Int24,
+    Int32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ExportOptions {
+    pub format: ExportFormat,
+    // Only applies to the integer formats
+    pub dither: DitherMode,
+}
+
+impl ExportFormat {
+    pub fn spec(self, channels: u16, sample_rate: u32) -> WavSpec {
+        let (bits_per_sample, sample_format) = match self {
+            ExportFormat::Float32 => (32, hound::SampleFormat::Float),
+            ExportFormat::Int16 => (16, hound::SampleFormat::Int),
+            ExportFormat::Int24 => (24, hound::SampleFormat::Int),
+            ExportFormat::Int32 => (32, hound::SampleFormat::Int),
+        };
+        WavSpec {
+            channels,
+            sample_rate,
+            bits_per_sample,
+            sample_format,
+        }
+    }
+
+    // The smallest step of the format, zero when there is nothing to dither
+    pub fn step(self) -> f64 {
+        match self {
+            ExportFormat::Float32 => 0.0,
+            ExportFormat::Int16 => i16::STEP,
+            ExportFormat::Int24 => I24::STEP,
+            ExportFormat::Int32 => i32::STEP,
+        }
+    }
+
+    pub fn write(
+        self,
+        writer: &mut WavWriter<BufWriter<File>>,
+        samples: &[f64],
+    ) -> Result<(), AudioError> {
+        for sample in samples {
+            match self {
+                ExportFormat::Float32 => writer.write_sample(f32::from_f64_sample(*sample))?,
+                ExportFormat::Int16 => writer.write_sample(i16::from_f64_sample(*sample))?,
+                ExportFormat::Int24 => {
+                    writer.write_sample(I24::from_f64_sample(*sample).inner())?
+                }
+                ExportFormat::Int32 => writer.write_sample(i32::from_f64_sample(*sample))?,
+            }
+        }
+        Ok(())
+    }
+}
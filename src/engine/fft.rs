@@ -0,0 +1,111 @@
+use std::f64::consts::PI;
+
+// Iterative radix-2 FFT, enough for the short analysis frames used across the engine
+pub struct Fft {
+    size: usize,
+    cos: Vec<f64>,
+    sin: Vec<f64>,
+}
+
+impl Fft {
+    pub fn new(size: usize) -> Self {
+        assert!(size.is_power_of_two(), "FFT size must be a power of two");
+
+        let (cos, sin) = (0..size / 2)
+            .map(|k| {
+                let angle = -2.0 * PI * k as f64 / size as f64;
+                (angle.cos(), angle.sin())
+            })
+            .unzip();
+
+        Fft { size, cos, sin }
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn hann_window(size: usize) -> Vec<f64> {
+        (0..size)
+            .map(|i| 0.5 - 0.5 * (2.0 * PI * i as f64 / size as f64).cos())
+            .collect()
+    }
+
+    pub fn forward(&self, real: &mut [f64], imaginary: &mut [f64]) {
+        self.transform(real, imaginary);
+    }
+
+    // Magnitudes of the first half of the spectrum, DC up to Nyquist
+    pub fn magnitudes(&self, frame: &[f64], window: &[f64]) -> Vec<f64> {
+        let mut real: Vec<f64> = (0..self.size)
+            .map(|i| frame.get(i).copied().unwrap_or_default() * window[i])
+            .collect();
+        let mut imaginary = vec![0.0; self.size];
+
+        self.forward(&mut real, &mut imaginary);
+
+        real[..=self.size / 2]
+            .iter()
+            .zip(&imaginary)
+            .map(|(re, im)| re.hypot(*im))
+            .collect()
+    }
+
+    fn transform(&self, real: &mut [f64], imaginary: &mut [f64]) {
+        let size = self.size;
+        let bits = size.trailing_zeros();
+
+        for i in 0..size {
+            let j = i.reverse_bits() >> (usize::BITS - bits);
+            if size > 1 && j > i {
+                real.swap(i, j);
+                imaginary.swap(i, j);
+            }
+        }
+
+        let mut length = 2;
+        while length <= size {
+            let stride = size / length;
+            for start in (0..size).step_by(length) {
+                for k in 0..length / 2 {
+                    let (cos, sin) = (self.cos[k * stride], self.sin[k * stride]);
+
+                    let (a, b) = (start + k, start + k + length / 2);
+                    let re = real[b] * cos - imaginary[b] * sin;
+                    let im = real[b] * sin + imaginary[b] * cos;
+
+                    real[b] = real[a] - re;
+                    imaginary[b] = imaginary[a] - im;
+                    real[a] += re;
+                    imaginary[a] += im;
+                }
+            }
+            length *= 2;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sine_lands_in_its_bin() {
+        let fft = Fft::new(256);
+        let frame: Vec<f64> = (0..256)
+            .map(|i| (2.0 * PI * 16.0 * i as f64 / 256.0).sin())
+            .collect();
+
+        let magnitudes = fft.magnitudes(&frame, &vec![1.0; 256]);
+
+        assert_eq!(magnitudes.len(), 129);
+        assert!((magnitudes[16] - 128.0).abs() < 1e-9);
+        assert!(
+            magnitudes
+                .iter()
+                .enumerate()
+                .filter(|(bin, _)| *bin != 16)
+                .all(|(_, magnitude)| *magnitude < 1e-9)
+        );
+    }
+}
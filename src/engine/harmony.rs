@@ -0,0 +1,354 @@
+use crate::engine::{PitchPoint, PitchTracker, TrackId};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Tuning {
+    #[default]
+    EqualTemperament,
+    JustIntonation,
+}
+
+impl Tuning {
+    const JUST_RATIOS: [f64; 12] = [
+        1.0,
+        16.0 / 15.0,
+        9.0 / 8.0,
+        6.0 / 5.0,
+        5.0 / 4.0,
+        4.0 / 3.0,
+        45.0 / 32.0,
+        3.0 / 2.0,
+        8.0 / 5.0,
+        5.0 / 3.0,
+        9.0 / 5.0,
+        15.0 / 8.0,
+    ];
+
+    pub fn interval_cents(&self, semitones: i32) -> f64 {
+        match self {
+            Tuning::EqualTemperament => semitones as f64 * 100.0,
+            Tuning::JustIntonation => {
+                let class = semitones.rem_euclid(12) as usize;
+                let octave = semitones.div_euclid(12) as f64;
+                1200.0 * Self::JUST_RATIOS[class].log2() + 1200.0 * octave
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IntervalDeviation {
+    pub lower: TrackId,
+    pub upper: TrackId,
+    pub semitones: i32,
+    pub cents_offset: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VoiceDeviation {
+    pub track_id: TrackId,
+    pub cents_offset: f64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HarmonySegment {
+    pub start_seconds: f64,
+    pub end_seconds: f64,
+    pub intervals: Vec<IntervalDeviation>,
+    pub voices: Vec<VoiceDeviation>,
+    pub is_drifting: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HarmonyReport {
+    pub tuning: Tuning,
+    pub segments: Vec<HarmonySegment>,
+}
+
+impl HarmonyReport {
+    pub fn drifting_segments(&self) -> impl Iterator<Item = &HarmonySegment> {
+        self.segments.iter().filter(|segment| segment.is_drifting)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HarmonyAnalyzer {
+    pub tuning: Tuning,
+    pub tracker: PitchTracker,
+    pub tolerance_cents: f64,
+    pub min_segment_seconds: f64,
+}
+
+impl Default for HarmonyAnalyzer {
+    fn default() -> Self {
+        HarmonyAnalyzer {
+            tuning: Tuning::default(),
+            tracker: PitchTracker::default(),
+            tolerance_cents: 15.0,
+            min_segment_seconds: 0.05,
+        }
+    }
+}
+
+struct SegmentBuilder {
+    start_seconds: f64,
+    end_seconds: f64,
+    key: Vec<(TrackId, TrackId, i32)>,
+    frames: usize,
+    interval_sums: Vec<f64>,
+    voice_sums: Vec<(TrackId, f64)>,
+}
+
+impl SegmentBuilder {
+    fn finish(self, tolerance_cents: f64) -> HarmonySegment {
+        let frames = self.frames as f64;
+
+        let intervals: Vec<IntervalDeviation> = self
+            .key
+            .iter()
+            .zip(&self.interval_sums)
+            .map(|(&(lower, upper, semitones), sum)| IntervalDeviation {
+                lower,
+                upper,
+                semitones,
+                cents_offset: sum / frames,
+            })
+            .collect();
+
+        let is_drifting = intervals
+            .iter()
+            .any(|interval| interval.cents_offset.abs() > tolerance_cents);
+
+        HarmonySegment {
+            start_seconds: self.start_seconds,
+            end_seconds: self.end_seconds,
+            voices: self
+                .voice_sums
+                .iter()
+                .map(|&(track_id, sum)| VoiceDeviation {
+                    track_id,
+                    cents_offset: sum / frames,
+                })
+                .collect(),
+            intervals,
+            is_drifting,
+        }
+    }
+}
+
+impl HarmonyAnalyzer {
+    // Takes one pitch curve per voice, with times on the timeline, and compares every
+    // pair of voices sounding at the same time. A segment runs for as long as the same
+    // voices hold the same intervals.
+    pub fn analyze(&self, voices: &[(TrackId, Vec<PitchPoint>)]) -> HarmonyReport {
+        let hop = self.tracker.hop_seconds;
+        let end_seconds = voices
+            .iter()
+            .filter_map(|(_, curve)| curve.last())
+            .map(|point| point.time_seconds)
+            .fold(0.0, f64::max);
+
+        let mut segments = Vec::new();
+        let mut current: Option<SegmentBuilder> = None;
+
+        let frame_count = if hop > 0.0 {
+            (end_seconds / hop).floor() as usize + 1
+        } else {
+            0
+        };
+
+        for frame in 0..frame_count {
+            let time_seconds = frame as f64 * hop;
+
+            let mut sounding: Vec<(TrackId, f64)> = voices
+                .iter()
+                .filter_map(|(track_id, curve)| {
+                    Self::frequency_at(curve, time_seconds, hop).map(|f| (*track_id, f))
+                })
+                .collect();
+            sounding.sort_by(|a, b| a.1.total_cmp(&b.1));
+
+            let mut key = Vec::new();
+            let mut offsets = Vec::new();
+            for (i, &(lower, lower_frequency)) in sounding.iter().enumerate() {
+                for &(upper, upper_frequency) in &sounding[i + 1..] {
+                    let cents = 1200.0 * (upper_frequency / lower_frequency).log2();
+                    let semitones = (cents / 100.0).round() as i32;
+                    key.push((lower, upper, semitones));
+                    offsets.push(cents - self.tuning.interval_cents(semitones));
+                }
+            }
+
+            // Each voice is measured against the lowest sounding voice
+            let voice_offsets: Vec<(TrackId, f64)> = sounding
+                .iter()
+                .enumerate()
+                .map(|(i, &(track_id, _))| (track_id, if i == 0 { 0.0 } else { offsets[i - 1] }))
+                .collect();
+
+            match current.as_mut() {
+                Some(segment) if segment.key == key && !key.is_empty() => {
+                    segment.end_seconds = time_seconds + hop;
+                    segment.frames += 1;
+                    segment
+                        .interval_sums
+                        .iter_mut()
+                        .zip(&offsets)
+                        .for_each(|(sum, offset)| *sum += offset);
+                    segment
+                        .voice_sums
+                        .iter_mut()
+                        .zip(&voice_offsets)
+                        .for_each(|(sum, (_, offset))| sum.1 += offset);
+                }
+                _ => {
+                    if let Some(segment) = current.take()
+                        && segment.end_seconds - segment.start_seconds >= self.min_segment_seconds
+                    {
+                        segments.push(segment.finish(self.tolerance_cents));
+                    }
+
+                    if !key.is_empty() {
+                        current = Some(SegmentBuilder {
+                            start_seconds: time_seconds,
+                            end_seconds: time_seconds + hop,
+                            key,
+                            frames: 1,
+                            interval_sums: offsets,
+                            voice_sums: voice_offsets,
+                        });
+                    }
+                }
+            }
+        }
+
+        if let Some(segment) = current
+            && segment.end_seconds - segment.start_seconds >= self.min_segment_seconds
+        {
+            segments.push(segment.finish(self.tolerance_cents));
+        }
+
+        HarmonyReport {
+            tuning: self.tuning,
+            segments,
+        }
+    }
+
+    fn frequency_at(curve: &[PitchPoint], time_seconds: f64, hop: f64) -> Option<f64> {
+        let index = curve.partition_point(|point| point.time_seconds < time_seconds);
+
+        [index.checked_sub(1), Some(index)]
+            .into_iter()
+            .flatten()
+            .filter_map(|index| curve.get(index))
+            .filter(|point| (point.time_seconds - time_seconds).abs() <= hop / 2.0)
+            .min_by(|a, b| {
+                (a.time_seconds - time_seconds)
+                    .abs()
+                    .total_cmp(&(b.time_seconds - time_seconds).abs())
+            })
+            .and_then(|point| point.frequency)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn steady(frequency: f64, from: f64, to: f64) -> Vec<PitchPoint> {
+        let hop = PitchTracker::default().hop_seconds;
+        let frames = ((to - from) / hop).round() as usize;
+        (0..frames)
+            .map(|i| PitchPoint {
+                time_seconds: from + i as f64 * hop,
+                frequency: Some(frequency),
+                confidence: 1.0,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_interval_cents() {
+        assert_eq!(Tuning::EqualTemperament.interval_cents(7), 700.0);
+        assert!((Tuning::JustIntonation.interval_cents(7) - 701.955).abs() < 1e-3);
+        assert!((Tuning::JustIntonation.interval_cents(16) - 1586.314).abs() < 1e-3);
+        assert!((Tuning::JustIntonation.interval_cents(-7) + 701.955).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_just_fifth() {
+        let voices = vec![
+            (TrackId(1), steady(220.0, 0.0, 1.0)),
+            (TrackId(2), steady(330.0, 0.0, 1.0)),
+        ];
+
+        let equal = HarmonyAnalyzer::default().analyze(&voices);
+        let just = HarmonyAnalyzer {
+            tuning: Tuning::JustIntonation,
+            ..HarmonyAnalyzer::default()
+        }
+        .analyze(&voices);
+
+        assert_eq!(equal.segments.len(), 1);
+        let interval = equal.segments[0].intervals[0];
+        assert_eq!(interval.lower, TrackId(1));
+        assert_eq!(interval.upper, TrackId(2));
+        assert_eq!(interval.semitones, 7);
+        assert!((interval.cents_offset - 1.955).abs() < 1e-2);
+        assert!(!equal.segments[0].is_drifting);
+
+        assert!(just.segments[0].intervals[0].cents_offset.abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_drift_is_flagged() {
+        let sharp_third = 220.0 * 2f64.powf(420.0 / 1200.0);
+        let voices = vec![
+            (TrackId(1), steady(220.0, 0.0, 0.5)),
+            (TrackId(2), steady(sharp_third, 0.0, 0.5)),
+            (TrackId(3), steady(330.0, 0.0, 0.5)),
+        ];
+
+        let report = HarmonyAnalyzer::default().analyze(&voices);
+
+        assert_eq!(report.segments.len(), 1);
+        let segment = &report.segments[0];
+        assert!(segment.is_drifting);
+        assert_eq!(report.drifting_segments().count(), 1);
+
+        let third = segment
+            .voices
+            .iter()
+            .find(|voice| voice.track_id == TrackId(2))
+            .unwrap();
+        assert!((third.cents_offset - 20.0).abs() < 1e-6);
+        assert_eq!(segment.voices[0].track_id, TrackId(1));
+        assert_eq!(segment.voices[0].cents_offset, 0.0);
+    }
+
+    #[test]
+    fn test_segments_follow_interval_changes() {
+        let mut upper = steady(330.0, 0.0, 0.5);
+        upper.extend(steady(275.0, 0.5, 1.0));
+        let voices = vec![(TrackId(1), steady(220.0, 0.0, 1.0)), (TrackId(2), upper)];
+
+        let report = HarmonyAnalyzer::default().analyze(&voices);
+
+        assert_eq!(report.segments.len(), 2);
+        assert_eq!(report.segments[0].intervals[0].semitones, 7);
+        assert_eq!(report.segments[1].intervals[0].semitones, 4);
+        assert!((report.segments[0].end_seconds - 0.5).abs() < 1e-9);
+        assert!((report.segments[1].start_seconds - 0.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_single_voice_has_no_segments() {
+        let voices = vec![(TrackId(1), steady(220.0, 0.0, 1.0))];
+
+        assert!(
+            HarmonyAnalyzer::default()
+                .analyze(&voices)
+                .segments
+                .is_empty()
+        );
+    }
+}
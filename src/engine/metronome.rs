@@ -0,0 +1,315 @@
+use std::{ops::AddAssign, path::Path, sync::Arc};
+
+use crate::engine::{AudioError, Clip, FromF64Sample, Grid, ResamplerQuality, TempoMap};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ClickSound {
+    Sine {
+        frequency: f64,
+        accent_frequency: f64,
+    },
+    // Mono, at the timeline sample rate
+    Sample(Arc<[f64]>),
+}
+
+impl ClickSound {
+    pub fn from_path<P: AsRef<Path>>(path: P, sample_rate: u32) -> Result<Self, AudioError> {
+        let clip = Clip::from_path(path, 0, sample_rate, ResamplerQuality::default())?;
+        Ok(ClickSound::Sample(clip.mixdown().into()))
+    }
+}
+
+impl Default for ClickSound {
+    fn default() -> Self {
+        ClickSound::Sine {
+            frequency: 1000.0,
+            accent_frequency: 1500.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Metronome {
+    pub enabled: bool,
+    pub level: f64,
+    pub accent: bool,
+    pub sound: ClickSound,
+    pub count_in_bars: u32,
+}
+
+impl Default for Metronome {
+    fn default() -> Self {
+        Metronome {
+            enabled: false,
+            level: 0.5,
+            accent: true,
+            sound: ClickSound::default(),
+            count_in_bars: 0,
+        }
+    }
+}
+
+// Beats played before recording starts, on a clock of their own
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CountIn {
+    elapsed: u64,
+    length: u64,
+    beat_samples: f64,
+    beats_per_bar: u32,
+}
+
+impl CountIn {
+    pub fn new(bars: u32, tempo_map: &TempoMap, start: u64) -> Option<Self> {
+        if bars == 0 {
+            return None;
+        }
+
+        let signature = tempo_map.time_signature_at(start);
+        let quarter_seconds = 60.0 / tempo_map.tempo_at(start);
+        let beat_samples =
+            quarter_seconds * tempo_map.sample_rate() as f64 * 4.0 / signature.denominator() as f64;
+        let beats = bars * signature.numerator();
+
+        Some(CountIn {
+            elapsed: 0,
+            length: (beats as f64 * beat_samples).round() as u64,
+            beat_samples,
+            beats_per_bar: signature.numerator(),
+        })
+    }
+
+    pub fn remaining(&self) -> u64 {
+        self.length - self.elapsed
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.elapsed >= self.length
+    }
+}
+
+impl Metronome {
+    const CLICK_SECONDS: f64 = 0.03;
+    const ACCENT_GAIN: f64 = 1.5;
+    const BEAT_GAIN: f64 = 0.7;
+
+    pub fn set_level(&mut self, level: f64) {
+        self.level = level.clamp(0.0, 1.0);
+    }
+
+    // Mixes the clicks for the beats that sound in `start..start + frames`
+    pub fn process<T>(
+        &self,
+        buffer: &mut [T],
+        output_channels: u16,
+        start: u64,
+        tempo_map: &TempoMap,
+    ) where
+        T: FromF64Sample + AddAssign,
+    {
+        if !self.enabled {
+            return;
+        }
+
+        let frames = (buffer.len() / output_channels.max(1) as usize) as u64;
+        let click_frames = self.click_frames(tempo_map.sample_rate());
+        let clicks = tempo_map
+            .grid_lines(
+                start.saturating_sub(click_frames),
+                start + frames,
+                Grid::Beat,
+            )
+            .map(|(samples, position)| (samples, position.beat == 1));
+
+        self.mix_clicks(
+            buffer,
+            output_channels,
+            start,
+            clicks,
+            tempo_map.sample_rate(),
+        );
+    }
+
+    // Plays the count-in and returns how many frames of the buffer it used up
+    pub fn process_count_in<T>(
+        &self,
+        buffer: &mut [T],
+        output_channels: u16,
+        count_in: &mut CountIn,
+        sample_rate: u32,
+    ) -> usize
+    where
+        T: FromF64Sample + AddAssign,
+    {
+        let frames = ((buffer.len() / output_channels.max(1) as usize) as u64)
+            .min(count_in.remaining()) as usize;
+        let start = count_in.elapsed;
+
+        let (beat_samples, beats_per_bar, length) = (
+            count_in.beat_samples,
+            count_in.beats_per_bar,
+            count_in.length,
+        );
+        let clicks = (0..)
+            .map(move |beat: u32| {
+                let samples = (beat as f64 * beat_samples).round() as u64;
+                (samples, beat.is_multiple_of(beats_per_bar))
+            })
+            .take_while(move |(samples, _)| *samples < length);
+
+        self.mix_clicks(
+            &mut buffer[..frames * output_channels as usize],
+            output_channels,
+            start,
+            clicks,
+            sample_rate,
+        );
+        count_in.elapsed += frames as u64;
+
+        frames
+    }
+
+    fn mix_clicks<T>(
+        &self,
+        buffer: &mut [T],
+        output_channels: u16,
+        start: u64,
+        clicks: impl Iterator<Item = (u64, bool)>,
+        sample_rate: u32,
+    ) where
+        T: FromF64Sample + AddAssign,
+    {
+        let channels = output_channels.max(1) as usize;
+        let frames = (buffer.len() / channels) as u64;
+        let click_frames = self.click_frames(sample_rate);
+
+        for (click, is_downbeat) in clicks {
+            let accented = self.accent && is_downbeat;
+            let from = click.max(start);
+            let to = (click + click_frames).min(start + frames);
+
+            for position in from..to {
+                let sample =
+                    self.click_sample(position - click, accented, sample_rate) * self.level;
+                let frame = (position - start) as usize;
+                for channel in 0..channels {
+                    buffer[frame * channels + channel] += T::from_f64_sample(sample);
+                }
+            }
+        }
+    }
+
+    fn click_frames(&self, sample_rate: u32) -> u64 {
+        match &self.sound {
+            ClickSound::Sine { .. } => (Self::CLICK_SECONDS * sample_rate as f64) as u64,
+            ClickSound::Sample(samples) => samples.len() as u64,
+        }
+    }
+
+    fn click_sample(&self, offset: u64, accented: bool, sample_rate: u32) -> f64 {
+        match &self.sound {
+            ClickSound::Sine {
+                frequency,
+                accent_frequency,
+            } => {
+                let frequency = if accented {
+                    *accent_frequency
+                } else {
+                    *frequency
+                };
+                let t = offset as f64 / sample_rate as f64;
+                let envelope = (-t / (Self::CLICK_SECONDS / 5.0)).exp();
+                (2.0 * std::f64::consts::PI * frequency * t).sin() * envelope
+            }
+            ClickSound::Sample(samples) => {
+                let gain = if accented {
+                    Self::ACCENT_GAIN
+                } else {
+                    Self::BEAT_GAIN
+                };
+                samples.get(offset as usize).copied().unwrap_or_default() * gain
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 48000;
+
+    fn enabled(sound: ClickSound) -> Metronome {
+        Metronome {
+            enabled: true,
+            level: 1.0,
+            sound,
+            ..Metronome::default()
+        }
+    }
+
+    fn onsets(buffer: &[f64]) -> Vec<usize> {
+        buffer
+            .iter()
+            .enumerate()
+            .filter(|(i, sample)| **sample != 0.0 && (*i == 0 || buffer[i - 1] == 0.0))
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    #[test]
+    fn test_clicks_on_beats() {
+        let tempo_map = TempoMap::new(SAMPLE_RATE);
+        let metronome = enabled(ClickSound::Sample(vec![1.0; 10].into()));
+        let mut buffer = vec![0.0f64; 96000];
+
+        metronome.process(&mut buffer, 1, 0, &tempo_map);
+
+        assert_eq!(onsets(&buffer), [0, 24000, 48000, 72000]);
+        assert_eq!(buffer[0], Metronome::ACCENT_GAIN);
+        assert_eq!(buffer[24000], Metronome::BEAT_GAIN);
+    }
+
+    #[test]
+    fn test_click_spans_buffers() {
+        let tempo_map = TempoMap::new(SAMPLE_RATE);
+        let metronome = enabled(ClickSound::default());
+        let mut whole = vec![0.0f64; 2048];
+        metronome.process(&mut whole, 2, 23500, &tempo_map);
+
+        let mut first = vec![0.0f64; 1024];
+        let mut second = vec![0.0f64; 1024];
+        metronome.process(&mut first, 2, 23500, &tempo_map);
+        metronome.process(&mut second, 2, 24012, &tempo_map);
+        first.extend(second);
+
+        assert_eq!(whole, first);
+        assert!(whole[..1000].iter().all(|sample| *sample == 0.0));
+        assert!(whole.iter().any(|sample| *sample != 0.0));
+    }
+
+    #[test]
+    fn test_disabled_is_silent() {
+        let tempo_map = TempoMap::new(SAMPLE_RATE);
+        let metronome = Metronome::default();
+        let mut buffer = vec![0.0f64; 4800];
+
+        metronome.process(&mut buffer, 1, 0, &tempo_map);
+
+        assert!(buffer.iter().all(|sample| *sample == 0.0));
+    }
+
+    #[test]
+    fn test_count_in() {
+        let tempo_map = TempoMap::new(SAMPLE_RATE);
+        let metronome = enabled(ClickSound::Sample(vec![1.0; 10].into()));
+        let mut count_in = CountIn::new(1, &tempo_map, 0).unwrap();
+        let mut buffer = vec![0.0f64; 100_000];
+
+        let used = metronome.process_count_in(&mut buffer, 1, &mut count_in, SAMPLE_RATE);
+
+        assert_eq!(used, 96000);
+        assert!(count_in.is_finished());
+        assert_eq!(onsets(&buffer), [0, 24000, 48000, 72000]);
+        assert!(CountIn::new(0, &tempo_map, 0).is_none());
+    }
+}
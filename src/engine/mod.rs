@@ -1,19 +1,50 @@
+mod alignment;
 mod audio_engine;
+mod backend;
+mod channel_map;
 mod clip;
+mod comp;
+mod cpal_backend;
+mod device;
+mod dither;
 mod error;
+mod export;
+mod fft;
+mod harmony;
+mod metronome;
+mod monitor;
+mod null_backend;
+mod peaks;
+mod pitch;
+mod pitch_correction;
+mod recording;
 mod resampler;
+mod sample;
+mod tempo;
 mod timeline;
 mod track;
+mod transport;
 mod utils;
+mod varispeed;
+mod warp;
 
+use backend::LatencyMeter;
 use clip::Clip;
+use comp::Comp;
 use error::AudioError;
+use metronome::CountIn;
+use monitor::InputMonitor;
+use peaks::{PeakPyramid, SourceStamp};
+use recording::Recording;
 use resampler::Resampler;
 use track::Track;
 use track::TrackId;
+use transport::Transport;
+use varispeed::Varispeed;
 
 #[derive(Clone, Copy)]
 enum Scale {
+    U8,
     I8,
     I16,
     I24,
@@ -22,6 +53,7 @@ enum Scale {
 }
 
 impl Scale {
+    const U8_SCALE: f64 = 1.0 / 128.0;
     const I8_SCALE: f64 = 1.0 / i8::MAX as f64;
     const I16_SCALE: f64 = 1.0 / i16::MAX as f64;
     const I24_SCALE: f64 = 1.0 / ((1 << 23) - 1) as f64;
@@ -29,6 +61,7 @@ impl Scale {
 
     fn get_f64_scale(&self) -> f64 {
         match self {
+            Scale::U8 => Self::U8_SCALE,
             Scale::I8 => Self::I8_SCALE,
             Scale::I16 => Self::I16_SCALE,
             Scale::I24 => Self::I24_SCALE,
@@ -38,39 +71,27 @@ impl Scale {
     }
 }
 
-pub trait FromF64Sample {
-    fn from_f64_sample(sample: f64) -> Self;
-}
-
-impl FromF64Sample for u8 {
-    fn from_f64_sample(sample: f64) -> Self {
-        ((sample + 1.0) * 127.5) as u8
-    }
-}
-
-impl FromF64Sample for i16 {
-    fn from_f64_sample(sample: f64) -> Self {
-        (sample * i16::MAX as f64) as i16
-    }
-}
-
-impl FromF64Sample for i32 {
-    fn from_f64_sample(sample: f64) -> Self {
-        (sample * i32::MAX as f64) as i32
-    }
-}
-
-impl FromF64Sample for f32 {
-    fn from_f64_sample(sample: f64) -> Self {
-        sample as f32
-    }
-}
-
-impl FromF64Sample for f64 {
-    fn from_f64_sample(sample: f64) -> Self {
-        sample
-    }
-}
-
+pub use alignment::{Alignment, TakeAligner};
 pub use audio_engine::AudioEngine;
+pub use backend::Backend;
+pub use channel_map::ChannelMap;
+pub use comp::CompSegment;
+pub use cpal_backend::CpalBackend;
+pub use device::{ConfigRange, ConfigRequest, DeviceInfo, LatencyProfile, StreamSettings};
+pub use dither::{Dither, DitherMode};
+pub use export::{ExportFormat, ExportOptions};
+pub use harmony::{
+    HarmonyAnalyzer, HarmonyReport, HarmonySegment, IntervalDeviation, Tuning, VoiceDeviation,
+};
+pub use metronome::{ClickSound, Metronome};
+pub use monitor::MonitorMode;
+pub use null_backend::NullBackend;
+pub use peaks::Peak;
+pub use pitch::{PitchPoint, PitchTracker};
+pub use pitch_correction::{CorrectionTarget, MusicalScale, NotePoint, PitchCorrection};
+pub use resampler::ResamplerQuality;
+pub use sample::{FromF64Sample, ToF64Sample};
+pub use tempo::{Grid, MusicalPosition, TempoMap, TimeSignature};
 pub use timeline::Timeline;
+pub use transport::{EndAction, TransportCommand, TransportEvent, TransportState};
+pub use warp::{TimeStretcher, WarpMap, WarpMarker};
@@ -0,0 +1,141 @@
+use std::collections::VecDeque;
+
+use crate::engine::{ChannelMap, ToF64Sample};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MonitorMode {
+    #[default]
+    Off,
+    // Heard while recording or with the transport stopped, the take plays back otherwise
+    Auto,
+    Always,
+}
+
+// Input handed from the input callback to the next output buffer. Only the newest
+// `max_frames` are kept, so a late output callback can't let the delay build up.
+#[derive(Debug, Clone)]
+pub struct InputMonitor {
+    channels: u16,
+    max_frames: usize,
+    pending: VecDeque<f64>,
+    // One input frame at a time while mixing, so the output callback doesn't allocate
+    frame: Vec<f64>,
+    // Resolved off the audio thread whenever the track's map or the output changes
+    channel_map: ChannelMap,
+}
+
+impl InputMonitor {
+    pub fn new(channels: u16, output_channels: u16, max_frames: usize) -> Self {
+        let channels = channels.max(1);
+        InputMonitor {
+            channels,
+            max_frames,
+            pending: VecDeque::with_capacity(max_frames * channels as usize),
+            frame: vec![0.0; channels as usize],
+            channel_map: ChannelMap::default_for(channels, output_channels),
+        }
+    }
+
+    pub fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    pub fn set_channel_map(&mut self, channel_map: ChannelMap) {
+        self.channel_map = channel_map;
+    }
+
+    pub fn pending_frames(&self) -> usize {
+        self.pending.len() / self.channels as usize
+    }
+
+    pub fn push<T>(&mut self, input: &[T])
+    where
+        T: ToF64Sample,
+    {
+        // Makes room before adding, so `pending` never grows past its capacity
+        let channels = self.channels as usize;
+        let incoming = (input.len() / channels).min(self.max_frames);
+        let excess = (self.pending_frames() + incoming).saturating_sub(self.max_frames);
+        self.pending.drain(..excess * channels);
+
+        let skipped = input.len() - incoming * channels;
+        self.pending
+            .extend(input[skipped..].iter().map(|sample| sample.to_f64_sample()));
+    }
+
+    pub fn clear(&mut self) {
+        self.pending.clear();
+    }
+
+    // Adds the pending input to the buffer frame by frame; once it runs dry the rest of the
+    // buffer gets nothing
+    pub fn mix_into(&mut self, buffer: &mut [f64], output_channels: u16, gain: f64) {
+        let channels = self.channels as usize;
+        let outputs = output_channels.max(1) as usize;
+        let frames = (buffer.len() / outputs).min(self.pending_frames());
+
+        for frame in buffer.chunks_exact_mut(outputs).take(frames) {
+            self.frame
+                .iter_mut()
+                .zip(self.pending.drain(..channels))
+                .for_each(|(input, sample)| *input = sample);
+
+            for (output, sample) in frame.iter_mut().enumerate() {
+                *sample += self
+                    .frame
+                    .iter()
+                    .enumerate()
+                    .map(|(source, input)| {
+                        input * self.channel_map.gain(source as u16, output as u16)
+                    })
+                    .sum::<f64>()
+                    * gain;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mixes_through_channel_map() {
+        let mut monitor = InputMonitor::new(1, 2, 64);
+        monitor.push(&[0.5f32, 0.25]);
+
+        let mut buffer = vec![0.1; 6];
+        monitor.mix_into(&mut buffer, 2, 0.5);
+
+        assert_eq!(buffer, [0.35, 0.35, 0.225, 0.225, 0.1, 0.1]);
+        assert_eq!(monitor.pending_frames(), 0);
+    }
+
+    #[test]
+    fn test_keeps_only_newest_frames() {
+        let mut monitor = InputMonitor::new(2, 2, 2);
+        monitor.push(&[1.0f32, 1.0, 2.0, 2.0, 3.0, 3.0]);
+
+        let mut buffer = vec![0.0; 4];
+        monitor.mix_into(&mut buffer, 2, 1.0);
+
+        assert_eq!(buffer, [2.0, 2.0, 3.0, 3.0]);
+    }
+
+    #[test]
+    fn test_pending_stays_within_capacity() {
+        let mut monitor = InputMonitor::new(2, 2, 4);
+        let capacity = monitor.pending.capacity();
+
+        for block in 0..8 {
+            monitor.push(&[block as f32; 2 * 3]);
+        }
+
+        assert_eq!(monitor.pending.capacity(), capacity);
+        assert_eq!(monitor.pending_frames(), 4);
+        assert_eq!(
+            monitor.pending.iter().copied().collect::<Vec<_>>(),
+            [6.0, 6.0, 7.0, 7.0, 7.0, 7.0, 7.0, 7.0]
+        );
+    }
+}
@@ -0,0 +1,805 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use cpal::{SampleFormat, SupportedBufferSize};
+
+use crate::engine::{
+    AudioError, Backend, ConfigRange, DeviceInfo, FromF64Sample, LatencyMeter, StreamSettings,
+    Timeline, ToF64Sample,
+};
+
+struct NullStream {
+    settings: StreamSettings,
+    timeline: Arc<Mutex<Timeline>>,
+    latency: Arc<LatencyMeter>,
+}
+
+impl NullStream {
+    // Renders in the stream's own format, as a device would ask for it, and keeps the result
+    // as f64 for inspection
+    fn render<T>(&self, samples: usize, rendered: &mut Vec<f64>) -> Result<(), AudioError>
+    where
+        T: FromF64Sample + ToF64Sample,
+    {
+        let mut data: Vec<T> = (0..samples).map(|_| T::from_f64_sample(0.0)).collect();
+        self.timeline
+            .lock()
+            .map_err(|_| AudioError::TimelineUnavailable)?
+            .process(&mut data, self.settings.channels);
+        rendered.extend(data.iter().map(|sample| sample.to_f64_sample()));
+        Ok(())
+    }
+
+    fn capture<T>(&self, input: &[f32]) -> Result<(), AudioError>
+    where
+        T: FromF64Sample + ToF64Sample,
+    {
+        let data: Vec<T> = input
+            .iter()
+            .map(|sample| T::from_f64_sample(*sample as f64))
+            .collect();
+        self.timeline
+            .lock()
+            .map_err(|_| AudioError::TimelineUnavailable)?
+            .record(&data);
+        Ok(())
+    }
+}
+
+// A single device with no hardware behind it. Nothing happens until `run` advances the clock,
+// which calls the stream callbacks as a device would and keeps the rendered output in memory.
+pub struct NullBackend {
+    configs: Vec<ConfigRange>,
+    output: Option<NullStream>,
+    input: Option<NullStream>,
+    clock: u64,
+    rendered: Vec<f64>,
+    pending_input: VecDeque<f32>,
+}
+
+impl NullBackend {
+    pub const DEVICE_NAME: &str = "Null";
+    // Used when the stream was opened without a fixed buffer size
+    const DEFAULT_BUFFER_FRAMES: u32 = 512;
+    const FORMATS: &[SampleFormat] = &[SampleFormat::F32, SampleFormat::I16];
+
+    pub fn new(channels: u16, sample_rate: u32) -> Self {
+        Self::with_configs(
+            Self::FORMATS
+                .iter()
+                .map(|sample_format| ConfigRange {
+                    channels,
+                    sample_format: *sample_format,
+                    min_sample_rate: sample_rate,
+                    max_sample_rate: sample_rate,
+                    buffer_size: SupportedBufferSize::Range { min: 1, max: 8192 },
+                })
+                .collect(),
+        )
+    }
+
+    // The device offers exactly these, for both directions
+    pub fn with_configs(configs: Vec<ConfigRange>) -> Self {
+        NullBackend {
+            configs,
+            output: None,
+            input: None,
+            clock: 0,
+            rendered: Vec::new(),
+            pending_input: VecDeque::new(),
+        }
+    }
+
+    // Interleaved frames for the input stream, delivered as the clock runs
+    pub fn feed_input(&mut self, samples: &[f32]) {
+        self.pending_input.extend(samples);
+    }
+
+    // Runs the callbacks for `frames` frames, one buffer at a time, input before output.
+    // Once the fed input runs out the input delivers silence.
+    pub fn run(&mut self, frames: u64) -> Result<(), AudioError> {
+        let Some(settings) = self.stream_settings() else {
+            self.clock += frames;
+            return Ok(());
+        };
+        let buffer_frames = settings.buffer_size.unwrap_or(Self::DEFAULT_BUFFER_FRAMES) as u64;
+        let sample_rate = settings.sample_rate;
+        let mut remaining = frames;
+
+        while remaining > 0 {
+            let block = buffer_frames.min(remaining);
+            let block_duration = Duration::from_secs_f64(block as f64 / sample_rate as f64);
+
+            if let Some(input) = &self.input {
+                let samples = block as usize * input.settings.channels as usize;
+                let mut data: Vec<f32> = self
+                    .pending_input
+                    .drain(..samples.min(self.pending_input.len()))
+                    .collect();
+                data.resize(samples, 0.0);
+
+                input.latency.set_input(block_duration);
+                match input.settings.sample_format {
+                    SampleFormat::I16 => input.capture::<i16>(&data)?,
+                    _ => input.capture::<f32>(&data)?,
+                }
+            }
+
+            if let Some(output) = &self.output {
+                let samples = block as usize * output.settings.channels as usize;
+
+                output.latency.set_output(block_duration);
+                match output.settings.sample_format {
+                    SampleFormat::I16 => output.render::<i16>(samples, &mut self.rendered)?,
+                    _ => output.render::<f32>(samples, &mut self.rendered)?,
+                }
+            }
+
+            self.clock += block;
+            remaining -= block;
+        }
+
+        Ok(())
+    }
+
+    // Frames the simulated clock has run for
+    pub fn clock(&self) -> u64 {
+        self.clock
+    }
+
+    // Everything the output stream rendered so far, interleaved
+    pub fn output(&self) -> &[f64] {
+        &self.rendered
+    }
+
+    pub fn take_output(&mut self) -> Vec<f64> {
+        std::mem::take(&mut self.rendered)
+    }
+
+    pub fn input_settings(&self) -> Option<StreamSettings> {
+        self.input.as_ref().map(|stream| stream.settings)
+    }
+
+    // The clock follows the output when both streams run, as they share a rate
+    fn stream_settings(&self) -> Option<StreamSettings> {
+        self.output
+            .as_ref()
+            .or(self.input.as_ref())
+            .map(|stream| stream.settings)
+    }
+
+    fn check_format(settings: &StreamSettings) -> Result<(), AudioError> {
+        if Self::FORMATS.contains(&settings.sample_format) {
+            Ok(())
+        } else {
+            Err(AudioError::UnsupportedConfig {
+                channels: settings.channels,
+                sample_format: settings.sample_format,
+                sample_rate: settings.sample_rate,
+            })
+        }
+    }
+
+    fn find_device(name: &str) -> Result<(), AudioError> {
+        if name == Self::DEVICE_NAME {
+            Ok(())
+        } else {
+            Err(AudioError::DeviceNotFound(name.to_string()))
+        }
+    }
+}
+
+impl Backend for NullBackend {
+    const OUTPUT_FORMATS: &'static [SampleFormat] = Self::FORMATS;
+    const INPUT_FORMATS: &'static [SampleFormat] = Self::FORMATS;
+
+    fn devices(&self) -> Result<Vec<DeviceInfo>, AudioError> {
+        Ok(vec![DeviceInfo {
+            name: Self::DEVICE_NAME.to_string(),
+            is_default_input: true,
+            is_default_output: true,
+            input_configs: self.configs.clone(),
+            output_configs: self.configs.clone(),
+        }])
+    }
+
+    fn output_device_name(&self) -> Option<String> {
+        Some(Self::DEVICE_NAME.to_string())
+    }
+
+    fn input_device_name(&self) -> Option<String> {
+        Some(Self::DEVICE_NAME.to_string())
+    }
+
+    fn set_output_device(&mut self, name: &str) -> Result<(), AudioError> {
+        Self::find_device(name)
+    }
+
+    fn set_input_device(&mut self, name: &str) -> Result<(), AudioError> {
+        Self::find_device(name)
+    }
+
+    fn output_configs(&self) -> Result<Vec<ConfigRange>, AudioError> {
+        Ok(self.configs.clone())
+    }
+
+    fn input_configs(&self) -> Result<Vec<ConfigRange>, AudioError> {
+        Ok(self.configs.clone())
+    }
+
+    fn start_output(
+        &mut self,
+        settings: StreamSettings,
+        timeline: Arc<Mutex<Timeline>>,
+        latency: Arc<LatencyMeter>,
+    ) -> Result<(), AudioError> {
+        Self::check_format(&settings)?;
+        self.output = Some(NullStream {
+            settings,
+            timeline,
+            latency,
+        });
+        Ok(())
+    }
+
+    fn start_input(
+        &mut self,
+        settings: StreamSettings,
+        timeline: Arc<Mutex<Timeline>>,
+        latency: Arc<LatencyMeter>,
+    ) -> Result<(), AudioError> {
+        Self::check_format(&settings)?;
+        self.input = Some(NullStream {
+            settings,
+            timeline,
+            latency,
+        });
+        Ok(())
+    }
+
+    fn stop_output(&mut self) {
+        self.output = None;
+    }
+
+    fn stop_input(&mut self) {
+        self.input = None;
+    }
+
+    fn is_output_running(&self) -> bool {
+        self.output.is_some()
+    }
+
+    fn is_input_running(&self) -> bool {
+        self.input.is_some()
+    }
+
+    // Time only passes on the simulated clock, which needs a stream to tick at its rate
+    fn wait(&mut self, duration: Duration) -> Result<(), AudioError> {
+        match self.stream_settings() {
+            Some(settings) => {
+                self.run((duration.as_secs_f64() * settings.sample_rate as f64).ceil() as u64)
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use cpal::SampleRate;
+
+    use super::*;
+    use crate::engine::{
+        AudioEngine, Clip, CorrectionTarget, DitherMode, LatencyProfile, MonitorMode, MusicalScale,
+        PitchCorrection, TransportEvent, TransportState,
+    };
+
+    const SAMPLE_RATE: u32 = 48000;
+
+    fn engine() -> Result<AudioEngine<NullBackend>, AudioError> {
+        AudioEngine::with_backend(
+            NullBackend::new(2, SAMPLE_RATE),
+            2,
+            SampleFormat::F32,
+            SampleRate(SAMPLE_RATE),
+        )
+    }
+
+    #[test]
+    fn test_record_and_play_back() -> Result<(), anyhow::Error> {
+        let mut engine = engine()?;
+        let timeline = engine.timeline();
+        let track_id = {
+            let mut timeline = timeline.lock().unwrap();
+            let track_id = timeline.new_track();
+            timeline.arm(track_id)?;
+            track_id
+        };
+
+        let input: Vec<f32> = (0..2000).map(|i| (i % 200) as f32 / 400.0).collect();
+        engine.start_recording()?;
+        assert!(engine.is_recording());
+        engine.backend_mut().feed_input(&input);
+        engine.backend_mut().run(1000)?;
+        engine.stop_recording()?;
+        assert_eq!(engine.transport_state(), TransportState::Stopped);
+        assert!(!engine.backend().is_output_running() && !engine.backend().is_input_running());
+
+        {
+            let timeline = timeline.lock().unwrap();
+            let track = timeline.get_track(track_id).unwrap();
+            assert_eq!(track.clip_count(), 1);
+            assert_eq!(track.clips()[0].duration_in_samples(), 1000);
+        }
+
+        // Lets the stop fade out before playback starts over
+        engine.backend_mut().run(512)?;
+        engine.backend_mut().take_output();
+        engine.start_playing()?;
+        engine.backend_mut().run(1000)?;
+
+        let output = engine.backend().output();
+        assert_eq!(output.len(), input.len());
+        assert!(
+            output
+                .iter()
+                .zip(&input)
+                .all(|(played, recorded)| (played - *recorded as f64).abs() < 1e-6)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_transport_runs_on_simulated_clock() -> Result<(), anyhow::Error> {
+        let mut engine = engine()?;
+        let events = engine.subscribe()?;
+        {
+            let timeline = engine.timeline();
+            let mut timeline = timeline.lock().unwrap();
+            let track_id = timeline.new_track();
+            timeline.add_clip(track_id, "sample-f32-stereo.wav")?;
+        }
+
+        engine.set_buffer_size(Some(256))?;
+        engine.start_playing()?;
+        engine.backend_mut().run(1024)?;
+        assert_eq!(engine.transport_state(), TransportState::Playing);
+        assert_eq!(engine.output_latency(), Some(Duration::from_micros(5333)));
+
+        // The output runs on until the stop has faded out, then the device is released
+        engine.stop_playing()?;
+        assert!(!engine.backend().is_output_running());
+        engine.backend_mut().run(256)?;
+
+        assert_eq!(engine.backend().clock(), 1536);
+        assert_eq!(engine.backend().output().len(), 1280 * 2);
+        assert!(
+            engine.backend().output()[1278 * 2..]
+                .iter()
+                .all(|s| s.abs() < 0.01)
+        );
+        assert!(!engine.is_playing());
+        assert!(
+            events
+                .try_iter()
+                .any(|event| event == TransportEvent::StateChanged(TransportState::Stopped))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_long_recording_gets_chunks_as_it_runs() -> Result<(), anyhow::Error> {
+        let mut engine = engine()?;
+        let timeline = engine.timeline();
+        let track_id = {
+            let mut timeline = timeline.lock().unwrap();
+            let track_id = timeline.new_track();
+            timeline.arm(track_id)?;
+            track_id
+        };
+        let events = engine.subscribe()?;
+
+        engine.start_recording()?;
+        for _ in 0..8 {
+            engine
+                .backend_mut()
+                .feed_input(&[0.25; 2 * SAMPLE_RATE as usize]);
+            engine.backend_mut().run(SAMPLE_RATE as u64)?;
+
+            let deadline = Instant::now() + Duration::from_secs(2);
+            while timeline.lock().unwrap().recording_chunks_needed().is_some() {
+                assert!(Instant::now() < deadline);
+                std::thread::sleep(Duration::from_millis(1));
+            }
+        }
+        engine.stop_recording()?;
+
+        let timeline = timeline.lock().unwrap();
+        let clip = &timeline.get_track(track_id).unwrap().clips()[0];
+        assert_eq!(clip.duration_in_samples(), 8 * SAMPLE_RATE as u64);
+        assert!(events.try_iter().all(|event| event != TransportEvent::Xrun));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_stop_playing_finishes_recording_on_the_caller() -> Result<(), anyhow::Error> {
+        let mut engine = engine()?;
+        let timeline = engine.timeline();
+        let track_id = {
+            let mut timeline = timeline.lock().unwrap();
+            let track_id = timeline.new_track();
+            timeline.arm(track_id)?;
+            track_id
+        };
+
+        engine.start_recording()?;
+        engine.backend_mut().feed_input(&[0.25; 1024]);
+        engine.backend_mut().run(512)?;
+        engine.stop_playing()?;
+
+        let timeline = timeline.lock().unwrap();
+        assert_eq!(timeline.get_track(track_id).unwrap().clip_count(), 1);
+        assert_eq!(timeline.transport_state(), TransportState::Stopped);
+        assert!(!engine.backend().is_output_running() && !engine.backend().is_input_running());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_monitor_input_through_output() -> Result<(), anyhow::Error> {
+        let mut engine = engine()?;
+        {
+            let timeline = engine.timeline();
+            let mut timeline = timeline.lock().unwrap();
+            let track_id = timeline.new_track();
+            timeline.arm(track_id)?;
+            timeline.set_monitor_mode(track_id, MonitorMode::Auto)?;
+        }
+
+        engine.set_buffer_size(Some(64))?;
+        engine.start_monitoring()?;
+        assert!(engine.is_monitoring() && !engine.is_recording());
+
+        let input: Vec<f32> = (0..256).map(|i| i as f32 / 512.0).collect();
+        engine.backend_mut().feed_input(&input);
+        engine.backend_mut().run(128)?;
+
+        // Each input buffer goes out with the output buffer of the same callback round
+        assert!(
+            engine
+                .backend()
+                .output()
+                .iter()
+                .zip(&input)
+                .all(|(output, input)| (output - *input as f64).abs() < 1e-6)
+        );
+
+        engine.stop_monitoring();
+        assert!(!engine.backend().is_input_running());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_pitch_correction_renders_outside_the_timeline() -> Result<(), anyhow::Error> {
+        let mut engine = engine()?;
+        let timeline = engine.timeline();
+        let track_id = {
+            let mut timeline = timeline.lock().unwrap();
+            let track_id = timeline.new_track();
+            timeline.add_clip(track_id, "sample-f32-stereo.wav")?;
+            track_id
+        };
+
+        let correction = PitchCorrection::new(CorrectionTarget::Scale {
+            root: 0,
+            scale: MusicalScale::Major,
+        });
+        engine.set_pitch_correction(track_id, 0, correction.clone())?;
+        assert_eq!(
+            timeline
+                .lock()
+                .unwrap()
+                .clip(track_id, 0)?
+                .pitch_correction(),
+            Some(&correction)
+        );
+
+        assert!(matches!(
+            engine.set_pitch_correction(
+                track_id,
+                0,
+                PitchCorrection::new(CorrectionTarget::NoteCurve(Vec::new()))
+            ),
+            Err(AudioError::InvalidNoteCurve)
+        ));
+        assert!(matches!(
+            engine.set_pitch_correction(track_id, 1, correction),
+            Err(AudioError::ClipNotFound(1))
+        ));
+        assert!(
+            timeline
+                .lock()
+                .unwrap()
+                .clip(track_id, 0)?
+                .pitch_correction()
+                .is_some()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_output_in_negotiated_format() -> Result<(), anyhow::Error> {
+        let mut engine = AudioEngine::with_backend(
+            NullBackend::new(1, SAMPLE_RATE),
+            1,
+            SampleFormat::I16,
+            SampleRate(SAMPLE_RATE),
+        )?;
+        assert_eq!(engine.stream_settings().sample_format, SampleFormat::I16);
+        {
+            let timeline = engine.timeline();
+            let mut timeline = timeline.lock().unwrap();
+            let track_id = timeline.new_track();
+            let clip = Clip::from_samples(vec![0.3; 2000], 1, SAMPLE_RATE, 0);
+            timeline.get_mut_track(track_id).unwrap().push_clip(clip)?;
+        }
+
+        engine.start_playing()?;
+        engine.backend_mut().run(1000)?;
+        let output = engine.backend_mut().take_output();
+        let declick = output.len() / 2;
+        assert!(
+            output
+                .iter()
+                .all(|sample| i16::from_f64_sample(*sample).to_f64_sample() == *sample)
+        );
+        assert_ne!(output[declick], 0.3);
+        assert!(output[declick..].windows(2).all(|pair| pair[0] == pair[1]));
+
+        // Dithered, the quantized level no longer holds still
+        engine
+            .timeline()
+            .lock()
+            .unwrap()
+            .set_dither_mode(DitherMode::Tpdf);
+        engine.backend_mut().run(1000)?;
+        let output = engine.backend().output();
+        assert!(output.windows(2).any(|pair| pair[0] != pair[1]));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_punch_out_leaves_take_to_the_engine() -> Result<(), anyhow::Error> {
+        let mut engine = engine()?;
+        let timeline = engine.timeline();
+        let track_id = {
+            let mut timeline = timeline.lock().unwrap();
+            let track_id = timeline.new_track();
+            timeline.arm(track_id)?;
+            timeline.set_punch_range(4800, 9600, None)?;
+            timeline.set_pre_roll_seconds(0.05);
+            track_id
+        };
+
+        engine.start_recording()?;
+        engine.backend_mut().feed_input(&[0.5; 2 * 12000]);
+        engine.backend_mut().run(12000)?;
+        assert!(!engine.is_recording());
+        assert_eq!(
+            timeline
+                .lock()
+                .unwrap()
+                .get_track(track_id)
+                .unwrap()
+                .take_count(),
+            0
+        );
+
+        engine.stop_recording()?;
+        let timeline = timeline.lock().unwrap();
+        let take = &timeline.get_track(track_id).unwrap().takes()[0];
+        assert!(take.start_time_in_samples() < 4800 && take.end_time_in_samples() > 9600);
+        assert!(!engine.backend().is_output_running() && !engine.backend().is_input_running());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_failed_recording_closes_input() -> Result<(), anyhow::Error> {
+        let mut engine = engine()?;
+        engine.timeline().lock().unwrap().new_track();
+
+        assert!(matches!(
+            engine.start_recording(),
+            Err(AudioError::NoArmedTrack)
+        ));
+        assert!(!engine.backend().is_input_running());
+        assert!(!engine.is_recording());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_device_selection() -> Result<(), anyhow::Error> {
+        let mut engine = engine()?;
+
+        let devices = engine.list_devices()?;
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].name, NullBackend::DEVICE_NAME);
+        assert!(devices[0].is_default_input && devices[0].is_default_output);
+        assert!(devices[0].is_input() && devices[0].is_output());
+
+        engine.set_output_device(NullBackend::DEVICE_NAME)?;
+        assert!(matches!(
+            engine.set_input_device("Missing"),
+            Err(AudioError::DeviceNotFound(_))
+        ));
+        assert_eq!(
+            engine.output_device_name().as_deref(),
+            Some(NullBackend::DEVICE_NAME)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_input_channels_requested_separately() -> Result<(), anyhow::Error> {
+        let range = |channels| ConfigRange {
+            channels,
+            sample_format: SampleFormat::F32,
+            min_sample_rate: SAMPLE_RATE,
+            max_sample_rate: SAMPLE_RATE,
+            buffer_size: SupportedBufferSize::Range { min: 64, max: 4096 },
+        };
+        let backend = NullBackend::with_configs(vec![range(2), range(8)]);
+        let mut engine =
+            AudioEngine::with_backend(backend, 2, SampleFormat::F32, SampleRate(SAMPLE_RATE))?;
+        let track_id = {
+            let timeline = engine.timeline();
+            let mut timeline = timeline.lock().unwrap();
+            let track_id = timeline.new_track();
+            timeline.arm(track_id)?;
+            track_id
+        };
+
+        // All of the interface goes into one clip
+        engine.start_recording()?;
+        assert_eq!(engine.stream_settings().channels, 2);
+        assert_eq!(engine.backend().input_settings().unwrap().channels, 8);
+        engine.backend_mut().feed_input(&[0.5; 8 * 512]);
+        engine.backend_mut().run(512)?;
+        engine.stop_recording()?;
+        {
+            let timeline = engine.timeline();
+            let timeline = timeline.lock().unwrap();
+            assert_eq!(
+                timeline.get_track(track_id).unwrap().clips()[0].channels(),
+                8
+            );
+        }
+
+        engine.start_monitoring()?;
+        engine.set_input_channels(Some(2))?;
+        assert_eq!(engine.backend().input_settings().unwrap().channels, 2);
+        assert!(matches!(
+            engine.set_input_channels(Some(0)),
+            Err(AudioError::InvalidChannelCount(0))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_latency_profile_reopens_the_input() -> Result<(), anyhow::Error> {
+        let mut engine = engine()?;
+        engine.set_latency_profile(LatencyProfile::Mixing)?;
+        engine.start_monitoring()?;
+        assert_eq!(
+            engine.backend().input_settings().unwrap().buffer_size,
+            Some(1024)
+        );
+
+        engine.set_latency_profile(LatencyProfile::Tracking)?;
+        assert_eq!(engine.stream_settings().buffer_size, Some(128));
+        assert_eq!(
+            engine.backend().input_settings().unwrap().buffer_size,
+            Some(128)
+        );
+        assert!(engine.backend().is_output_running() && engine.is_monitoring());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_engine_negotiates_with_the_device() -> Result<(), anyhow::Error> {
+        let range = |channels, min_sample_rate, max_sample_rate| ConfigRange {
+            channels,
+            sample_format: SampleFormat::F32,
+            min_sample_rate,
+            max_sample_rate,
+            buffer_size: SupportedBufferSize::Range { min: 64, max: 4096 },
+        };
+        let backend =
+            NullBackend::with_configs(vec![range(1, 8000, 96000), range(2, 44100, 48000)]);
+
+        let mut engine =
+            AudioEngine::with_backend(backend, 2, SampleFormat::I16, SampleRate(96000))?;
+        assert_eq!(
+            engine.stream_settings(),
+            StreamSettings {
+                channels: 2,
+                sample_format: SampleFormat::F32,
+                sample_rate: 48000,
+                buffer_size: None,
+            }
+        );
+        assert_eq!(engine.timeline().lock().unwrap().sample_rate(), 48000);
+
+        engine.set_buffer_size(Some(16))?;
+        engine.start_playing()?;
+        engine.backend_mut().run(128)?;
+        assert_eq!(engine.stream_settings().buffer_size, Some(64));
+        assert_eq!(engine.backend().output().len(), 128 * 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_device_change_keeps_streams_running() -> Result<(), anyhow::Error> {
+        let mut engine = engine()?;
+        engine.start_playing()?;
+        engine.start_monitoring()?;
+
+        engine.set_output_device(NullBackend::DEVICE_NAME)?;
+        engine.set_input_device(NullBackend::DEVICE_NAME)?;
+        assert!(matches!(
+            engine.set_output_device("Missing"),
+            Err(AudioError::DeviceNotFound(_))
+        ));
+        assert!(engine.backend().is_output_running() && engine.backend().is_input_running());
+
+        engine.backend_mut().run(512)?;
+        assert_eq!(engine.backend().output().len(), 512 * 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_device_change_refused_while_recording() -> Result<(), anyhow::Error> {
+        let mut engine = engine()?;
+        {
+            let timeline = engine.timeline();
+            let mut timeline = timeline.lock().unwrap();
+            let track_id = timeline.new_track();
+            timeline.arm(track_id)?;
+        }
+        engine.start_recording()?;
+
+        assert!(matches!(
+            engine.set_output_device(NullBackend::DEVICE_NAME),
+            Err(AudioError::RecordingInProgress)
+        ));
+        assert!(matches!(
+            engine.set_input_device(NullBackend::DEVICE_NAME),
+            Err(AudioError::RecordingInProgress)
+        ));
+        assert!(matches!(
+            engine.start_recording(),
+            Err(AudioError::RecordingInProgress)
+        ));
+        assert!(engine.is_recording());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,530 @@
+use std::{
+    fs::{self, File},
+    io::{BufReader, BufWriter, Read, Write},
+    ops::Range,
+    path::Path,
+    time::UNIX_EPOCH,
+};
+
+use crate::engine::AudioError;
+
+// Size and modification time of the audio file a cache was built from. A file edited in
+// place at the same length still gets a new stamp.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SourceStamp {
+    len: u64,
+    modified_nanos: u64,
+}
+
+impl SourceStamp {
+    pub fn of<P: AsRef<Path>>(path: P) -> Result<Self, AudioError> {
+        let metadata = fs::metadata(path)?;
+        let modified_nanos = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_nanos() as u64);
+
+        Ok(SourceStamp {
+            len: metadata.len(),
+            modified_nanos,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Peak {
+    pub min: f32,
+    pub max: f32,
+    pub rms: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Bucket {
+    min: f32,
+    max: f32,
+    sum_squares: f64,
+}
+
+impl Bucket {
+    const EMPTY: Bucket = Bucket {
+        min: f32::MAX,
+        max: f32::MIN,
+        sum_squares: 0.0,
+    };
+
+    fn add_sample(&mut self, sample: f64) {
+        self.min = self.min.min(sample as f32);
+        self.max = self.max.max(sample as f32);
+        self.sum_squares += sample * sample;
+    }
+
+    fn merge(&mut self, other: &Bucket) {
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+        self.sum_squares += other.sum_squares;
+    }
+
+    fn to_peak(self, frames: u64) -> Peak {
+        if frames == 0 {
+            return Peak::default();
+        }
+
+        Peak {
+            min: self.min,
+            max: self.max,
+            rms: (self.sum_squares / frames as f64).sqrt() as f32,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct PeakLevel {
+    frames_per_bucket: u64,
+    buckets: Vec<Bucket>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeakPyramid {
+    channels: u16,
+    sample_rate: u32,
+    frames: u64,
+    levels: Vec<PeakLevel>,
+}
+
+impl PeakPyramid {
+    const BASE_FRAMES_PER_BUCKET: u64 = 64;
+    const LEVEL_FACTOR: u64 = 4;
+    const MAGIC: &[u8; 4] = b"ZPK2";
+    const HEADER_BYTES: u64 = 38;
+    const LEVEL_HEADER_BYTES: u64 = 16;
+    const BUCKET_BYTES: u64 = 16;
+
+    pub fn build(data: &[f64], channels: u16, sample_rate: u32) -> Self {
+        let channel_count = channels.max(1) as usize;
+        let frames = (data.len() / channel_count) as u64;
+
+        let base = PeakLevel {
+            frames_per_bucket: Self::BASE_FRAMES_PER_BUCKET,
+            buckets: data
+                .chunks(Self::BASE_FRAMES_PER_BUCKET as usize * channel_count)
+                .flat_map(|chunk| {
+                    (0..channel_count).map(move |channel| {
+                        let mut bucket = Bucket::EMPTY;
+                        chunk
+                            .iter()
+                            .skip(channel)
+                            .step_by(channel_count)
+                            .for_each(|sample| bucket.add_sample(*sample));
+                        bucket
+                    })
+                })
+                .collect(),
+        };
+
+        let mut levels = vec![base];
+
+        while let Some(level) = levels.last()
+            && level.buckets.len() > channel_count
+        {
+            let next = PeakLevel {
+                frames_per_bucket: level.frames_per_bucket * Self::LEVEL_FACTOR,
+                buckets: level
+                    .buckets
+                    .chunks(Self::LEVEL_FACTOR as usize * channel_count)
+                    .flat_map(|chunk| {
+                        (0..channel_count).map(move |channel| {
+                            let mut bucket = Bucket::EMPTY;
+                            chunk
+                                .iter()
+                                .skip(channel)
+                                .step_by(channel_count)
+                                .for_each(|other| bucket.merge(other));
+                            bucket
+                        })
+                    })
+                    .collect(),
+            };
+            levels.push(next);
+        }
+
+        PeakPyramid {
+            channels,
+            sample_rate,
+            frames,
+            levels,
+        }
+    }
+
+    pub fn matches(&self, channels: u16, sample_rate: u32, frames: u64) -> bool {
+        self.channels == channels && self.sample_rate == sample_rate && self.frames == frames
+    }
+
+    // Returns one peak per pixel for every channel, indexed as [channel][pixel].
+    // `data` is the interleaved audio the pyramid was built from, used when zoomed in
+    // further than the finest level.
+    pub fn peaks(&self, data: &[f64], range: Range<u64>, pixels: usize) -> Vec<Vec<Peak>> {
+        let channel_count = self.channels as usize;
+        let start = range.start.min(self.frames);
+        let end = range.end.min(self.frames);
+
+        if pixels == 0 || start >= end {
+            return vec![Vec::new(); channel_count];
+        }
+
+        let length = end - start;
+        let frames_per_pixel = length / pixels as u64;
+        let level = self
+            .levels
+            .iter()
+            .rev()
+            .find(|level| level.frames_per_bucket <= frames_per_pixel);
+
+        (0..channel_count)
+            .map(|channel| {
+                (0..pixels as u64)
+                    .map(|pixel| {
+                        let from = start + (length as u128 * pixel as u128 / pixels as u128) as u64;
+                        let to =
+                            start + (length as u128 * (pixel as u128 + 1) / pixels as u128) as u64;
+                        let to = to.max(from + 1).min(end);
+
+                        match level {
+                            Some(level) => self.level_peak(level, channel, from..to),
+                            None => Self::raw_peak(data, channel_count, channel, from..to),
+                        }
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn level_peak(&self, level: &PeakLevel, channel: usize, range: Range<u64>) -> Peak {
+        let channel_count = self.channels as usize;
+        let first = range.start / level.frames_per_bucket;
+        let last = range.end.div_ceil(level.frames_per_bucket);
+
+        let mut bucket = Bucket::EMPTY;
+        let mut frames = 0;
+        for index in first..last {
+            if let Some(other) = level.buckets.get(index as usize * channel_count + channel) {
+                bucket.merge(other);
+                frames += level
+                    .frames_per_bucket
+                    .min(self.frames - index * level.frames_per_bucket);
+            }
+        }
+
+        bucket.to_peak(frames)
+    }
+
+    fn raw_peak(data: &[f64], channel_count: usize, channel: usize, range: Range<u64>) -> Peak {
+        let mut bucket = Bucket::EMPTY;
+        let mut frames = 0;
+        for frame in range {
+            if let Some(sample) = data.get(frame as usize * channel_count + channel) {
+                bucket.add_sample(*sample);
+                frames += 1;
+            }
+        }
+
+        bucket.to_peak(frames)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P, source: SourceStamp) -> Result<(), AudioError> {
+        let mut writer = BufWriter::new(File::create(path)?);
+
+        writer.write_all(Self::MAGIC)?;
+        writer.write_all(&self.channels.to_le_bytes())?;
+        writer.write_all(&self.sample_rate.to_le_bytes())?;
+        writer.write_all(&self.frames.to_le_bytes())?;
+        writer.write_all(&source.len.to_le_bytes())?;
+        writer.write_all(&source.modified_nanos.to_le_bytes())?;
+        writer.write_all(&(self.levels.len() as u32).to_le_bytes())?;
+
+        for level in &self.levels {
+            writer.write_all(&level.frames_per_bucket.to_le_bytes())?;
+            writer.write_all(&(level.buckets.len() as u64).to_le_bytes())?;
+            for bucket in &level.buckets {
+                writer.write_all(&bucket.min.to_le_bytes())?;
+                writer.write_all(&bucket.max.to_le_bytes())?;
+                writer.write_all(&bucket.sum_squares.to_le_bytes())?;
+            }
+        }
+
+        writer.flush()?;
+        Ok(())
+    }
+
+    // Only a cache built for exactly this audio, from the source file as it is now, is
+    // accepted. Every count in the file is checked against it and against the file length
+    // before anything is allocated.
+    pub fn load<P: AsRef<Path>>(
+        path: P,
+        channels: u16,
+        sample_rate: u32,
+        frames: u64,
+        source: SourceStamp,
+    ) -> Result<Self, AudioError> {
+        let file = File::open(path)?;
+        let mut remaining = file.metadata()?.len();
+        let mut reader = BufReader::new(file);
+
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != Self::MAGIC {
+            return Err(AudioError::InvalidPeakFile);
+        }
+
+        let header = PeakPyramid {
+            channels: u16::from_le_bytes(Self::read_bytes(&mut reader)?),
+            sample_rate: u32::from_le_bytes(Self::read_bytes(&mut reader)?),
+            frames: u64::from_le_bytes(Self::read_bytes(&mut reader)?),
+            levels: Vec::new(),
+        };
+        let stamp = SourceStamp {
+            len: u64::from_le_bytes(Self::read_bytes(&mut reader)?),
+            modified_nanos: u64::from_le_bytes(Self::read_bytes(&mut reader)?),
+        };
+        let level_count = u32::from_le_bytes(Self::read_bytes(&mut reader)?);
+        let sizes = Self::level_sizes(channels, frames);
+        if !header.matches(channels, sample_rate, frames)
+            || stamp != source
+            || level_count as usize != sizes.len()
+        {
+            return Err(AudioError::InvalidPeakFile);
+        }
+        remaining = remaining.saturating_sub(Self::HEADER_BYTES);
+
+        let mut levels = Vec::with_capacity(sizes.len());
+        for (frames_per_bucket, bucket_count) in sizes {
+            let level_bytes = Self::LEVEL_HEADER_BYTES + bucket_count * Self::BUCKET_BYTES;
+            if u64::from_le_bytes(Self::read_bytes(&mut reader)?) != frames_per_bucket
+                || u64::from_le_bytes(Self::read_bytes(&mut reader)?) != bucket_count
+                || level_bytes > remaining
+            {
+                return Err(AudioError::InvalidPeakFile);
+            }
+            remaining -= level_bytes;
+
+            let mut buckets = Vec::with_capacity(bucket_count as usize);
+            for _ in 0..bucket_count {
+                buckets.push(Bucket {
+                    min: f32::from_le_bytes(Self::read_bytes(&mut reader)?),
+                    max: f32::from_le_bytes(Self::read_bytes(&mut reader)?),
+                    sum_squares: f64::from_le_bytes(Self::read_bytes(&mut reader)?),
+                });
+            }
+
+            levels.push(PeakLevel {
+                frames_per_bucket,
+                buckets,
+            });
+        }
+
+        Ok(PeakPyramid { levels, ..header })
+    }
+
+    // Frames per bucket and bucket count of every level `build` makes for this much audio
+    fn level_sizes(channels: u16, frames: u64) -> Vec<(u64, u64)> {
+        let channels = channels.max(1) as u64;
+        let mut sizes = vec![(
+            Self::BASE_FRAMES_PER_BUCKET,
+            frames.div_ceil(Self::BASE_FRAMES_PER_BUCKET) * channels,
+        )];
+
+        while let Some(&(frames_per_bucket, buckets)) = sizes.last()
+            && buckets > channels
+        {
+            sizes.push((
+                frames_per_bucket * Self::LEVEL_FACTOR,
+                buckets.div_ceil(Self::LEVEL_FACTOR * channels) * channels,
+            ));
+        }
+
+        sizes
+    }
+
+    fn read_bytes<const N: usize>(reader: &mut impl Read) -> Result<[u8; N], AudioError> {
+        let mut bytes = [0u8; N];
+        reader.read_exact(&mut bytes)?;
+        Ok(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signal(frames: usize, channels: usize) -> Vec<f64> {
+        (0..frames * channels)
+            .map(|i| ((i * 7919) % 2001) as f64 / 1000.0 - 1.0)
+            .collect()
+    }
+
+    fn assert_peak_eq(actual: Peak, expected: Peak) {
+        assert!((actual.min - expected.min).abs() < 1e-6);
+        assert!((actual.max - expected.max).abs() < 1e-6);
+        assert!((actual.rms - expected.rms).abs() < 1e-4);
+    }
+
+    #[test]
+    fn test_aligned_levels_match_raw_data() {
+        let data = signal(100_000, 2);
+        let pyramid = PeakPyramid::build(&data, 2, 44100);
+
+        assert_eq!(pyramid.frames, 100_000);
+
+        for frames_per_pixel in [64, 256, 1024, 4096, 16384] {
+            let pixels = (100_000 / frames_per_pixel) as usize;
+            let peaks = pyramid.peaks(&data, 0..frames_per_pixel * pixels as u64, pixels);
+
+            for (channel, channel_peaks) in peaks.iter().enumerate() {
+                assert_eq!(channel_peaks.len(), pixels);
+                for (pixel, peak) in channel_peaks.iter().enumerate() {
+                    let from = pixel as u64 * frames_per_pixel;
+                    let expected =
+                        PeakPyramid::raw_peak(&data, 2, channel, from..from + frames_per_pixel);
+                    assert_peak_eq(*peak, expected);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_unaligned_pixels_cover_raw_data() {
+        let data = signal(100_000, 2);
+        let pyramid = PeakPyramid::build(&data, 2, 44100);
+        let range = 123..98_765;
+        let pixels = 333;
+        let peaks = pyramid.peaks(&data, range.clone(), pixels);
+        let length = range.end - range.start;
+
+        for (channel, channel_peaks) in peaks.iter().enumerate() {
+            for (pixel, peak) in channel_peaks.iter().enumerate() {
+                let from = range.start + length * pixel as u64 / pixels as u64;
+                let to = range.start + length * (pixel as u64 + 1) / pixels as u64;
+                let expected = PeakPyramid::raw_peak(&data, 2, channel, from..to);
+                assert!(peak.min <= expected.min);
+                assert!(peak.max >= expected.max);
+            }
+        }
+    }
+
+    #[test]
+    fn test_whole_clip_single_pixel() {
+        let data = signal(10_000, 1);
+        let pyramid = PeakPyramid::build(&data, 1, 44100);
+        let peaks = pyramid.peaks(&data, 0..10_000, 1);
+        let expected = PeakPyramid::raw_peak(&data, 1, 0, 0..10_000);
+
+        assert_peak_eq(peaks[0][0], expected);
+    }
+
+    #[test]
+    fn test_zoomed_in_uses_raw_data() {
+        let data = vec![0.0, 0.5, -0.5, 1.0];
+        let pyramid = PeakPyramid::build(&data, 1, 44100);
+        let peaks = pyramid.peaks(&data, 0..4, 8);
+
+        assert_eq!(peaks[0].len(), 8);
+        assert_eq!(peaks[0][2].max, 0.5);
+        assert_eq!(peaks[0][4].min, -0.5);
+        assert_eq!(peaks[0][7].max, 1.0);
+    }
+
+    #[test]
+    fn test_empty_queries() {
+        let data = signal(1000, 2);
+        let pyramid = PeakPyramid::build(&data, 2, 44100);
+
+        assert_eq!(pyramid.peaks(&data, 0..1000, 0), vec![Vec::new(); 2]);
+        assert_eq!(pyramid.peaks(&data, 500..500, 10), vec![Vec::new(); 2]);
+        assert_eq!(pyramid.peaks(&data, 2000..3000, 10), vec![Vec::new(); 2]);
+    }
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("zari-{name}-{}.pk", std::process::id()))
+    }
+
+    const STAMP: SourceStamp = SourceStamp {
+        len: 80_044,
+        modified_nanos: 1_700_000_000_000_000_000,
+    };
+
+    #[test]
+    fn test_save_load_roundtrip() -> Result<(), AudioError> {
+        let data = signal(20_000, 2);
+        let pyramid = PeakPyramid::build(&data, 2, 48000);
+        let path = temp_path("peaks");
+
+        pyramid.save(&path, STAMP)?;
+        let loaded = PeakPyramid::load(&path, 2, 48000, 20_000, STAMP);
+        let other_rate = PeakPyramid::load(&path, 2, 44100, 20_000, STAMP);
+        let edited = PeakPyramid::load(
+            &path,
+            2,
+            48000,
+            20_000,
+            SourceStamp {
+                modified_nanos: STAMP.modified_nanos + 1,
+                ..STAMP
+            },
+        );
+        std::fs::remove_file(&path)?;
+
+        assert_eq!(loaded?, pyramid);
+        assert!(matches!(other_rate, Err(AudioError::InvalidPeakFile)));
+        assert!(matches!(edited, Err(AudioError::InvalidPeakFile)));
+        assert_eq!(
+            PeakPyramid::level_sizes(2, 20_000),
+            pyramid
+                .levels
+                .iter()
+                .map(|level| (level.frames_per_bucket, level.buckets.len() as u64))
+                .collect::<Vec<_>>()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_rejects_corrupt_counts() -> Result<(), AudioError> {
+        let data = signal(20_000, 2);
+        let path = temp_path("corrupt-peaks");
+        PeakPyramid::build(&data, 2, 48000).save(&path, STAMP)?;
+        let bytes = std::fs::read(&path)?;
+
+        // Truncated part way through the buckets
+        std::fs::write(&path, &bytes[..bytes.len() / 2])?;
+        let truncated = PeakPyramid::load(&path, 2, 48000, 20_000, STAMP);
+
+        // A bucket count far beyond the file, which must not be allocated
+        let mut huge = bytes.clone();
+        huge[46..54].copy_from_slice(&(u64::MAX / 32).to_le_bytes());
+        std::fs::write(&path, &huge)?;
+        let oversized = PeakPyramid::load(&path, 2, 48000, 20_000, STAMP);
+
+        // A header claiming more audio than the clip has
+        let mut longer = bytes;
+        longer[10..18].copy_from_slice(&u64::MAX.to_le_bytes());
+        std::fs::write(&path, &longer)?;
+        let mismatched = PeakPyramid::load(&path, 2, 48000, 20_000, STAMP);
+        std::fs::remove_file(&path)?;
+
+        assert!(matches!(truncated, Err(AudioError::InvalidPeakFile)));
+        assert!(matches!(oversized, Err(AudioError::InvalidPeakFile)));
+        assert!(matches!(mismatched, Err(AudioError::InvalidPeakFile)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_rejects_other_files() {
+        let path = format!("{}/sample-i16-stereo.wav", env!("CARGO_MANIFEST_DIR"));
+
+        assert!(matches!(
+            PeakPyramid::load(&path, 2, 8000, 0, SourceStamp::of(&path).unwrap()),
+            Err(AudioError::InvalidPeakFile)
+        ));
+    }
+}
@@ -0,0 +1,289 @@
+use crate::engine::AudioError;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PitchPoint {
+    pub time_seconds: f64,
+    pub frequency: Option<f64>,
+    pub confidence: f64,
+}
+
+impl PitchPoint {
+    pub fn is_voiced(&self) -> bool {
+        self.frequency.is_some()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PitchTracker {
+    pub min_frequency: f64,
+    pub max_frequency: f64,
+    pub threshold: f64,
+    pub hop_seconds: f64,
+    pub silence_rms: f64,
+}
+
+impl Default for PitchTracker {
+    fn default() -> Self {
+        // Covers bass to soprano, with a hop fine enough to follow vibrato
+        PitchTracker {
+            min_frequency: 60.0,
+            max_frequency: 1200.0,
+            threshold: 0.15,
+            hop_seconds: 0.01,
+            silence_rms: 1e-3,
+        }
+    }
+}
+
+impl PitchTracker {
+    // YIN: the cumulative mean normalised difference function picks the first lag that
+    // dips under the threshold, refined with parabolic interpolation.
+    pub fn track(&self, samples: &[f64], sample_rate: u32) -> Result<Vec<PitchPoint>, AudioError> {
+        // The lags are derived from the range, so it has to be sane before anything is sized
+        if sample_rate == 0 {
+            return Err(AudioError::InvalidSampleRate(sample_rate));
+        }
+        if !(self.min_frequency > 0.0
+            && self.max_frequency > self.min_frequency
+            && self.max_frequency.is_finite())
+        {
+            return Err(AudioError::InvalidPitchRange {
+                min: self.min_frequency,
+                max: self.max_frequency,
+            });
+        }
+
+        let sample_rate_f64 = sample_rate as f64;
+        let min_lag = ((sample_rate_f64 / self.max_frequency).floor() as usize).max(2);
+        let max_lag = (sample_rate_f64 / self.min_frequency).ceil() as usize;
+        let window = max_lag;
+        let frame_len = window + max_lag + 1;
+        let hop = ((self.hop_seconds * sample_rate_f64).round() as usize).max(1);
+
+        if min_lag >= max_lag || samples.len() < frame_len {
+            return Ok(Vec::new());
+        }
+
+        let mut difference = vec![0.0; max_lag + 1];
+        let mut normalised = vec![1.0; max_lag + 1];
+
+        Ok((0..=samples.len() - frame_len)
+            .step_by(hop)
+            .map(|start| {
+                let frame = &samples[start..start + frame_len];
+                let time_seconds = (start + window / 2) as f64 / sample_rate_f64;
+
+                let rms =
+                    (frame[..window].iter().map(|s| s * s).sum::<f64>() / window as f64).sqrt();
+                if rms < self.silence_rms {
+                    return PitchPoint {
+                        time_seconds,
+                        frequency: None,
+                        confidence: 0.0,
+                    };
+                }
+
+                Self::difference(frame, window, &mut difference);
+                Self::normalise(&difference, &mut normalised);
+
+                match self.best_lag(&normalised, min_lag, max_lag) {
+                    Some(lag) => {
+                        let refined = Self::parabolic(&normalised, lag);
+                        PitchPoint {
+                            time_seconds,
+                            frequency: Some(sample_rate_f64 / refined),
+                            confidence: (1.0 - normalised[lag]).clamp(0.0, 1.0),
+                        }
+                    }
+                    None => {
+                        let lowest = normalised[min_lag..=max_lag]
+                            .iter()
+                            .copied()
+                            .fold(f64::MAX, f64::min);
+                        PitchPoint {
+                            time_seconds,
+                            frequency: None,
+                            confidence: (1.0 - lowest).clamp(0.0, 1.0),
+                        }
+                    }
+                }
+            })
+            .collect())
+    }
+
+    fn difference(frame: &[f64], window: usize, difference: &mut [f64]) {
+        difference[0] = 0.0;
+        for lag in 1..difference.len() {
+            difference[lag] = (0..window)
+                .map(|j| {
+                    let delta = frame[j] - frame[j + lag];
+                    delta * delta
+                })
+                .sum();
+        }
+    }
+
+    fn normalise(difference: &[f64], normalised: &mut [f64]) {
+        normalised[0] = 1.0;
+        let mut running_sum = 0.0;
+        for lag in 1..difference.len() {
+            running_sum += difference[lag];
+            normalised[lag] = if running_sum > 0.0 {
+                difference[lag] * lag as f64 / running_sum
+            } else {
+                1.0
+            };
+        }
+    }
+
+    fn best_lag(&self, normalised: &[f64], min_lag: usize, max_lag: usize) -> Option<usize> {
+        let mut lag = (min_lag..max_lag).find(|&lag| normalised[lag] < self.threshold)?;
+
+        while lag + 1 < max_lag && normalised[lag + 1] < normalised[lag] {
+            lag += 1;
+        }
+
+        Some(lag)
+    }
+
+    fn parabolic(values: &[f64], index: usize) -> f64 {
+        if index == 0 || index + 1 >= values.len() {
+            return index as f64;
+        }
+
+        let (left, centre, right) = (values[index - 1], values[index], values[index + 1]);
+        let denominator = left - 2.0 * centre + right;
+
+        if denominator.abs() < f64::EPSILON {
+            return index as f64;
+        }
+
+        index as f64 + 0.5 * (left - right) / denominator
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 44100;
+
+    fn sine(frequency: f64, seconds: f64) -> Vec<f64> {
+        let frames = (seconds * SAMPLE_RATE as f64) as usize;
+        (0..frames)
+            .map(|i| {
+                0.5 * (2.0 * std::f64::consts::PI * frequency * i as f64 / SAMPLE_RATE as f64).sin()
+            })
+            .collect()
+    }
+
+    fn sawtooth(frequency: f64, seconds: f64) -> Vec<f64> {
+        let frames = (seconds * SAMPLE_RATE as f64) as usize;
+        (0..frames)
+            .map(|i| {
+                let phase = (frequency * i as f64 / SAMPLE_RATE as f64).fract();
+                0.5 * (2.0 * phase - 1.0)
+            })
+            .collect()
+    }
+
+    fn cents(frequency: f64, reference: f64) -> f64 {
+        1200.0 * (frequency / reference).log2()
+    }
+
+    fn assert_tracks(samples: &[f64], expected: f64) -> Result<(), AudioError> {
+        let track = PitchTracker::default().track(samples, SAMPLE_RATE)?;
+
+        assert!(!track.is_empty());
+        for point in &track {
+            let frequency = point.frequency.expect("frame should be voiced");
+            assert!(
+                cents(frequency, expected).abs() < 5.0,
+                "expected {expected} Hz, got {frequency} Hz"
+            );
+            assert!(point.confidence > 0.8);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sine() -> Result<(), AudioError> {
+        for frequency in [82.41, 220.0, 440.0, 987.77] {
+            assert_tracks(&sine(frequency, 0.25), frequency)?;
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sawtooth() -> Result<(), AudioError> {
+        for frequency in [110.0, 261.63, 659.25] {
+            assert_tracks(&sawtooth(frequency, 0.25), frequency)?;
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_silence_is_unvoiced() -> Result<(), AudioError> {
+        let track =
+            PitchTracker::default().track(&vec![0.0; SAMPLE_RATE as usize / 4], SAMPLE_RATE)?;
+
+        assert!(!track.is_empty());
+        assert!(track.iter().all(|point| !point.is_voiced()));
+        assert!(track.iter().all(|point| point.confidence == 0.0));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_timestamps_advance_by_hop() -> Result<(), AudioError> {
+        let tracker = PitchTracker::default();
+        let track = tracker.track(&sine(220.0, 0.25), SAMPLE_RATE)?;
+
+        for pair in track.windows(2) {
+            let step = pair[1].time_seconds - pair[0].time_seconds;
+            assert!((step - tracker.hop_seconds).abs() < 1.0 / SAMPLE_RATE as f64);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_too_short_input() -> Result<(), AudioError> {
+        assert!(
+            PitchTracker::default()
+                .track(&sine(220.0, 0.01), SAMPLE_RATE)?
+                .is_empty()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rejects_invalid_range() {
+        let samples = sine(220.0, 0.25);
+        let tracker = |min_frequency, max_frequency| PitchTracker {
+            min_frequency,
+            max_frequency,
+            ..PitchTracker::default()
+        };
+
+        for (min, max) in [
+            (0.0, 1200.0),
+            (-60.0, 1200.0),
+            (500.0, 400.0),
+            (60.0, f64::NAN),
+        ] {
+            assert!(matches!(
+                tracker(min, max).track(&samples, SAMPLE_RATE),
+                Err(AudioError::InvalidPitchRange { .. })
+            ));
+        }
+        assert!(matches!(
+            PitchTracker::default().track(&samples, 0),
+            Err(AudioError::InvalidSampleRate(0))
+        ));
+    }
+}
@@ -0,0 +1,491 @@
+use crate::engine::{AudioError, PitchPoint, PitchTracker};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MusicalScale {
+    #[default]
+    Chromatic,
+    Major,
+    NaturalMinor,
+    HarmonicMinor,
+}
+
+impl MusicalScale {
+    fn pitch_classes(&self) -> &'static [u8] {
+        match self {
+            MusicalScale::Chromatic => &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11],
+            MusicalScale::Major => &[0, 2, 4, 5, 7, 9, 11],
+            MusicalScale::NaturalMinor => &[0, 2, 3, 5, 7, 8, 10],
+            MusicalScale::HarmonicMinor => &[0, 2, 3, 5, 7, 8, 11],
+        }
+    }
+
+    // Nearest note of the scale to a (fractional) MIDI note, with `root` as a pitch class
+    pub fn nearest_note(&self, root: u8, midi_note: f64) -> f64 {
+        let base = midi_note.floor() as i64;
+
+        (base - 12..=base + 12)
+            .filter(|note| {
+                let class = (note - root as i64).rem_euclid(12) as u8;
+                self.pitch_classes().contains(&class)
+            })
+            .map(|note| note as f64)
+            .min_by(|a, b| (a - midi_note).abs().total_cmp(&(b - midi_note).abs()))
+            .unwrap_or(midi_note)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NotePoint {
+    pub time_seconds: f64,
+    pub midi_note: f64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CorrectionTarget {
+    Scale { root: u8, scale: MusicalScale },
+    NoteCurve(Vec<NotePoint>),
+}
+
+impl CorrectionTarget {
+    fn target_note(&self, time_seconds: f64, midi_note: f64) -> f64 {
+        match self {
+            CorrectionTarget::Scale { root, scale } => scale.nearest_note(*root, midi_note),
+            CorrectionTarget::NoteCurve(points) => {
+                let index = points.partition_point(|point| point.time_seconds <= time_seconds);
+                match (index.checked_sub(1).map(|i| &points[i]), points.get(index)) {
+                    (Some(before), Some(after)) => {
+                        let span = after.time_seconds - before.time_seconds;
+                        let position = (time_seconds - before.time_seconds) / span;
+                        before.midi_note + (after.midi_note - before.midi_note) * position
+                    }
+                    (Some(point), None) | (None, Some(point)) => point.midi_note,
+                    (None, None) => midi_note,
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PitchCorrection {
+    pub target: CorrectionTarget,
+    pub retune_seconds: f64,
+    pub amount: f64,
+    pub tracker: PitchTracker,
+}
+
+impl PitchCorrection {
+    // Used for the grain size where nothing is voiced
+    const UNVOICED_FREQUENCY: f64 = 100.0;
+
+    pub fn new(target: CorrectionTarget) -> Self {
+        PitchCorrection {
+            target,
+            retune_seconds: 0.0,
+            amount: 1.0,
+            tracker: PitchTracker::default(),
+        }
+    }
+
+    pub fn with_retune_seconds(mut self, retune_seconds: f64) -> Self {
+        self.retune_seconds = retune_seconds.max(0.0);
+        self
+    }
+
+    pub fn with_amount(mut self, amount: f64) -> Self {
+        self.amount = amount.clamp(0.0, 1.0);
+        self
+    }
+
+    // The same correction for audio that starts `seconds` later, as a clip split there does
+    pub fn shifted(&self, seconds: f64) -> Self {
+        let target = match &self.target {
+            CorrectionTarget::NoteCurve(points) => CorrectionTarget::NoteCurve(
+                points
+                    .iter()
+                    .map(|point| NotePoint {
+                        time_seconds: point.time_seconds - seconds,
+                        ..*point
+                    })
+                    .collect(),
+            ),
+            target => target.clone(),
+        };
+
+        PitchCorrection {
+            target,
+            ..self.clone()
+        }
+    }
+
+    // A note curve needs at least one point, all finite and in time order
+    pub fn validate(&self) -> Result<(), AudioError> {
+        match &self.target {
+            CorrectionTarget::Scale { .. } => Ok(()),
+            CorrectionTarget::NoteCurve(points) => {
+                let finite = points
+                    .iter()
+                    .all(|point| point.time_seconds.is_finite() && point.midi_note.is_finite());
+                let sorted = points
+                    .windows(2)
+                    .all(|pair| pair[0].time_seconds < pair[1].time_seconds);
+
+                if points.is_empty() || !finite || !sorted {
+                    Err(AudioError::InvalidNoteCurve)
+                } else {
+                    Ok(())
+                }
+            }
+        }
+    }
+
+    pub fn midi_to_frequency(midi_note: f64) -> f64 {
+        440.0 * 2f64.powf((midi_note - 69.0) / 12.0)
+    }
+
+    pub fn frequency_to_midi(frequency: f64) -> f64 {
+        69.0 + 12.0 * (frequency / 440.0).log2()
+    }
+
+    // Renders the corrected audio with TD-PSOLA: grains two periods long are cut at the
+    // detected pitch period and laid back down at the corrected period. The grains keep
+    // their own spectrum, so the formants stay where they are.
+    pub fn render(
+        &self,
+        data: &[f64],
+        channels: u16,
+        sample_rate: u32,
+    ) -> Result<Vec<f64>, AudioError> {
+        self.validate()?;
+
+        let channel_count = channels.max(1) as usize;
+        let frames = data.len() / channel_count;
+
+        let mono: Vec<f64> = data
+            .chunks_exact(channel_count)
+            .map(|frame| frame.iter().sum::<f64>() / channel_count as f64)
+            .collect();
+        let pitch_track = self.tracker.track(&mono, sample_rate)?;
+
+        if frames == 0 || !pitch_track.iter().any(PitchPoint::is_voiced) {
+            return Ok(data.to_vec());
+        }
+
+        let corrections = self.corrections(&pitch_track);
+        let sample_rate = sample_rate as f64;
+        let period_at = |frame: f64| -> f64 {
+            let frequency =
+                Self::interpolate(&pitch_track, frame / sample_rate, |point| point.frequency)
+                    .unwrap_or(Self::UNVOICED_FREQUENCY);
+            (sample_rate / frequency).max(2.0)
+        };
+        let ratio_at = |frame: f64| -> f64 {
+            let cents = Self::interpolate_values(&pitch_track, &corrections, frame / sample_rate);
+            2f64.powf(cents / 1200.0)
+        };
+
+        let mut analysis_marks = Vec::new();
+        let mut mark = 0.0;
+        while mark < frames as f64 {
+            analysis_marks.push(mark);
+            mark += period_at(mark);
+        }
+
+        let mut output = vec![0.0; data.len()];
+        let mut weights = vec![0.0; frames];
+        let mut synthesis_mark = 0.0;
+
+        while synthesis_mark < frames as f64 {
+            let index = analysis_marks
+                .partition_point(|mark| *mark < synthesis_mark)
+                .min(analysis_marks.len() - 1);
+            let analysis_mark = [index.saturating_sub(1), index]
+                .into_iter()
+                .map(|index| analysis_marks[index])
+                .min_by(|a, b| {
+                    (a - synthesis_mark)
+                        .abs()
+                        .total_cmp(&(b - synthesis_mark).abs())
+                })
+                .unwrap_or(0.0);
+
+            let period = period_at(analysis_mark).round() as i64;
+            let source_centre = analysis_mark.round() as i64;
+            let target_centre = synthesis_mark.round() as i64;
+
+            for offset in -period..period {
+                let source = source_centre + offset;
+                let target = target_centre + offset;
+                if source < 0 || target < 0 || source >= frames as i64 || target >= frames as i64 {
+                    continue;
+                }
+
+                let phase = (offset + period) as f64 / (2 * period) as f64;
+                let weight = 0.5 - 0.5 * (2.0 * std::f64::consts::PI * phase).cos();
+                let (source, target) = (source as usize, target as usize);
+
+                for channel in 0..channel_count {
+                    output[target * channel_count + channel] +=
+                        data[source * channel_count + channel] * weight;
+                }
+                weights[target] += weight;
+            }
+
+            synthesis_mark += period_at(synthesis_mark) / ratio_at(synthesis_mark);
+        }
+
+        for (frame, weight) in weights.iter().enumerate() {
+            for channel in 0..channel_count {
+                let sample = &mut output[frame * channel_count + channel];
+                *sample = if *weight > 1e-3 {
+                    *sample / weight
+                } else {
+                    0.0
+                };
+            }
+        }
+
+        Ok(output)
+    }
+
+    // Correction in cents for each analysis frame, smoothed by the retune speed
+    fn corrections(&self, pitch_track: &[PitchPoint]) -> Vec<f64> {
+        let hop = self.tracker.hop_seconds;
+        let smoothing = if self.retune_seconds > 0.0 {
+            1.0 - (-hop / self.retune_seconds).exp()
+        } else {
+            1.0
+        };
+
+        let mut correction = 0.0;
+        pitch_track
+            .iter()
+            .map(|point| {
+                let target = match point.frequency {
+                    Some(frequency) => {
+                        let midi_note = Self::frequency_to_midi(frequency);
+                        let target_note = self.target.target_note(point.time_seconds, midi_note);
+                        (target_note - midi_note) * 100.0 * self.amount
+                    }
+                    None => 0.0,
+                };
+                correction += (target - correction) * smoothing;
+                correction
+            })
+            .collect()
+    }
+
+    fn interpolate<F>(pitch_track: &[PitchPoint], time_seconds: f64, value: F) -> Option<f64>
+    where
+        F: Fn(&PitchPoint) -> Option<f64>,
+    {
+        let index = pitch_track.partition_point(|point| point.time_seconds <= time_seconds);
+        let before = index.checked_sub(1).and_then(|i| pitch_track.get(i));
+        let after = pitch_track.get(index);
+
+        match (before, after) {
+            (Some(before), Some(after)) => match (value(before), value(after)) {
+                (Some(a), Some(b)) => {
+                    let span = after.time_seconds - before.time_seconds;
+                    let position = (time_seconds - before.time_seconds) / span;
+                    Some(a + (b - a) * position)
+                }
+                (a, b) => a.or(b),
+            },
+            (Some(point), None) | (None, Some(point)) => value(point),
+            (None, None) => None,
+        }
+    }
+
+    fn interpolate_values(pitch_track: &[PitchPoint], values: &[f64], time_seconds: f64) -> f64 {
+        let index = pitch_track.partition_point(|point| point.time_seconds <= time_seconds);
+        match (index.checked_sub(1), index < values.len()) {
+            (Some(before), true) => {
+                let span = pitch_track[index].time_seconds - pitch_track[before].time_seconds;
+                let position = (time_seconds - pitch_track[before].time_seconds) / span;
+                values[before] + (values[index] - values[before]) * position
+            }
+            (Some(before), false) => values[before],
+            (None, _) => values.first().copied().unwrap_or(0.0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 44100;
+
+    fn voice(frequency: f64, seconds: f64) -> Vec<f64> {
+        let frames = (seconds * SAMPLE_RATE as f64) as usize;
+        (0..frames)
+            .map(|i| {
+                let t = i as f64 / SAMPLE_RATE as f64;
+                (1..=4)
+                    .map(|harmonic| {
+                        let harmonic = harmonic as f64;
+                        0.3 / harmonic
+                            * (2.0 * std::f64::consts::PI * frequency * harmonic * t).sin()
+                    })
+                    .sum()
+            })
+            .collect()
+    }
+
+    fn median_frequency(samples: &[f64]) -> f64 {
+        let mut frequencies: Vec<f64> = PitchTracker::default()
+            .track(samples, SAMPLE_RATE)
+            .unwrap()
+            .iter()
+            .filter_map(|point| point.frequency)
+            .collect();
+        frequencies.sort_by(f64::total_cmp);
+        frequencies[frequencies.len() / 2]
+    }
+
+    fn rms(samples: &[f64]) -> f64 {
+        (samples.iter().map(|s| s * s).sum::<f64>() / samples.len() as f64).sqrt()
+    }
+
+    fn cents(frequency: f64, reference: f64) -> f64 {
+        1200.0 * (frequency / reference).log2()
+    }
+
+    #[test]
+    fn test_nearest_note() {
+        assert_eq!(MusicalScale::Chromatic.nearest_note(0, 69.4), 69.0);
+        assert_eq!(MusicalScale::Chromatic.nearest_note(0, 69.6), 70.0);
+        // C major has no A#, so 70.2 goes to A or B
+        assert_eq!(MusicalScale::Major.nearest_note(0, 70.2), 71.0);
+        assert_eq!(MusicalScale::Major.nearest_note(0, 69.8), 69.0);
+        // D major has F#
+        assert_eq!(MusicalScale::Major.nearest_note(2, 65.4), 66.0);
+    }
+
+    #[test]
+    fn test_note_curve_interpolates() {
+        let target = CorrectionTarget::NoteCurve(vec![
+            NotePoint {
+                time_seconds: 0.0,
+                midi_note: 60.0,
+            },
+            NotePoint {
+                time_seconds: 1.0,
+                midi_note: 62.0,
+            },
+        ]);
+
+        assert_eq!(target.target_note(-1.0, 0.0), 60.0);
+        assert_eq!(target.target_note(0.5, 0.0), 61.0);
+        assert_eq!(target.target_note(2.0, 0.0), 62.0);
+    }
+
+    #[test]
+    fn test_rejects_invalid_note_curves() {
+        let point = |time_seconds, midi_note| NotePoint {
+            time_seconds,
+            midi_note,
+        };
+        let curves = [
+            vec![],
+            vec![point(1.0, 60.0), point(0.5, 62.0)],
+            vec![point(0.5, 60.0), point(0.5, 62.0)],
+            vec![point(0.0, f64::NAN)],
+            vec![point(f64::INFINITY, 60.0)],
+        ];
+
+        for curve in curves {
+            let correction = PitchCorrection::new(CorrectionTarget::NoteCurve(curve));
+            assert!(matches!(
+                correction.render(&voice(220.0, 0.1), 1, SAMPLE_RATE),
+                Err(AudioError::InvalidNoteCurve)
+            ));
+        }
+    }
+
+    #[test]
+    fn test_corrects_to_scale() -> Result<(), AudioError> {
+        // 25 cents sharp of A4
+        let input = voice(440.0 * 2f64.powf(25.0 / 1200.0), 0.5);
+        let correction = PitchCorrection::new(CorrectionTarget::Scale {
+            root: 0,
+            scale: MusicalScale::Chromatic,
+        });
+
+        let output = correction.render(&input, 1, SAMPLE_RATE)?;
+
+        assert_eq!(output.len(), input.len());
+        assert!(cents(median_frequency(&output), 440.0).abs() < 5.0);
+        assert!((rms(&output) / rms(&input) - 1.0).abs() < 0.2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_corrects_to_note_curve() -> Result<(), AudioError> {
+        let input = voice(220.0, 0.5);
+        let correction = PitchCorrection::new(CorrectionTarget::NoteCurve(vec![NotePoint {
+            time_seconds: 0.0,
+            midi_note: 59.0,
+        }]));
+
+        let output = correction.render(&input, 1, SAMPLE_RATE)?;
+        let expected = PitchCorrection::midi_to_frequency(59.0);
+
+        assert!(cents(median_frequency(&output), expected).abs() < 5.0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_zero_amount_leaves_pitch() -> Result<(), AudioError> {
+        let frequency = 440.0 * 2f64.powf(30.0 / 1200.0);
+        let input = voice(frequency, 0.5);
+        let correction = PitchCorrection::new(CorrectionTarget::Scale {
+            root: 0,
+            scale: MusicalScale::Chromatic,
+        })
+        .with_amount(0.0);
+
+        let output = correction.render(&input, 1, SAMPLE_RATE)?;
+
+        assert!(cents(median_frequency(&output), frequency).abs() < 3.0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_slow_retune_lags_behind() -> Result<(), AudioError> {
+        let input = voice(440.0 * 2f64.powf(40.0 / 1200.0), 0.5);
+        let target = CorrectionTarget::Scale {
+            root: 0,
+            scale: MusicalScale::Chromatic,
+        };
+        let pitch_track = PitchTracker::default().track(&input, SAMPLE_RATE)?;
+
+        let fast = PitchCorrection::new(target.clone()).corrections(&pitch_track);
+        let slow = PitchCorrection::new(target)
+            .with_retune_seconds(0.1)
+            .corrections(&pitch_track);
+
+        assert!((fast[0] + 40.0).abs() < 3.0);
+        assert!(slow[0].abs() < fast[0].abs());
+        assert!((slow[slow.len() - 1] - fast[fast.len() - 1]).abs() < 3.0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_silence_is_untouched() -> Result<(), AudioError> {
+        let input = vec![0.0; 4410 * 2];
+        let correction = PitchCorrection::new(CorrectionTarget::Scale {
+            root: 0,
+            scale: MusicalScale::Major,
+        });
+
+        assert_eq!(correction.render(&input, 2, SAMPLE_RATE)?, input);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,320 @@
+use std::ops::Range;
+
+use crate::engine::{Clip, ToF64Sample, TrackId};
+
+#[derive(Debug, Clone)]
+pub struct Recording {
+    track_id: TrackId,
+    channels: u16,
+    sample_rate: u32,
+    start_time_in_samples: u64,
+    cycle: Option<Range<u64>>,
+    punch: Option<Range<u64>>,
+    punch_handle: u64,
+    stopped: bool,
+    // Input is written into chunks allocated ahead of time, never grown on the audio thread.
+    // `add_chunks` tops up the spares from another thread.
+    chunks: Vec<Vec<f64>>,
+    spare: Vec<Vec<f64>>,
+}
+
+impl Recording {
+    const CHUNK_SECONDS: f64 = 1.0;
+    // How far the input can run ahead of the thread that tops up the spares
+    const SPARE_CHUNKS: usize = 4;
+
+    pub fn new(
+        track_id: TrackId,
+        channels: u16,
+        sample_rate: u32,
+        start_time_in_samples: u64,
+    ) -> Self {
+        let mut recording = Recording {
+            track_id,
+            channels,
+            sample_rate,
+            start_time_in_samples,
+            cycle: None,
+            punch: None,
+            punch_handle: 0,
+            stopped: false,
+            chunks: Vec::new(),
+            spare: Vec::new(),
+        };
+        let chunks = (0..=Self::SPARE_CHUNKS)
+            .map(|_| Self::allocate_chunk(recording.chunk_samples()))
+            .collect();
+        recording.add_chunks(chunks);
+        recording
+    }
+
+    // Samples per chunk, a whole number of frames
+    pub fn chunk_samples(&self) -> usize {
+        let frames = (Self::CHUNK_SECONDS * self.sample_rate as f64).ceil() as usize;
+        frames.max(1) * self.channels.max(1) as usize
+    }
+
+    // Written through once, so the pages are mapped before the audio thread gets the chunk
+    pub fn allocate_chunk(samples: usize) -> Vec<f64> {
+        let mut chunk = vec![0.0; samples];
+        chunk.clear();
+        chunk
+    }
+
+    pub fn spare_chunks_needed(&self) -> usize {
+        if self.stopped {
+            return 0;
+        }
+        Self::SPARE_CHUNKS.saturating_sub(self.spare.len())
+    }
+
+    // Called off the audio thread. Room for the chunks to be filled is reserved here too.
+    pub fn add_chunks(&mut self, chunks: Vec<Vec<f64>>) {
+        self.chunks.reserve(self.spare.len() + chunks.len() + 1);
+        self.spare.extend(chunks);
+    }
+
+    // Playback wraps at the end of the range, so every pass over it is a separate take. Started
+    // before the range, the lead-in up to the first wrap is the first take.
+    pub fn with_cycle(mut self, cycle: Range<u64>) -> Self {
+        if self.start_time_in_samples < cycle.end {
+            self.cycle = Some(cycle);
+        }
+        self
+    }
+
+    pub fn is_cycle(&self) -> bool {
+        self.cycle.is_some()
+    }
+
+    // Input is taken from the pre-roll on, but only the punch range is kept, with `handle`
+    // frames either side for the crossfades at the punch points
+    pub fn with_punch(mut self, punch: Range<u64>, handle: u64) -> Self {
+        self.punch = Some(punch);
+        self.punch_handle = handle;
+        self
+    }
+
+    pub fn punch(&self) -> Option<Range<u64>> {
+        self.punch.clone()
+    }
+
+    fn kept_range(&self) -> Option<Range<u64>> {
+        self.punch.as_ref().map(|punch| {
+            punch.start.saturating_sub(self.punch_handle)..punch.end + self.punch_handle
+        })
+    }
+
+    pub fn is_past_punch_out(&self) -> bool {
+        self.kept_range()
+            .is_some_and(|kept| self.start_time_in_samples + self.duration_in_samples() >= kept.end)
+    }
+
+    // Stopped from a stream callback: no more input is taken, and the takes are made later
+    // off the audio thread
+    pub fn stop(&mut self) {
+        self.stopped = true;
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stopped
+    }
+
+    pub fn track_id(&self) -> TrackId {
+        self.track_id
+    }
+
+    pub fn duration_in_samples(&self) -> u64 {
+        if self.channels == 0 {
+            return 0;
+        }
+        let samples: usize = self.chunks.iter().map(Vec::len).sum();
+        samples as u64 / self.channels as u64
+    }
+
+    // False when the spares ran out and the rest of the input was dropped
+    pub fn push<T>(&mut self, input: &[T]) -> bool
+    where
+        T: ToF64Sample,
+    {
+        if self.stopped {
+            return true;
+        }
+
+        let mut input = input.iter().map(|sample| sample.to_f64_sample());
+        loop {
+            let chunk = match self.chunks.last_mut() {
+                Some(chunk) if chunk.len() < chunk.capacity() => chunk,
+                _ => match self.spare.pop() {
+                    Some(chunk) => {
+                        self.chunks.push(chunk);
+                        self.chunks.last_mut().unwrap()
+                    }
+                    None => return input.next().is_none(),
+                },
+            };
+            let room = chunk.capacity() - chunk.len();
+            chunk.extend(input.by_ref().take(room));
+            if chunk.len() < chunk.capacity() {
+                return true;
+            }
+        }
+    }
+
+    fn into_data(self) -> Vec<f64> {
+        let samples = self.chunks.iter().map(Vec::len).sum();
+        let mut data = Vec::with_capacity(samples);
+        self.chunks.into_iter().for_each(|chunk| data.extend(chunk));
+        data
+    }
+
+    pub fn into_takes(self) -> Vec<Clip> {
+        if let Some(kept) = self.kept_range() {
+            return self.into_punch(kept).into_iter().collect();
+        }
+        let Some(cycle) = self.cycle.clone() else {
+            return vec![self.into_clip()];
+        };
+
+        let (channels, sample_rate) = (self.channels, self.sample_rate);
+        let mut takes = Vec::new();
+        let mut start = self.start_time_in_samples;
+        let data = self.into_data();
+        let mut remaining = data.as_slice();
+
+        while !remaining.is_empty() {
+            let frames = (cycle.end - start) as usize;
+            let (pass, rest) =
+                remaining.split_at((frames * channels.max(1) as usize).min(remaining.len()));
+            takes.push(Clip::from_samples(
+                pass.to_vec(),
+                channels,
+                sample_rate,
+                start,
+            ));
+            remaining = rest;
+            start = cycle.start;
+        }
+
+        takes
+    }
+
+    fn into_punch(self, punch: Range<u64>) -> Option<Clip> {
+        let channels = self.channels.max(1) as usize;
+        let start = punch.start.max(self.start_time_in_samples);
+        let end = punch
+            .end
+            .min(self.start_time_in_samples + self.duration_in_samples());
+        if end <= start {
+            return None;
+        }
+
+        let offset = (start - self.start_time_in_samples) as usize * channels;
+        let samples = (end - start) as usize * channels;
+        let (channels, sample_rate) = (self.channels, self.sample_rate);
+        let mut data = self.into_data();
+        data.truncate(offset + samples);
+        data.drain(..offset);
+        Some(Clip::from_samples(data, channels, sample_rate, start))
+    }
+
+    pub fn into_clip(self) -> Clip {
+        let (channels, sample_rate, start) =
+            (self.channels, self.sample_rate, self.start_time_in_samples);
+        Clip::from_samples(self.into_data(), channels, sample_rate, start)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cycle_recording_splits_passes() {
+        let mut recording = Recording::new(TrackId(1), 2, 44100, 150).with_cycle(100..200);
+        let input: Vec<f32> = (0..230).flat_map(|i| [i as f32, -(i as f32)]).collect();
+        recording.push(&input);
+
+        let takes = recording.into_takes();
+
+        assert_eq!(takes.len(), 3);
+        assert_eq!(takes[0].start_time_in_samples(), 150);
+        assert_eq!(takes[0].duration_in_samples(), 50);
+        assert_eq!(takes[1].start_time_in_samples(), 100);
+        assert_eq!(takes[1].duration_in_samples(), 100);
+        assert_eq!(takes[1].render()[..2], [50.0, -50.0]);
+        assert_eq!(takes[2].duration_in_samples(), 80);
+    }
+
+    #[test]
+    fn test_punch_keeps_only_range() {
+        let mut recording = Recording::new(TrackId(1), 1, 44100, 100).with_punch(155..175, 5);
+        let input: Vec<f32> = (0..200).map(|i| i as f32).collect();
+        recording.push(&input[..79]);
+        assert!(!recording.is_past_punch_out());
+        recording.push(&input[79..]);
+        assert!(recording.is_past_punch_out());
+
+        let takes = recording.into_takes();
+
+        assert_eq!(takes.len(), 1);
+        assert_eq!(takes[0].start_time_in_samples(), 150);
+        assert_eq!(takes[0].duration_in_samples(), 30);
+        assert_eq!(takes[0].render()[0], 50.0);
+
+        // Stopped before the punch in, nothing to keep
+        let mut recording = Recording::new(TrackId(1), 1, 44100, 100).with_punch(155..175, 5);
+        recording.push(&input[..40]);
+        assert!(recording.into_takes().is_empty());
+    }
+
+    #[test]
+    fn test_push_fills_chunks_without_growing_them() {
+        let mut recording = Recording::new(TrackId(1), 2, 100, 0);
+        assert_eq!(recording.chunk_samples(), 200);
+        let input: Vec<f32> = (0..2 * 250).map(|i| i as f32).collect();
+
+        assert!(recording.push(&input));
+        assert_eq!(recording.duration_in_samples(), 250);
+        assert!(recording.chunks.iter().all(|chunk| chunk.capacity() == 200));
+        assert_eq!(recording.spare_chunks_needed(), 2);
+
+        // Without the spares topped up, what doesn't fit is dropped
+        assert!(recording.push(&input));
+        assert!(!recording.push(&input));
+        assert_eq!(recording.duration_in_samples(), 500);
+
+        recording.add_chunks(vec![Recording::allocate_chunk(200)]);
+        assert!(recording.push(&input[..2]));
+        assert_eq!(recording.duration_in_samples(), 501);
+
+        let clip = recording.into_clip();
+        assert_eq!(clip.duration_in_samples(), 501);
+        assert_eq!(
+            clip.render()[..2 * 250],
+            input.iter().map(|s| *s as f64).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_cycle_from_before_range() {
+        let mut recording = Recording::new(TrackId(1), 1, 44100, 50).with_cycle(100..200);
+        let input: Vec<f32> = (0..230).map(|i| i as f32).collect();
+        recording.push(&input);
+
+        let takes = recording.into_takes();
+
+        assert_eq!(takes.len(), 2);
+        assert_eq!(takes[0].start_time_in_samples(), 50);
+        assert_eq!(takes[0].duration_in_samples(), 150);
+        assert_eq!(takes[1].start_time_in_samples(), 100);
+        assert_eq!(takes[1].duration_in_samples(), 80);
+        assert_eq!(takes[1].render()[0], 150.0);
+    }
+
+    #[test]
+    fn test_cycle_ignored_outside_range() {
+        let recording = Recording::new(TrackId(1), 1, 44100, 250).with_cycle(100..200);
+        assert!(!recording.is_cycle());
+    }
+}
@@ -1,17 +1,36 @@
 use rubato::{
-    Resampler as RubatoResampler, SincFixedIn, SincInterpolationParameters, SincInterpolationType,
-    WindowFunction,
+    FastFixedIn, FastFixedOut, PolynomialDegree, Resampler as RubatoResampler, SincFixedIn,
+    SincFixedOut, SincInterpolationParameters, SincInterpolationType, VecResampler, WindowFunction,
 };
 
 use crate::engine::{AudioError, utils::Utils};
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ResamplerQuality {
+    Fast,
+    #[default]
+    Balanced,
+    High,
+}
+
 pub struct Resampler;
 
 impl Resampler {
-    const PARAMETERS: SincInterpolationParameters = SincInterpolationParameters {
+    const CHUNK_SIZE: usize = 1024;
+    const STREAMING_CHUNK_SIZE: usize = 256;
+
+    const BALANCED_PARAMETERS: SincInterpolationParameters = SincInterpolationParameters {
+        sinc_len: 128,
+        f_cutoff: 0.925,
+        interpolation: SincInterpolationType::Linear,
+        oversampling_factor: 128,
+        window: WindowFunction::Blackman2,
+    };
+
+    const HIGH_PARAMETERS: SincInterpolationParameters = SincInterpolationParameters {
         sinc_len: 256,
         f_cutoff: 0.95,
-        interpolation: SincInterpolationType::Linear,
+        interpolation: SincInterpolationType::Cubic,
         oversampling_factor: 256,
         window: WindowFunction::BlackmanHarris2,
     };
@@ -21,27 +40,393 @@ impl Resampler {
         new_sample_rate: u32,
         old_sample_rate: u32,
         num_channels: u16,
+        quality: ResamplerQuality,
     ) -> Result<Vec<f64>, AudioError> {
         if num_channels == 0 || samples.is_empty() {
             return Ok(Vec::new());
         }
 
-        let num_channels = num_channels as usize;
+        if new_sample_rate == 0 {
+            return Err(AudioError::InvalidSampleRate(new_sample_rate));
+        }
 
-        let deinterleaved_samples: Vec<Vec<f64>> =
-            Utils::deinterleave_samples(&samples, num_channels);
+        if old_sample_rate == 0 {
+            return Err(AudioError::InvalidSampleRate(old_sample_rate));
+        }
+
+        let ratio = new_sample_rate as f64 / old_sample_rate as f64;
+        let input_frames = samples.len() / num_channels as usize;
+        let output_frames = Self::output_frames(input_frames, new_sample_rate, old_sample_rate);
+
+        Self::resample_frames(samples, ratio, num_channels, quality, output_frames)
+    }
+
+    // Same as `resample`, for ratios that don't come from a pair of sample rates
+    pub fn resample_by_ratio(
+        samples: Vec<f64>,
+        ratio: f64,
+        num_channels: u16,
+        quality: ResamplerQuality,
+    ) -> Result<Vec<f64>, AudioError> {
+        if num_channels == 0 || samples.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let input_frames = samples.len() / num_channels as usize;
+        let output_frames = (input_frames as f64 * ratio).round() as usize;
+
+        Self::resample_frames(samples, ratio, num_channels, quality, output_frames)
+    }
+
+    // A resampler for continuous streams whose ratio can be changed on the fly, within
+    // `max_relative` of the starting ratio. Produces a fixed number of frames per call.
+    pub fn streaming(
+        ratio: f64,
+        max_relative: f64,
+        num_channels: u16,
+        quality: ResamplerQuality,
+    ) -> Result<Box<dyn VecResampler<f64>>, AudioError> {
+        let channels = num_channels as usize;
+        let chunk_size = Self::STREAMING_CHUNK_SIZE;
 
-        let mut resampler = SincFixedIn::<f64>::new(
-            new_sample_rate as f64 / old_sample_rate as f64,
-            2.0,
-            Resampler::PARAMETERS,
-            samples.len() / num_channels,
-            num_channels,
-        )?;
+        Ok(match quality {
+            ResamplerQuality::Fast => Box::new(FastFixedOut::<f64>::new(
+                ratio,
+                max_relative,
+                PolynomialDegree::Cubic,
+                chunk_size,
+                channels,
+            )?),
+            ResamplerQuality::Balanced => Box::new(SincFixedOut::<f64>::new(
+                ratio,
+                max_relative,
+                Self::BALANCED_PARAMETERS,
+                chunk_size,
+                channels,
+            )?),
+            ResamplerQuality::High => Box::new(SincFixedOut::<f64>::new(
+                ratio,
+                max_relative,
+                Self::HIGH_PARAMETERS,
+                chunk_size,
+                channels,
+            )?),
+        })
+    }
 
-        let samples: Vec<Vec<f64>> = resampler.process(&deinterleaved_samples, None)?;
+    fn resample_frames(
+        samples: Vec<f64>,
+        ratio: f64,
+        num_channels: u16,
+        quality: ResamplerQuality,
+        output_frames: usize,
+    ) -> Result<Vec<f64>, AudioError> {
+        let deinterleaved_samples: Vec<Vec<f64>> =
+            Utils::deinterleave_samples(&samples, num_channels as usize);
+
+        let samples = match quality {
+            ResamplerQuality::Fast => Self::process_chunked(
+                |channels| {
+                    FastFixedIn::<f64>::new(
+                        ratio,
+                        1.0,
+                        PolynomialDegree::Linear,
+                        Self::CHUNK_SIZE,
+                        channels,
+                    )
+                },
+                &deinterleaved_samples,
+                ratio,
+                output_frames,
+            )?,
+            ResamplerQuality::Balanced => Self::process_chunked(
+                |channels| {
+                    SincFixedIn::<f64>::new(
+                        ratio,
+                        1.0,
+                        Self::BALANCED_PARAMETERS,
+                        Self::CHUNK_SIZE,
+                        channels,
+                    )
+                },
+                &deinterleaved_samples,
+                ratio,
+                output_frames,
+            )?,
+            ResamplerQuality::High => Self::process_chunked(
+                |channels| {
+                    SincFixedIn::<f64>::new(
+                        ratio,
+                        1.0,
+                        Self::HIGH_PARAMETERS,
+                        Self::CHUNK_SIZE,
+                        channels,
+                    )
+                },
+                &deinterleaved_samples,
+                ratio,
+                output_frames,
+            )?,
+        };
 
         let interleaved_output = Utils::interleave_samples(&samples);
         Ok(interleaved_output)
     }
+
+    pub fn output_frames(input_frames: usize, new_sample_rate: u32, old_sample_rate: u32) -> usize {
+        let old_sample_rate = old_sample_rate as u128;
+        let scaled = input_frames as u128 * new_sample_rate as u128;
+        ((scaled + old_sample_rate / 2) / old_sample_rate) as usize
+    }
+
+    fn process_chunked<R, F>(
+        make_resampler: F,
+        input: &[Vec<f64>],
+        ratio: f64,
+        output_frames: usize,
+    ) -> Result<Vec<Vec<f64>>, AudioError>
+    where
+        R: RubatoResampler<f64>,
+        F: Fn(usize) -> Result<R, rubato::ResamplerConstructionError>,
+    {
+        let delay = Self::measure_delay(make_resampler(1)?, ratio)?;
+
+        let mut output = Self::run(make_resampler(input.len())?, input, delay + output_frames)?;
+
+        for channel in output.iter_mut() {
+            channel.drain(..delay);
+        }
+
+        Ok(output)
+    }
+
+    // The delay reported by rubato does not line up with where the samples actually land,
+    // so push an impulse through an identical resampler and see where it comes out.
+    fn measure_delay<R>(resampler: R, ratio: f64) -> Result<usize, AudioError>
+    where
+        R: RubatoResampler<f64>,
+    {
+        let impulse_position = Self::CHUNK_SIZE / 4;
+        let mut impulse = vec![0.0; Self::CHUNK_SIZE];
+        impulse[impulse_position] = 1.0;
+
+        let expected_position = impulse_position as f64 * ratio;
+        let search_frames = 2 * resampler.output_delay() + expected_position.ceil() as usize + 1;
+
+        let output = Self::run(resampler, &[impulse], search_frames)?;
+
+        Ok(Self::impulse_delay(&output[0], expected_position))
+    }
+
+    // The same measurement for a streaming resampler with these settings, in output frames.
+    // A new one lines its first output up with the input, so it is run on silence first to
+    // measure the delay it settles at.
+    pub fn streaming_delay(
+        ratio: f64,
+        max_relative: f64,
+        quality: ResamplerQuality,
+    ) -> Result<usize, AudioError> {
+        let mut resampler = Self::streaming(ratio, max_relative, 1, quality)?;
+        let mut chunk_output = resampler.output_buffer_allocate(true);
+
+        let flush = 2 * resampler.output_delay() + 1;
+        let mut flushed = 0;
+        while flushed < flush {
+            let silence = vec![0.0; resampler.input_frames_next()];
+            let (_, produced) =
+                resampler.process_into_buffer(&[silence], &mut chunk_output, None)?;
+            flushed += produced;
+        }
+
+        let impulse_position = Self::STREAMING_CHUNK_SIZE / 4;
+        let expected_position = impulse_position as f64 * ratio;
+        let search_frames = 2 * resampler.output_delay() + expected_position.ceil() as usize + 1;
+
+        let mut output = Vec::with_capacity(search_frames + resampler.output_frames_max());
+        let mut position = 0;
+        while output.len() < search_frames {
+            let frames = resampler.input_frames_next();
+            let chunk = (position..position + frames)
+                .map(|frame| if frame == impulse_position { 1.0 } else { 0.0 })
+                .collect();
+            let (_, produced) = resampler.process_into_buffer(&[chunk], &mut chunk_output, None)?;
+            output.extend_from_slice(&chunk_output[0][..produced]);
+            position += frames;
+        }
+
+        Ok(Self::impulse_delay(&output, expected_position))
+    }
+
+    fn impulse_delay(output: &[f64], expected_position: f64) -> usize {
+        let peak_position = output
+            .iter()
+            .enumerate()
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map(|(position, _)| position)
+            .unwrap_or_default();
+
+        (peak_position as f64 - expected_position).round().max(0.0) as usize
+    }
+
+    // Feeds the input through in fixed-size chunks, padding with silence once it runs out,
+    // until the requested number of frames has been produced.
+    fn run<R>(
+        mut resampler: R,
+        input: &[Vec<f64>],
+        output_frames: usize,
+    ) -> Result<Vec<Vec<f64>>, AudioError>
+    where
+        R: RubatoResampler<f64>,
+    {
+        let input_frames = input.first().map(|ch| ch.len()).unwrap_or_default();
+
+        let mut output: Vec<Vec<f64>> = vec![Vec::with_capacity(output_frames); input.len()];
+        let mut chunk_output = resampler.output_buffer_allocate(true);
+        let mut position = 0;
+
+        while output[0].len() < output_frames {
+            let remaining = input_frames - position;
+            let chunk: Vec<&[f64]> = input.iter().map(|ch| &ch[position..]).collect();
+
+            let (consumed, produced) = if remaining >= resampler.input_frames_next() {
+                resampler.process_into_buffer(&chunk, &mut chunk_output, None)?
+            } else if remaining > 0 {
+                resampler.process_partial_into_buffer(Some(&chunk), &mut chunk_output, None)?
+            } else {
+                resampler.process_partial_into_buffer::<&[f64], _>(None, &mut chunk_output, None)?
+            };
+
+            position += consumed.min(remaining);
+
+            for (channel, chunk) in output.iter_mut().zip(&chunk_output) {
+                channel.extend_from_slice(&chunk[..produced]);
+            }
+        }
+
+        for channel in output.iter_mut() {
+            channel.truncate(output_frames);
+        }
+
+        Ok(output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const QUALITIES: [ResamplerQuality; 3] = [
+        ResamplerQuality::Fast,
+        ResamplerQuality::Balanced,
+        ResamplerQuality::High,
+    ];
+
+    fn sine(frequency: f64, sample_rate: u32, frames: usize) -> Vec<f64> {
+        (0..frames)
+            .map(|i| (2.0 * std::f64::consts::PI * frequency * i as f64 / sample_rate as f64).sin())
+            .collect()
+    }
+
+    #[test]
+    fn test_output_frames() {
+        assert_eq!(Resampler::output_frames(44100, 48000, 44100), 48000);
+        assert_eq!(Resampler::output_frames(48000, 44100, 48000), 44100);
+        assert_eq!(Resampler::output_frames(1000, 48000, 44100), 1088);
+        assert_eq!(Resampler::output_frames(0, 48000, 44100), 0);
+    }
+
+    #[test]
+    fn test_resample_exact_length() -> Result<(), AudioError> {
+        let rates = [
+            (44100, 48000),
+            (48000, 44100),
+            (8000, 44100),
+            (96000, 44100),
+        ];
+
+        for quality in QUALITIES {
+            for (old_rate, new_rate) in rates {
+                for frames in [1, 100, 1024, 5000] {
+                    let samples = vec![0.25; frames * 2];
+                    let output = Resampler::resample(samples, new_rate, old_rate, 2, quality)?;
+                    let expected = Resampler::output_frames(frames, new_rate, old_rate);
+
+                    assert_eq!(output.len(), expected * 2);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_resample_has_no_delay() -> Result<(), AudioError> {
+        let rates = [(8000, 16000), (44100, 48000), (48000, 44100)];
+
+        for quality in QUALITIES {
+            for (old_rate, new_rate) in rates {
+                let mut samples = vec![0.0; 4000];
+                samples[1000] = 1.0;
+
+                let output = Resampler::resample(samples, new_rate, old_rate, 1, quality)?;
+                let peak_position = output
+                    .iter()
+                    .enumerate()
+                    .max_by(|(_, a), (_, b)| a.total_cmp(b))
+                    .map(|(position, _)| position as f64)
+                    .unwrap_or_default();
+                let expected_position = 1000.0 * new_rate as f64 / old_rate as f64;
+
+                assert!(
+                    (peak_position - expected_position).abs() <= 1.0,
+                    "{quality:?} {old_rate} -> {new_rate}: {peak_position}"
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_resample_preserves_sine() -> Result<(), AudioError> {
+        let (old_rate, new_rate) = (44100, 48000);
+        let samples = sine(440.0, old_rate, 8000);
+        let expected = sine(
+            440.0,
+            new_rate,
+            Resampler::output_frames(8000, new_rate, old_rate),
+        );
+
+        for quality in QUALITIES {
+            let output = Resampler::resample(samples.clone(), new_rate, old_rate, 1, quality)?;
+
+            // Alignment is to the nearest frame, so allow up to half a frame of phase
+            let max_error = output[500..output.len() - 500]
+                .iter()
+                .zip(&expected[500..expected.len() - 500])
+                .map(|(a, b)| (a - b).abs())
+                .fold(0.0, f64::max);
+
+            assert!(max_error < 3e-2, "{quality:?}: {max_error}");
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_resample_empty() -> Result<(), AudioError> {
+        for quality in QUALITIES {
+            assert!(Resampler::resample(Vec::new(), 48000, 44100, 2, quality)?.is_empty());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_resample_invalid_rate() {
+        assert!(matches!(
+            Resampler::resample(vec![0.0; 4], 48000, 0, 2, ResamplerQuality::High),
+            Err(AudioError::InvalidSampleRate(0))
+        ));
+    }
 }
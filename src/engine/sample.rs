@@ -0,0 +1,287 @@
+use cpal::I24;
+
+use crate::engine::{Scale, utils::Utils};
+
+// Engine samples are f64 in -1.0..=1.0. Integer formats clip outside that range, floats pass
+// through so headroom survives until the final stage.
+pub trait FromF64Sample {
+    // One quantization step as an f64 sample, zero for float formats
+    const STEP: f64;
+
+    fn from_f64_sample(sample: f64) -> Self;
+}
+
+pub trait ToF64Sample: Copy {
+    fn to_f64_sample(self) -> f64;
+}
+
+// Unsigned formats are centred on half their range
+const U16_OFFSET: f64 = 32768.0;
+const U32_OFFSET: f64 = 2_147_483_648.0;
+const U64_OFFSET: f64 = 9_223_372_036_854_775_808.0;
+const I24_MAX: f64 = 8_388_607.0;
+
+impl FromF64Sample for u8 {
+    const STEP: f64 = 1.0 / 128.0;
+
+    fn from_f64_sample(sample: f64) -> Self {
+        (sample * 128.0 + 128.0).round().clamp(0.0, u8::MAX as f64) as u8
+    }
+}
+
+impl FromF64Sample for u16 {
+    const STEP: f64 = 1.0 / U16_OFFSET;
+
+    fn from_f64_sample(sample: f64) -> Self {
+        (sample * U16_OFFSET + U16_OFFSET)
+            .round()
+            .clamp(0.0, u16::MAX as f64) as u16
+    }
+}
+
+impl FromF64Sample for u32 {
+    const STEP: f64 = 1.0 / U32_OFFSET;
+
+    fn from_f64_sample(sample: f64) -> Self {
+        (sample * U32_OFFSET + U32_OFFSET)
+            .round()
+            .clamp(0.0, u32::MAX as f64) as u32
+    }
+}
+
+impl FromF64Sample for u64 {
+    const STEP: f64 = 1.0 / U64_OFFSET;
+
+    fn from_f64_sample(sample: f64) -> Self {
+        (sample * U64_OFFSET + U64_OFFSET)
+            .round()
+            .clamp(0.0, u64::MAX as f64) as u64
+    }
+}
+
+impl FromF64Sample for i8 {
+    const STEP: f64 = 1.0 / i8::MAX as f64;
+
+    fn from_f64_sample(sample: f64) -> Self {
+        (sample.clamp(-1.0, 1.0) * i8::MAX as f64).round() as i8
+    }
+}
+
+impl FromF64Sample for i16 {
+    const STEP: f64 = 1.0 / i16::MAX as f64;
+
+    fn from_f64_sample(sample: f64) -> Self {
+        (sample.clamp(-1.0, 1.0) * i16::MAX as f64).round() as i16
+    }
+}
+
+impl FromF64Sample for I24 {
+    const STEP: f64 = 1.0 / I24_MAX;
+
+    fn from_f64_sample(sample: f64) -> Self {
+        I24::new_unchecked((sample.clamp(-1.0, 1.0) * I24_MAX).round() as i32)
+    }
+}
+
+impl FromF64Sample for i32 {
+    const STEP: f64 = 1.0 / i32::MAX as f64;
+
+    fn from_f64_sample(sample: f64) -> Self {
+        (sample.clamp(-1.0, 1.0) * i32::MAX as f64).round() as i32
+    }
+}
+
+impl FromF64Sample for i64 {
+    const STEP: f64 = 1.0 / i64::MAX as f64;
+
+    fn from_f64_sample(sample: f64) -> Self {
+        (sample.clamp(-1.0, 1.0) * i64::MAX as f64).round() as i64
+    }
+}
+
+impl FromF64Sample for f32 {
+    const STEP: f64 = 0.0;
+
+    fn from_f64_sample(sample: f64) -> Self {
+        sample as f32
+    }
+}
+
+impl FromF64Sample for f64 {
+    const STEP: f64 = 0.0;
+
+    fn from_f64_sample(sample: f64) -> Self {
+        sample
+    }
+}
+
+impl ToF64Sample for u8 {
+    fn to_f64_sample(self) -> f64 {
+        Utils::convert_sample_to_f64((self as i16 - 128) as i8, Scale::U8)
+    }
+}
+
+impl ToF64Sample for u16 {
+    fn to_f64_sample(self) -> f64 {
+        (self as f64 - U16_OFFSET) / U16_OFFSET
+    }
+}
+
+impl ToF64Sample for u32 {
+    fn to_f64_sample(self) -> f64 {
+        (self as f64 - U32_OFFSET) / U32_OFFSET
+    }
+}
+
+impl ToF64Sample for u64 {
+    fn to_f64_sample(self) -> f64 {
+        (self as f64 - U64_OFFSET) / U64_OFFSET
+    }
+}
+
+impl ToF64Sample for i8 {
+    fn to_f64_sample(self) -> f64 {
+        Utils::convert_sample_to_f64(self, Scale::I8)
+    }
+}
+
+impl ToF64Sample for i16 {
+    fn to_f64_sample(self) -> f64 {
+        Utils::convert_sample_to_f64(self, Scale::I16)
+    }
+}
+
+impl ToF64Sample for I24 {
+    fn to_f64_sample(self) -> f64 {
+        Utils::convert_sample_to_f64(self.inner(), Scale::I24)
+    }
+}
+
+impl ToF64Sample for i32 {
+    fn to_f64_sample(self) -> f64 {
+        Utils::convert_sample_to_f64(self, Scale::I32)
+    }
+}
+
+impl ToF64Sample for i64 {
+    fn to_f64_sample(self) -> f64 {
+        (self as f64 / i64::MAX as f64).clamp(-1.0, 1.0)
+    }
+}
+
+impl ToF64Sample for f32 {
+    fn to_f64_sample(self) -> f64 {
+        self as f64
+    }
+}
+
+impl ToF64Sample for f64 {
+    fn to_f64_sample(self) -> f64 {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fmt::Debug;
+
+    use super::*;
+
+    // Evenly spread over -1.0..=1.0 plus a few values right at the edges
+    fn sweep() -> impl Iterator<Item = f64> {
+        (0..=20_000)
+            .map(|step| step as f64 / 10_000.0 - 1.0)
+            .chain([-1.0, -0.999_999_9, 1e-9, -1e-9, 0.999_999_9, 1.0])
+    }
+
+    // Converting to the format and back lands within half a step of the original, or of full
+    // scale for the top step unsigned formats can't reach
+    fn check_round_trip<T>(step: f64)
+    where
+        T: FromF64Sample + ToF64Sample + Debug,
+    {
+        assert!(T::STEP <= step);
+        let lowest = T::from_f64_sample(-1.0).to_f64_sample();
+        let highest = T::from_f64_sample(1.0).to_f64_sample();
+
+        for sample in sweep() {
+            let converted = T::from_f64_sample(sample);
+            let error = (converted.to_f64_sample() - sample.clamp(lowest, highest)).abs();
+            assert!(
+                error <= step / 2.0 + 1e-12,
+                "{sample} became {converted:?}, off by {error}"
+            );
+        }
+    }
+
+    // Anything past full scale sticks at full scale
+    fn check_clipping<T>()
+    where
+        T: FromF64Sample + ToF64Sample + PartialEq + Debug,
+    {
+        for over in [1.000_001, 1.5, 2.0, 100.0, f64::MAX] {
+            assert_eq!(T::from_f64_sample(over), T::from_f64_sample(1.0));
+            assert_eq!(T::from_f64_sample(-over), T::from_f64_sample(-1.0));
+        }
+        assert!(T::from_f64_sample(1.0).to_f64_sample() <= 1.0);
+        assert!(T::from_f64_sample(-1.0).to_f64_sample() >= -1.0);
+    }
+
+    #[test]
+    fn test_round_trip_every_format() {
+        check_round_trip::<u8>(1.0 / 128.0);
+        check_round_trip::<u16>(1.0 / U16_OFFSET);
+        check_round_trip::<u32>(1.0 / U32_OFFSET);
+        check_round_trip::<u64>(1.0 / U64_OFFSET);
+        check_round_trip::<i8>(1.0 / i8::MAX as f64);
+        check_round_trip::<i16>(1.0 / i16::MAX as f64);
+        check_round_trip::<I24>(1.0 / I24_MAX);
+        check_round_trip::<i32>(1.0 / i32::MAX as f64);
+        check_round_trip::<i64>(1.0 / i64::MAX as f64);
+        check_round_trip::<f32>(f32::EPSILON as f64);
+        check_round_trip::<f64>(0.0);
+    }
+
+    #[test]
+    fn test_integer_formats_clip() {
+        check_clipping::<u8>();
+        check_clipping::<u16>();
+        check_clipping::<u32>();
+        check_clipping::<u64>();
+        check_clipping::<i8>();
+        check_clipping::<i16>();
+        check_clipping::<I24>();
+        check_clipping::<i32>();
+        check_clipping::<i64>();
+
+        assert_eq!(u16::from_f64_sample(-2.0), 0);
+        assert_eq!(u16::from_f64_sample(2.0), u16::MAX);
+        assert_eq!(I24::from_f64_sample(2.0).inner(), 8_388_607);
+        assert_eq!(I24::from_f64_sample(-2.0).inner(), -8_388_607);
+    }
+
+    #[test]
+    fn test_float_formats_keep_headroom() {
+        assert_eq!(f32::from_f64_sample(1.5), 1.5);
+        assert_eq!(1.5f32.to_f64_sample(), 1.5);
+        assert_eq!(f64::from_f64_sample(-2.0).to_f64_sample(), -2.0);
+    }
+
+    #[test]
+    fn test_every_small_integer_survives() {
+        for value in u8::MIN..=u8::MAX {
+            assert_eq!(u8::from_f64_sample(value.to_f64_sample()), value);
+        }
+        for value in u16::MIN..=u16::MAX {
+            assert_eq!(u16::from_f64_sample(value.to_f64_sample()), value);
+        }
+        // The most negative value has no positive twin and reads as -1.0
+        for value in i8::MIN + 1..=i8::MAX {
+            assert_eq!(i8::from_f64_sample(value.to_f64_sample()), value);
+        }
+        for value in i16::MIN + 1..=i16::MAX {
+            assert_eq!(i16::from_f64_sample(value.to_f64_sample()), value);
+        }
+        assert_eq!(i16::MIN.to_f64_sample(), -1.0);
+    }
+}
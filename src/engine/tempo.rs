@@ -0,0 +1,491 @@
+use std::fmt;
+
+use crate::engine::AudioError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeSignature {
+    numerator: u32,
+    denominator: u32,
+}
+
+impl TimeSignature {
+    pub fn new(numerator: u32, denominator: u32) -> Result<Self, AudioError> {
+        if numerator == 0 || !denominator.is_power_of_two() || denominator > 64 {
+            return Err(AudioError::InvalidTimeSignature {
+                numerator,
+                denominator,
+            });
+        }
+
+        Ok(TimeSignature {
+            numerator,
+            denominator,
+        })
+    }
+
+    pub fn numerator(&self) -> u32 {
+        self.numerator
+    }
+
+    pub fn denominator(&self) -> u32 {
+        self.denominator
+    }
+
+    pub fn beat_ticks(&self) -> u64 {
+        TempoMap::TICKS_PER_QUARTER * 4 / self.denominator as u64
+    }
+
+    pub fn bar_ticks(&self) -> u64 {
+        self.beat_ticks() * self.numerator as u64
+    }
+}
+
+impl Default for TimeSignature {
+    fn default() -> Self {
+        TimeSignature {
+            numerator: 4,
+            denominator: 4,
+        }
+    }
+}
+
+// Bars and beats count from 1, ticks from 0
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct MusicalPosition {
+    pub bar: u32,
+    pub beat: u32,
+    pub tick: u32,
+}
+
+impl MusicalPosition {
+    pub fn new(bar: u32, beat: u32, tick: u32) -> Self {
+        MusicalPosition { bar, beat, tick }
+    }
+}
+
+impl fmt::Display for MusicalPosition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.bar, self.beat, self.tick)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Grid {
+    Bar,
+    Beat,
+    Ticks(u32),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct TempoChange {
+    tick: u64,
+    bpm: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct SignatureChange {
+    bar: u32,
+    tick: u64,
+    signature: TimeSignature,
+}
+
+// Tempo is in quarter notes per minute. Tempo changes are anchored to musical time, so
+// moving one shifts the sample position of everything after it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TempoMap {
+    sample_rate: u32,
+    tempos: Vec<TempoChange>,
+    signatures: Vec<SignatureChange>,
+}
+
+impl TempoMap {
+    pub const TICKS_PER_QUARTER: u64 = 960;
+    pub const DEFAULT_BPM: f64 = 120.0;
+    const MIN_BPM: f64 = 1.0;
+    const MAX_BPM: f64 = 1000.0;
+
+    pub fn new(sample_rate: u32) -> Self {
+        TempoMap {
+            sample_rate,
+            tempos: vec![TempoChange {
+                tick: 0,
+                bpm: Self::DEFAULT_BPM,
+            }],
+            signatures: vec![SignatureChange {
+                bar: 1,
+                tick: 0,
+                signature: TimeSignature::default(),
+            }],
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate;
+    }
+
+    pub fn set_tempo(&mut self, position: MusicalPosition, bpm: f64) -> Result<(), AudioError> {
+        if !(Self::MIN_BPM..=Self::MAX_BPM).contains(&bpm) {
+            return Err(AudioError::InvalidTempo(bpm));
+        }
+
+        let tick = self.position_to_ticks(position);
+        match self
+            .tempos
+            .binary_search_by_key(&tick, |change| change.tick)
+        {
+            Ok(index) => self.tempos[index].bpm = bpm,
+            Err(index) => self.tempos.insert(index, TempoChange { tick, bpm }),
+        }
+
+        Ok(())
+    }
+
+    pub fn remove_tempo(&mut self, position: MusicalPosition) {
+        let tick = self.position_to_ticks(position);
+        if tick > 0 {
+            self.tempos.retain(|change| change.tick != tick);
+        }
+    }
+
+    pub fn set_time_signature(
+        &mut self,
+        bar: u32,
+        signature: TimeSignature,
+    ) -> Result<(), AudioError> {
+        if bar == 0 {
+            return Err(AudioError::InvalidMusicalPosition(MusicalPosition::new(
+                bar, 1, 0,
+            )));
+        }
+
+        match self
+            .signatures
+            .binary_search_by_key(&bar, |change| change.bar)
+        {
+            Ok(index) => self.signatures[index].signature = signature,
+            Err(index) => self.signatures.insert(
+                index,
+                SignatureChange {
+                    bar,
+                    tick: 0,
+                    signature,
+                },
+            ),
+        }
+        self.update_signature_ticks();
+
+        Ok(())
+    }
+
+    pub fn remove_time_signature(&mut self, bar: u32) {
+        if bar > 1 {
+            self.signatures.retain(|change| change.bar != bar);
+            self.update_signature_ticks();
+        }
+    }
+
+    pub fn tempo_at(&self, samples: u64) -> f64 {
+        self.tempo_change_at(self.samples_to_ticks(samples)).bpm
+    }
+
+    pub fn time_signature_at(&self, samples: u64) -> TimeSignature {
+        self.signature_change_at(self.samples_to_ticks(samples).floor() as u64)
+            .signature
+    }
+
+    pub fn ticks_to_seconds(&self, ticks: f64) -> f64 {
+        let mut seconds = 0.0;
+
+        for (index, change) in self.tempos.iter().enumerate() {
+            let end = self
+                .tempos
+                .get(index + 1)
+                .map_or(f64::INFINITY, |next| next.tick as f64)
+                .min(ticks);
+
+            if end <= change.tick as f64 {
+                break;
+            }
+            seconds += Self::ticks_duration(end - change.tick as f64, change.bpm);
+        }
+
+        seconds
+    }
+
+    pub fn seconds_to_ticks(&self, seconds: f64) -> f64 {
+        let mut elapsed = 0.0;
+
+        for (index, change) in self.tempos.iter().enumerate() {
+            let remaining = seconds - elapsed;
+            let Some(next) = self.tempos.get(index + 1) else {
+                return change.tick as f64 + Self::seconds_ticks(remaining, change.bpm);
+            };
+
+            let duration = Self::ticks_duration((next.tick - change.tick) as f64, change.bpm);
+            if remaining < duration {
+                return change.tick as f64 + Self::seconds_ticks(remaining, change.bpm);
+            }
+            elapsed += duration;
+        }
+
+        0.0
+    }
+
+    pub fn samples_to_ticks(&self, samples: u64) -> f64 {
+        self.seconds_to_ticks(samples as f64 / self.sample_rate as f64)
+    }
+
+    pub fn ticks_to_samples(&self, ticks: f64) -> u64 {
+        (self.ticks_to_seconds(ticks) * self.sample_rate as f64).round() as u64
+    }
+
+    pub fn ticks_to_position(&self, ticks: u64) -> MusicalPosition {
+        let change = self.signature_change_at(ticks);
+        let signature = change.signature;
+        let relative = ticks - change.tick;
+        let within_bar = relative % signature.bar_ticks();
+
+        MusicalPosition {
+            bar: change.bar + (relative / signature.bar_ticks()) as u32,
+            beat: (within_bar / signature.beat_ticks()) as u32 + 1,
+            tick: (within_bar % signature.beat_ticks()) as u32,
+        }
+    }
+
+    pub fn position_to_ticks(&self, position: MusicalPosition) -> u64 {
+        let bar = position.bar.max(1);
+        let change = self
+            .signatures
+            .iter()
+            .rev()
+            .find(|change| change.bar <= bar)
+            .unwrap_or(&self.signatures[0]);
+        let signature = change.signature;
+
+        change.tick
+            + (bar - change.bar) as u64 * signature.bar_ticks()
+            + position.beat.saturating_sub(1) as u64 * signature.beat_ticks()
+            + position.tick as u64
+    }
+
+    pub fn samples_to_position(&self, samples: u64) -> MusicalPosition {
+        self.ticks_to_position(self.samples_to_ticks(samples).round() as u64)
+    }
+
+    pub fn position_to_samples(&self, position: MusicalPosition) -> u64 {
+        self.ticks_to_samples(self.position_to_ticks(position) as f64)
+    }
+
+    pub fn seconds_to_position(&self, seconds: f64) -> MusicalPosition {
+        self.ticks_to_position(self.seconds_to_ticks(seconds).round() as u64)
+    }
+
+    pub fn position_to_seconds(&self, position: MusicalPosition) -> f64 {
+        self.ticks_to_seconds(self.position_to_ticks(position) as f64)
+    }
+
+    // Nearest grid line, counted from the start of the time signature in effect
+    pub fn snap(&self, samples: u64, grid: Grid) -> u64 {
+        let ticks = self.samples_to_ticks(samples);
+        let index = self
+            .signatures
+            .partition_point(|change| change.tick as f64 <= ticks)
+            .saturating_sub(1);
+        let change = self.signatures[index];
+
+        let unit = match grid {
+            Grid::Bar => change.signature.bar_ticks(),
+            Grid::Beat => change.signature.beat_ticks(),
+            Grid::Ticks(ticks) => ticks.max(1) as u64,
+        } as f64;
+
+        let relative = ticks - change.tick as f64;
+        let mut snapped = change.tick as f64 + (relative / unit).round() * unit;
+        if let Some(next) = self.signatures.get(index + 1) {
+            snapped = snapped.min(next.tick as f64);
+        }
+
+        self.ticks_to_samples(snapped)
+    }
+
+    // Every grid line from `start` up to (not including) `end`, in samples. Lazy, so the
+    // audio thread can walk it without allocating.
+    pub fn grid_lines(
+        &self,
+        start: u64,
+        end: u64,
+        grid: Grid,
+    ) -> impl Iterator<Item = (u64, MusicalPosition)> + '_ {
+        let end_ticks = self.samples_to_ticks(end);
+        let mut ticks = self.samples_to_ticks(self.snap(start, grid)).round() as u64;
+
+        std::iter::from_fn(move || {
+            while (ticks as f64) < end_ticks {
+                let line = ticks;
+                let change = self.signature_change_at(ticks);
+                let step = match grid {
+                    Grid::Bar => change.signature.bar_ticks(),
+                    Grid::Beat => change.signature.beat_ticks(),
+                    Grid::Ticks(ticks) => ticks.max(1) as u64,
+                };
+                let next = ticks + step;
+                ticks = self
+                    .signatures
+                    .iter()
+                    .find(|change| change.tick > ticks && change.tick < next)
+                    .map_or(next, |change| change.tick);
+
+                let samples = self.ticks_to_samples(line as f64);
+                if samples >= start {
+                    return Some((samples, self.ticks_to_position(line)));
+                }
+            }
+
+            None
+        })
+    }
+
+    fn ticks_duration(ticks: f64, bpm: f64) -> f64 {
+        ticks / Self::TICKS_PER_QUARTER as f64 * 60.0 / bpm
+    }
+
+    fn seconds_ticks(seconds: f64, bpm: f64) -> f64 {
+        seconds * bpm / 60.0 * Self::TICKS_PER_QUARTER as f64
+    }
+
+    fn tempo_change_at(&self, ticks: f64) -> TempoChange {
+        let index = self
+            .tempos
+            .partition_point(|change| change.tick as f64 <= ticks);
+        self.tempos[index.saturating_sub(1)]
+    }
+
+    fn signature_change_at(&self, ticks: u64) -> SignatureChange {
+        let index = self
+            .signatures
+            .partition_point(|change| change.tick <= ticks);
+        self.signatures[index.saturating_sub(1)]
+    }
+
+    fn update_signature_ticks(&mut self) {
+        for index in 1..self.signatures.len() {
+            let previous = self.signatures[index - 1];
+            let change = &mut self.signatures[index];
+            change.tick =
+                previous.tick + (change.bar - previous.bar) as u64 * previous.signature.bar_ticks();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 48000;
+
+    #[test]
+    fn test_default_tempo() {
+        let map = TempoMap::new(SAMPLE_RATE);
+
+        // 120 bpm in 4/4 is two seconds per bar
+        assert_eq!(
+            map.position_to_samples(MusicalPosition::new(2, 1, 0)),
+            96000
+        );
+        assert_eq!(
+            map.samples_to_position(24000),
+            MusicalPosition::new(1, 2, 0)
+        );
+        assert_eq!(
+            map.seconds_to_position(1.125),
+            MusicalPosition::new(1, 3, 240)
+        );
+        assert_eq!(map.position_to_seconds(MusicalPosition::new(3, 1, 0)), 4.0);
+    }
+
+    #[test]
+    fn test_tempo_change() -> Result<(), AudioError> {
+        let mut map = TempoMap::new(SAMPLE_RATE);
+        map.set_tempo(MusicalPosition::new(2, 1, 0), 60.0)?;
+
+        assert_eq!(map.position_to_seconds(MusicalPosition::new(2, 1, 0)), 2.0);
+        assert_eq!(map.position_to_seconds(MusicalPosition::new(3, 1, 0)), 6.0);
+        assert_eq!(map.seconds_to_position(5.0), MusicalPosition::new(2, 4, 0));
+        assert_eq!(map.tempo_at(0), 120.0);
+        assert_eq!(map.tempo_at(3 * SAMPLE_RATE as u64), 60.0);
+
+        map.remove_tempo(MusicalPosition::new(2, 1, 0));
+        assert_eq!(map.position_to_seconds(MusicalPosition::new(3, 1, 0)), 4.0);
+
+        assert!(matches!(
+            map.set_tempo(MusicalPosition::new(1, 1, 0), 0.0),
+            Err(AudioError::InvalidTempo(_))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_time_signature_change() -> Result<(), AudioError> {
+        let mut map = TempoMap::new(SAMPLE_RATE);
+        map.set_time_signature(3, TimeSignature::new(6, 8)?)?;
+
+        // Two bars of 4/4, then bars of six eighth notes
+        let bar_three = map.position_to_ticks(MusicalPosition::new(3, 1, 0));
+        assert_eq!(bar_three, 8 * 960);
+        assert_eq!(
+            map.position_to_ticks(MusicalPosition::new(4, 1, 0)),
+            bar_three + 6 * 480
+        );
+        assert_eq!(
+            map.ticks_to_position(bar_three + 5 * 480 + 10),
+            MusicalPosition::new(3, 6, 10)
+        );
+        assert_eq!(
+            map.time_signature_at(map.position_to_samples(MusicalPosition::new(3, 2, 0))),
+            TimeSignature::new(6, 8)?
+        );
+
+        assert!(TimeSignature::new(4, 3).is_err());
+        assert!(TimeSignature::new(0, 4).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_snap() -> Result<(), AudioError> {
+        let mut map = TempoMap::new(SAMPLE_RATE);
+
+        assert_eq!(map.snap(100_000, Grid::Bar), 96000);
+        assert_eq!(map.snap(150_000, Grid::Bar), 192_000);
+        assert_eq!(map.snap(30_000, Grid::Beat), 24000);
+        assert_eq!(map.snap(5_000, Grid::Ticks(240)), 6000);
+
+        // 3/4 from bar 2, so bar 3 starts a beat earlier than in 4/4
+        map.set_time_signature(2, TimeSignature::new(3, 4)?)?;
+        assert_eq!(map.snap(170_000, Grid::Bar), 168_000);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_grid_lines() -> Result<(), AudioError> {
+        let mut map = TempoMap::new(SAMPLE_RATE);
+        map.set_time_signature(2, TimeSignature::new(3, 4)?)?;
+
+        let beats: Vec<_> = map.grid_lines(90_000, 170_000, Grid::Beat).collect();
+        let positions: Vec<String> = beats
+            .iter()
+            .map(|(_, position)| position.to_string())
+            .collect();
+
+        assert_eq!(positions, ["2:1:0", "2:2:0", "2:3:0", "3:1:0"]);
+        assert_eq!(beats[0].0, 96000);
+
+        Ok(())
+    }
+}
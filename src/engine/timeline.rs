@@ -1,242 +1,2442 @@
-use std::{collections::HashSet, ops::AddAssign, path::Path};
-
-use crate::engine::{AudioError, FromF64Sample, Track, TrackId};
+use std::{
+    collections::HashSet,
+    ops::{AddAssign, Range},
+    path::Path,
+    sync::mpsc::{Receiver, Sender},
+};
+
+use crate::engine::{
+    Alignment, AudioError, ChannelMap, Clip, CountIn, Dither, DitherMode, EndAction, ExportOptions,
+    FromF64Sample, Grid, HarmonyAnalyzer, HarmonyReport, InputMonitor, Metronome, MonitorMode,
+    MusicalPosition, PitchCorrection, PitchPoint, Recording, Resampler, ResamplerQuality,
+    TakeAligner, TempoMap, ToF64Sample, Track, TrackId, Transport, TransportCommand,
+    TransportEvent, TransportState, Varispeed, WarpMarker,
+};
 
 pub struct Timeline {
     tracks: Vec<Track>,
     active_track_ids: HashSet<TrackId>,
     sample_rate: u32,
+    resampler_quality: ResamplerQuality,
     playhead_position: u64,
+    armed_track_id: Option<TrackId>,
+    recording: Option<Recording>,
+    playback_rate: f64,
+    varispeed: Option<Varispeed>,
+    output_channels: u16,
+    tempo_map: TempoMap,
+    metronome: Metronome,
+    count_in: Option<CountIn>,
+    loop_range: Option<Range<u64>>,
+    punch_range: Option<Range<u64>>,
+    // Positions at the rate they were set at, so rate changes convert from there rather than
+    // from the previous rate and rounding doesn't build up. The playhead's holds only while
+    // it stays where the last rate change put it.
+    playhead_placement: Option<(u32, u64, u64)>,
+    loop_placement: Option<(u32, Range<u64>)>,
+    punch_placement: Option<(u32, Range<u64>)>,
+    pre_roll_seconds: f64,
+    comp_crossfade_seconds: f64,
+    transport: Transport,
+    end_action: EndAction,
+    dither: Dither,
+    monitor: Option<InputMonitor>,
+    scratch: Vec<f64>,
 }
 
-impl Timeline {
-    pub fn new(sample_rate: u32) -> Self {
-        Timeline {
-            tracks: Vec::new(),
-            active_track_ids: HashSet::new(),
-            sample_rate,
-            playhead_position: 0,
+impl Timeline {
+    const EXPORT_FRAMES: usize = 4096;
+    const DECLICK_SECONDS: f64 = 0.005;
+    const POSITION_INTERVAL_SECONDS: f64 = 0.05;
+    // Input older than this is dropped instead of monitored
+    const MONITOR_SECONDS: f64 = 0.05;
+
+    pub fn new(sample_rate: u32) -> Self {
+        Timeline {
+            tracks: Vec::new(),
+            active_track_ids: HashSet::new(),
+            sample_rate,
+            resampler_quality: ResamplerQuality::default(),
+            playhead_position: 0,
+            armed_track_id: None,
+            recording: None,
+            playback_rate: 1.0,
+            varispeed: None,
+            output_channels: 2,
+            tempo_map: TempoMap::new(sample_rate),
+            metronome: Metronome::default(),
+            count_in: None,
+            loop_range: None,
+            punch_range: None,
+            playhead_placement: None,
+            loop_placement: None,
+            punch_placement: None,
+            pre_roll_seconds: 2.0,
+            comp_crossfade_seconds: 0.01,
+            transport: Transport::default(),
+            end_action: EndAction::default(),
+            dither: Dither::default(),
+            monitor: None,
+            scratch: Vec::new(),
+        }
+    }
+
+    pub fn new_track(&mut self) -> TrackId {
+        let track_id = if let Some(track) = self.tracks.last() {
+            track.id + TrackId(1)
+        } else {
+            TrackId(1)
+        };
+
+        let mut track = Track::new(track_id);
+        track.set_comp_crossfade(self.comp_crossfade_frames());
+
+        if self.tracks.iter().any(|t| t.is_soloed()) {
+            track.mute();
+        } else {
+            self.active_track_ids.insert(track.id);
+        }
+        self.tracks.push(track);
+
+        track_id
+    }
+
+    pub fn add_clip<P: AsRef<Path>>(
+        &mut self,
+        track_id: TrackId,
+        path: P,
+    ) -> Result<(), AudioError> {
+        let timeline_sample_rate = self.sample_rate;
+        let resampler_quality = self.resampler_quality;
+
+        let track = self
+            .get_mut_track(track_id)
+            .ok_or(AudioError::TrackNotFound(track_id))?;
+
+        track.add_clip(path, timeline_sample_rate, resampler_quality)?;
+
+        Ok(())
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: u32) -> Result<(), AudioError> {
+        if sample_rate == 0 {
+            return Err(AudioError::InvalidSampleRate(sample_rate));
+        }
+
+        if self.is_recording() {
+            return Err(AudioError::RecordingInProgress);
+        }
+
+        if sample_rate == self.sample_rate {
+            return Ok(());
+        }
+
+        let tracks = self
+            .tracks
+            .iter()
+            .map(|track| {
+                track.with_sample_rate(sample_rate, self.sample_rate, self.resampler_quality)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        self.tracks = tracks;
+        let convert = |frames: u64, placed_rate: u32| {
+            Resampler::output_frames(frames as usize, sample_rate, placed_rate) as u64
+        };
+        let (placed_rate, placed) = match self.playhead_placement {
+            Some((rate, position, converted)) if converted == self.playhead_position => {
+                (rate, position)
+            }
+            _ => (self.sample_rate, self.playhead_position),
+        };
+        self.playhead_position = convert(placed, placed_rate);
+        self.playhead_placement = Some((placed_rate, placed, self.playhead_position));
+        let place = |placement: &Option<(u32, Range<u64>)>| {
+            placement
+                .as_ref()
+                .map(|(rate, range)| convert(range.start, *rate)..convert(range.end, *rate))
+        };
+        self.loop_range = place(&self.loop_placement);
+        self.punch_range = place(&self.punch_placement);
+        self.sample_rate = sample_rate;
+        self.reset_varispeed();
+        let crossfade = self.comp_crossfade_frames();
+        self.tracks
+            .iter_mut()
+            .for_each(|track| track.set_comp_crossfade(crossfade));
+        self.tempo_map.set_sample_rate(sample_rate);
+
+        Ok(())
+    }
+
+    pub fn save_peaks(&self) -> Result<(), AudioError> {
+        self.tracks
+            .iter()
+            .flat_map(|track| track.clips())
+            .try_for_each(|clip| clip.save_peaks())
+    }
+
+    pub fn resampler_quality(&self) -> ResamplerQuality {
+        self.resampler_quality
+    }
+
+    pub fn set_resampler_quality(&mut self, quality: ResamplerQuality) {
+        self.resampler_quality = quality;
+    }
+
+    pub fn set_volume(&mut self, track_id: TrackId, volume_percent: f32) -> Result<(), AudioError> {
+        let track = self
+            .get_mut_track(track_id)
+            .ok_or(AudioError::TrackNotFound(track_id))?;
+
+        track.set_volume_percent(volume_percent)?;
+
+        Ok(())
+    }
+
+    pub fn set_track_name(
+        &mut self,
+        track_id: TrackId,
+        new_name: String,
+    ) -> Result<(), AudioError> {
+        let track = self
+            .get_mut_track(track_id)
+            .ok_or(AudioError::TrackNotFound(track_id))?;
+
+        track.name = new_name;
+
+        Ok(())
+    }
+
+    pub fn set_monitor_mode(
+        &mut self,
+        track_id: TrackId,
+        monitor_mode: MonitorMode,
+    ) -> Result<(), AudioError> {
+        let track = self
+            .get_mut_track(track_id)
+            .ok_or(AudioError::TrackNotFound(track_id))?;
+
+        track.set_monitor_mode(monitor_mode);
+
+        Ok(())
+    }
+
+    pub fn set_channel_map(
+        &mut self,
+        track_id: TrackId,
+        channel_map: ChannelMap,
+    ) -> Result<(), AudioError> {
+        let track = self
+            .get_mut_track(track_id)
+            .ok_or(AudioError::TrackNotFound(track_id))?;
+
+        track.set_channel_map(channel_map)?;
+        self.update_monitor_channel_map();
+        Ok(())
+    }
+
+    pub fn set_pitch_correction(
+        &mut self,
+        track_id: TrackId,
+        clip_index: usize,
+        pitch_correction: PitchCorrection,
+    ) -> Result<(), AudioError> {
+        self.get_mut_track(track_id)
+            .ok_or(AudioError::TrackNotFound(track_id))?
+            .get_mut_clip(clip_index)
+            .ok_or(AudioError::ClipNotFound(clip_index))?
+            .set_pitch_correction(pitch_correction)
+    }
+
+    pub fn clip(&self, track_id: TrackId, clip_index: usize) -> Result<&Clip, AudioError> {
+        self.get_track(track_id)
+            .ok_or(AudioError::TrackNotFound(track_id))?
+            .clips()
+            .get(clip_index)
+            .ok_or(AudioError::ClipNotFound(clip_index))
+    }
+
+    // Swaps in a clip edited elsewhere, e.g. processed without holding the timeline
+    pub fn replace_clip(
+        &mut self,
+        track_id: TrackId,
+        clip_index: usize,
+        clip: Clip,
+    ) -> Result<Clip, AudioError> {
+        let slot = self
+            .get_mut_track(track_id)
+            .ok_or(AudioError::TrackNotFound(track_id))?
+            .get_mut_clip(clip_index)
+            .ok_or(AudioError::ClipNotFound(clip_index))?;
+
+        Ok(std::mem::replace(slot, clip))
+    }
+
+    pub fn clear_pitch_correction(
+        &mut self,
+        track_id: TrackId,
+        clip_index: usize,
+    ) -> Result<(), AudioError> {
+        self.get_mut_track(track_id)
+            .ok_or(AudioError::TrackNotFound(track_id))?
+            .get_mut_clip(clip_index)
+            .ok_or(AudioError::ClipNotFound(clip_index))?
+            .clear_pitch_correction()
+    }
+
+    pub fn set_stretch(
+        &mut self,
+        track_id: TrackId,
+        clip_index: usize,
+        stretch: f64,
+    ) -> Result<(), AudioError> {
+        self.get_mut_track(track_id)
+            .ok_or(AudioError::TrackNotFound(track_id))?
+            .get_mut_clip(clip_index)
+            .ok_or(AudioError::ClipNotFound(clip_index))?
+            .set_stretch(stretch)
+    }
+
+    pub fn set_warp_markers(
+        &mut self,
+        track_id: TrackId,
+        clip_index: usize,
+        warp_markers: Vec<WarpMarker>,
+    ) -> Result<(), AudioError> {
+        self.get_mut_track(track_id)
+            .ok_or(AudioError::TrackNotFound(track_id))?
+            .get_mut_clip(clip_index)
+            .ok_or(AudioError::ClipNotFound(clip_index))?
+            .set_warp_markers(warp_markers)
+    }
+
+    // Moves the take to line up with the reference and stores the fine timing as warp
+    // markers, replacing any earlier warp. The audio itself is left untouched.
+    pub fn align_clip(
+        &mut self,
+        track_id: TrackId,
+        clip_index: usize,
+        reference_track_id: TrackId,
+        reference_clip_index: usize,
+        aligner: &TakeAligner,
+    ) -> Result<Alignment, AudioError> {
+        let reference = self
+            .get_track(reference_track_id)
+            .ok_or(AudioError::TrackNotFound(reference_track_id))?
+            .clips()
+            .get(reference_clip_index)
+            .ok_or(AudioError::ClipNotFound(reference_clip_index))?;
+        let reference_start = reference.start_time_in_samples();
+        let reference = reference.mixdown();
+
+        let sample_rate = self.sample_rate;
+        let clip = self
+            .get_mut_track(track_id)
+            .ok_or(AudioError::TrackNotFound(track_id))?
+            .get_mut_clip(clip_index)
+            .ok_or(AudioError::ClipNotFound(clip_index))?;
+
+        // Aligns the audio as it plays, so that a stretch or warp already on the clip stays
+        let alignment = aligner.align(
+            &reference,
+            reference_start,
+            &clip.mixdown(),
+            clip.start_time_in_samples(),
+            sample_rate,
+        );
+
+        let start_time_in_samples =
+            (clip.start_time_in_samples() as i64 + alignment.offset_frames).max(0) as u64;
+        clip.set_start_time_in_samples(start_time_in_samples);
+        clip.warp_rendered(&alignment.warp_markers)?;
+
+        Ok(alignment)
+    }
+
+    pub fn arm(&mut self, track_id: TrackId) -> Result<(), AudioError> {
+        self.get_track(track_id)
+            .ok_or(AudioError::TrackNotFound(track_id))?;
+
+        self.armed_track_id = Some(track_id);
+        self.update_monitor_channel_map();
+
+        Ok(())
+    }
+
+    pub fn disarm(&mut self) {
+        self.armed_track_id = None;
+        self.update_monitor_channel_map();
+    }
+
+    pub fn armed_track_id(&self) -> Option<TrackId> {
+        self.armed_track_id
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recording
+            .as_ref()
+            .is_some_and(|recording| !recording.is_stopped())
+    }
+
+    // Stopped in a stream callback, by a transport command or at the punch out.
+    // `stop_recording` makes the takes.
+    pub fn has_stopped_recording(&self) -> bool {
+        self.recording.as_ref().is_some_and(Recording::is_stopped)
+    }
+
+    // Called when the input opens, from then on `record` also feeds the monitor
+    pub fn start_monitoring(&mut self, input_channels: u16) {
+        let max_frames = (Self::MONITOR_SECONDS * self.sample_rate as f64).round() as usize;
+        self.monitor = Some(InputMonitor::new(
+            input_channels,
+            self.output_channels,
+            max_frames,
+        ));
+        self.update_monitor_channel_map();
+    }
+
+    // The armed track's map when it fits the input and output, otherwise the default one
+    fn update_monitor_channel_map(&mut self) {
+        let Some(channels) = self.monitor.as_ref().map(InputMonitor::channels) else {
+            return;
+        };
+        let output_channels = self.output_channels;
+        let channel_map = self
+            .armed_track_id
+            .and_then(|id| self.get_track(id))
+            .and_then(|track| track.channel_map())
+            .filter(|map| {
+                map.source_channels() == channels && map.output_channels() == output_channels
+            })
+            .cloned()
+            .unwrap_or_else(|| ChannelMap::default_for(channels, output_channels));
+
+        if let Some(monitor) = self.monitor.as_mut() {
+            monitor.set_channel_map(channel_map);
+        }
+    }
+
+    pub fn stop_monitoring(&mut self) {
+        self.monitor = None;
+    }
+
+    // The armed track, while its monitor mode says its input should be heard
+    pub fn monitored_track_id(&self) -> Option<TrackId> {
+        let track = self.get_track(self.armed_track_id?)?;
+        let listening = match track.monitor_mode() {
+            MonitorMode::Off => false,
+            MonitorMode::Auto => self.is_recording() || !self.transport.state().is_rolling(),
+            MonitorMode::Always => true,
+        };
+
+        (listening && self.audible_tracks().any(|audible| audible.id == track.id))
+            .then_some(track.id)
+    }
+
+    // With a punch range, playback starts from the pre-roll and only the range is kept, as a
+    // take over whatever was there. A punch takes the place of cycle recording.
+    pub fn start_recording(&mut self, input_channels: u16) -> Result<(), AudioError> {
+        if self.is_recording() {
+            return Err(AudioError::RecordingInProgress);
+        }
+        let track_id = self.armed_track_id.ok_or(AudioError::NoArmedTrack)?;
+
+        self.get_track(track_id)
+            .ok_or(AudioError::TrackNotFound(track_id))?;
+
+        if self.has_stopped_recording() {
+            self.stop_recording()?;
+        }
+
+        if let Some(punch_range) = self.punch_range.clone() {
+            let pre_roll = (self.pre_roll_seconds * self.sample_rate as f64).round() as u64;
+            self.seek(punch_range.start.saturating_sub(pre_roll))?;
+        }
+
+        let mut recording = Recording::new(
+            track_id,
+            input_channels,
+            self.sample_rate,
+            self.playhead_position,
+        );
+        if let Some(punch_range) = self.punch_range.clone() {
+            let handle = self.comp_crossfade_frames() / 2;
+            recording = recording.with_punch(punch_range, handle);
+        } else if let Some(loop_range) = self.loop_range.clone() {
+            recording = recording.with_cycle(loop_range);
+        }
+        self.recording = Some(recording);
+        self.transport.set_state(TransportState::Recording);
+        self.transport
+            .emit(TransportEvent::RecordingStarted(track_id));
+        self.count_in = CountIn::new(
+            self.metronome.count_in_bars,
+            &self.tempo_map,
+            self.playhead_position,
+        );
+
+        Ok(())
+    }
+
+    pub fn is_counting_in(&self) -> bool {
+        self.count_in.is_some()
+    }
+
+    // Input that arrives during the count-in is monitored but not recorded. Recording stops
+    // taking input once it has passed the punch out, `stop_recording` then makes the take.
+    pub fn record<T>(&mut self, input: &[T])
+    where
+        T: ToF64Sample,
+    {
+        if let Some(monitor) = self.monitor.as_mut() {
+            monitor.push(input);
+        }
+
+        if self.count_in.is_some() {
+            return;
+        }
+
+        if let Some(recording) = self.recording.as_mut() {
+            if !recording.push(input) {
+                self.transport.emit(TransportEvent::Xrun);
+            }
+            if recording.is_past_punch_out() && !recording.is_stopped() {
+                recording.stop();
+                self.transport.set_state(TransportState::Playing);
+            }
+        }
+    }
+
+    // How many chunks the recording wants and their size in samples, for a thread off the
+    // audio path to allocate and hand over with `add_recording_chunks`
+    pub fn recording_chunks_needed(&self) -> Option<(usize, usize)> {
+        let recording = self.recording.as_ref()?;
+        let needed = recording.spare_chunks_needed();
+        (needed > 0).then(|| (needed, recording.chunk_samples()))
+    }
+
+    pub fn add_recording_chunks(&mut self, chunks: Vec<Vec<f64>>) {
+        if let Some(recording) = self.recording.as_mut() {
+            recording.add_chunks(chunks);
+        }
+    }
+
+    // The transport keeps playing, like a punch out
+    pub fn stop_recording(&mut self) -> Result<(), AudioError> {
+        self.count_in = None;
+        if self.transport.state() == TransportState::Recording {
+            self.transport.set_state(TransportState::Playing);
+        }
+
+        let Some(recording) = self.recording.take() else {
+            return Ok(());
+        };
+        self.transport
+            .emit(TransportEvent::RecordingStopped(recording.track_id()));
+
+        if recording.duration_in_samples() == 0 {
+            return Ok(());
+        }
+
+        let track_id = recording.track_id();
+        let track = self
+            .get_mut_track(track_id)
+            .ok_or(AudioError::TrackNotFound(track_id))?;
+
+        if let Some(punch) = recording.punch() {
+            recording
+                .into_takes()
+                .into_iter()
+                .try_for_each(|take| track.push_punch_take(take, punch.clone()))?;
+        } else if recording.is_cycle() {
+            recording
+                .into_takes()
+                .into_iter()
+                .try_for_each(|take| track.push_take(take))?;
+        } else {
+            track.push_clip(recording.into_clip())?;
+        }
+
+        Ok(())
+    }
+
+    pub fn mute(&mut self, track_id: TrackId) -> Result<(), AudioError> {
+        let track = self
+            .get_mut_track(track_id)
+            .ok_or(AudioError::TrackNotFound(track_id))?;
+
+        if !track.is_muted() {
+            track.mute();
+            track.unsolo();
+            self.active_track_ids.remove(&track_id);
+        }
+
+        Ok(())
+    }
+
+    pub fn unmute(&mut self, track_id: TrackId) -> Result<(), AudioError> {
+        let track = self
+            .get_mut_track(track_id)
+            .ok_or(AudioError::TrackNotFound(track_id))?;
+
+        if track.is_muted() {
+            track.unmute();
+            self.active_track_ids.insert(track_id);
+        }
+
+        Ok(())
+    }
+
+    pub fn is_muted(&self, track_id: TrackId) -> Result<bool, AudioError> {
+        let track = self
+            .get_track(track_id)
+            .ok_or(AudioError::TrackNotFound(track_id))?;
+
+        Ok(track.is_muted())
+    }
+
+    pub fn solo(&mut self, track_id: TrackId) -> Result<(), AudioError> {
+        let track = self
+            .get_mut_track(track_id)
+            .ok_or(AudioError::TrackNotFound(track_id))?;
+
+        if !track.is_soloed() {
+            track.solo();
+            self.tracks
+                .iter_mut()
+                .filter(|t| t.id != track_id)
+                .for_each(|track| {
+                    track.unsolo();
+                    track.mute();
+                });
+            self.active_track_ids.clear();
+            self.active_track_ids.insert(track_id);
+        }
+
+        Ok(())
+    }
+
+    pub fn unsolo(&mut self, track_id: TrackId) -> Result<(), AudioError> {
+        let track = self
+            .get_mut_track(track_id)
+            .ok_or(AudioError::TrackNotFound(track_id))?;
+
+        if track.is_soloed() {
+            track.unsolo();
+            if track.is_muted() {
+                self.active_track_ids.remove(&track_id);
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn is_soloed(&self, track_id: TrackId) -> Result<bool, AudioError> {
+        let track = self
+            .get_track(track_id)
+            .ok_or(AudioError::TrackNotFound(track_id))?;
+
+        Ok(track.is_soloed())
+    }
+
+    pub fn track_count(&self) -> usize {
+        self.tracks.len()
+    }
+
+    pub fn get_track_ids(&self) -> Vec<TrackId> {
+        self.tracks.iter().map(|t| t.id).collect()
+    }
+
+    // In timeline frames, the unit of the playhead, whatever the playback rate
+    pub fn duration_in_samples(&self) -> u64 {
+        self.tracks
+            .iter()
+            .map(|track| track.duration_in_samples())
+            .max()
+            .unwrap_or(0)
+    }
+
+    // How long playback takes at the current playback rate, in output frames
+    pub fn playback_duration_in_samples(&self) -> u64 {
+        (self.duration_in_samples() as f64 / self.playback_rate).round() as u64
+    }
+
+    pub fn duration_in_seconds(&self) -> f64 {
+        self.duration_in_samples() as f64 / self.sample_rate as f64
+    }
+
+    pub fn playhead_position_seconds(&self) -> f64 {
+        self.playhead_position as f64 / self.sample_rate as f64
+    }
+
+    pub fn set_playhead_seconds(&mut self, seconds: f64) {
+        self.playhead_position = (seconds * self.sample_rate as f64) as u64;
+        self.reset_varispeed();
+    }
+
+    pub fn reset_playhead(&mut self) {
+        self.playhead_position = 0;
+        self.reset_varispeed();
+    }
+
+    pub fn playhead_position(&self) -> MusicalPosition {
+        self.tempo_map.samples_to_position(self.playhead_position)
+    }
+
+    pub fn set_playhead_position(&mut self, position: MusicalPosition) {
+        self.playhead_position = self.tempo_map.position_to_samples(position);
+        self.reset_varispeed();
+    }
+
+    pub fn tempo_map(&self) -> &TempoMap {
+        &self.tempo_map
+    }
+
+    pub fn tempo_map_mut(&mut self) -> &mut TempoMap {
+        &mut self.tempo_map
+    }
+
+    pub fn select_take(
+        &mut self,
+        track_id: TrackId,
+        take: usize,
+        start: u64,
+        end: u64,
+        grid: Option<Grid>,
+    ) -> Result<(), AudioError> {
+        let (start, end) = (self.snap(start, grid), self.snap(end, grid));
+        let track = self
+            .get_mut_track(track_id)
+            .ok_or(AudioError::TrackNotFound(track_id))?;
+        track.select_take(take, start, end)
+    }
+
+    pub fn clear_comp(&mut self, track_id: TrackId) -> Result<(), AudioError> {
+        let track = self
+            .get_mut_track(track_id)
+            .ok_or(AudioError::TrackNotFound(track_id))?;
+        track.clear_comp();
+        Ok(())
+    }
+
+    pub fn comp_crossfade_seconds(&self) -> f64 {
+        self.comp_crossfade_seconds
+    }
+
+    pub fn set_comp_crossfade_seconds(&mut self, seconds: f64) {
+        self.comp_crossfade_seconds = seconds.max(0.0);
+        let crossfade = self.comp_crossfade_frames();
+        self.tracks
+            .iter_mut()
+            .for_each(|track| track.set_comp_crossfade(crossfade));
+    }
+
+    fn comp_crossfade_frames(&self) -> u64 {
+        (self.comp_crossfade_seconds * self.sample_rate as f64).round() as u64
+    }
+
+    pub fn transport_state(&self) -> TransportState {
+        self.transport.state()
+    }
+
+    // For other threads: commands are applied at the start of the next buffer, so they don't
+    // need to hold the timeline lock
+    pub fn transport_sender(&self) -> Sender<TransportCommand> {
+        self.transport.sender()
+    }
+
+    pub fn subscribe(&mut self) -> Receiver<TransportEvent> {
+        self.transport.subscribe()
+    }
+
+    // Passes on events from outside the timeline, such as stream errors
+    pub fn notify(&mut self, event: TransportEvent) {
+        self.transport.emit(event);
+    }
+
+    pub fn end_action(&self) -> EndAction {
+        self.end_action
+    }
+
+    pub fn set_end_action(&mut self, end_action: EndAction) {
+        self.end_action = end_action;
+    }
+
+    pub fn play(&mut self) {
+        match self.transport.state() {
+            TransportState::Paused => {
+                self.transport.fade_in(self.declick_frames());
+                self.transport.set_state(TransportState::Playing);
+            }
+            TransportState::Stopped => self.transport.set_state(TransportState::Playing),
+            TransportState::Playing | TransportState::Recording => {}
+        }
+    }
+
+    pub fn pause(&mut self) -> Result<(), AudioError> {
+        match self.transport.state() {
+            TransportState::Recording => Err(AudioError::RecordingInProgress),
+            TransportState::Playing => {
+                self.transport.fade_out_from(self.playhead_position);
+                self.transport.set_state(TransportState::Paused);
+                Ok(())
+            }
+            TransportState::Stopped | TransportState::Paused => Ok(()),
+        }
+    }
+
+    // Stopping also finishes a recording and returns the playhead to the start
+    pub fn stop(&mut self) -> Result<(), AudioError> {
+        self.stop_recording()?;
+        self.stop_transport();
+        Ok(())
+    }
+
+    // What a stop command does in the output callback: a recording only stops taking input,
+    // its takes are left for `stop_recording` to make
+    fn stop_transport(&mut self) {
+        if let Some(recording) = self.recording.as_mut() {
+            recording.stop();
+            self.count_in = None;
+        }
+        self.transport.fade_out_from(self.playhead_position);
+        self.transport.set_state(TransportState::Stopped);
+        self.playhead_position = 0;
+        self.reset_varispeed();
+    }
+
+    pub fn is_fading_out(&self) -> bool {
+        self.transport.is_fading_out()
+    }
+
+    // While rolling, the old position fades out and the new one fades in
+    pub fn seek(&mut self, samples: u64) -> Result<(), AudioError> {
+        if self.is_recording() {
+            return Err(AudioError::RecordingInProgress);
+        }
+
+        if self.transport.state().is_rolling() {
+            self.transport.fade_out_from(self.playhead_position);
+            self.transport.fade_in(self.declick_frames());
+        }
+        self.playhead_position = samples;
+
+        Ok(())
+    }
+
+    fn declick_frames(&self) -> u64 {
+        (Self::DECLICK_SECONDS * self.sample_rate as f64).round() as u64
+    }
+
+    pub fn loop_range(&self) -> Option<Range<u64>> {
+        self.loop_range.clone()
+    }
+
+    pub fn set_loop_range(
+        &mut self,
+        start: u64,
+        end: u64,
+        grid: Option<Grid>,
+    ) -> Result<(), AudioError> {
+        if self.is_recording() {
+            return Err(AudioError::RecordingInProgress);
+        }
+
+        let (start, end) = (self.snap(start, grid), self.snap(end, grid));
+        if end <= start {
+            return Err(AudioError::InvalidLoopRange { start, end });
+        }
+
+        self.loop_range = Some(start..end);
+        self.loop_placement = Some((self.sample_rate, start..end));
+
+        Ok(())
+    }
+
+    pub fn clear_loop_range(&mut self) -> Result<(), AudioError> {
+        if self.is_recording() {
+            return Err(AudioError::RecordingInProgress);
+        }
+
+        self.loop_range = None;
+        self.loop_placement = None;
+
+        Ok(())
+    }
+
+    pub fn punch_range(&self) -> Option<Range<u64>> {
+        self.punch_range.clone()
+    }
+
+    pub fn set_punch_range(
+        &mut self,
+        start: u64,
+        end: u64,
+        grid: Option<Grid>,
+    ) -> Result<(), AudioError> {
+        if self.is_recording() {
+            return Err(AudioError::RecordingInProgress);
+        }
+
+        let (start, end) = (self.snap(start, grid), self.snap(end, grid));
+        if end <= start {
+            return Err(AudioError::InvalidPunchRange { start, end });
+        }
+
+        self.punch_range = Some(start..end);
+        self.punch_placement = Some((self.sample_rate, start..end));
+
+        Ok(())
+    }
+
+    pub fn clear_punch_range(&mut self) -> Result<(), AudioError> {
+        if self.is_recording() {
+            return Err(AudioError::RecordingInProgress);
+        }
+
+        self.punch_range = None;
+        self.punch_placement = None;
+
+        Ok(())
+    }
+
+    pub fn pre_roll_seconds(&self) -> f64 {
+        self.pre_roll_seconds
+    }
+
+    pub fn set_pre_roll_seconds(&mut self, seconds: f64) {
+        self.pre_roll_seconds = seconds.max(0.0);
+    }
+
+    pub fn metronome(&self) -> &Metronome {
+        &self.metronome
+    }
+
+    pub fn metronome_mut(&mut self) -> &mut Metronome {
+        &mut self.metronome
+    }
+
+    pub fn snap(&self, samples: u64, grid: Option<Grid>) -> u64 {
+        grid.map_or(samples, |grid| self.tempo_map.snap(samples, grid))
+    }
+
+    pub fn move_clip(
+        &mut self,
+        track_id: TrackId,
+        clip_index: usize,
+        start_time_in_samples: u64,
+        grid: Option<Grid>,
+    ) -> Result<(), AudioError> {
+        let start_time_in_samples = self.snap(start_time_in_samples, grid);
+
+        self.get_mut_track(track_id)
+            .ok_or(AudioError::TrackNotFound(track_id))?
+            .get_mut_clip(clip_index)
+            .ok_or(AudioError::ClipNotFound(clip_index))?
+            .set_start_time_in_samples(start_time_in_samples);
+
+        Ok(())
+    }
+
+    // `position` is on the timeline, not relative to the clip
+    pub fn split_clip(
+        &mut self,
+        track_id: TrackId,
+        clip_index: usize,
+        position: u64,
+        grid: Option<Grid>,
+    ) -> Result<(), AudioError> {
+        let position = self.snap(position, grid);
+        let track = self
+            .get_mut_track(track_id)
+            .ok_or(AudioError::TrackNotFound(track_id))?;
+        let start = track
+            .clips()
+            .get(clip_index)
+            .ok_or(AudioError::ClipNotFound(clip_index))?
+            .start_time_in_samples();
+
+        let frame = position
+            .checked_sub(start)
+            .ok_or(AudioError::InvalidSplitPosition(position))?;
+        track.split_clip(clip_index, frame)
+    }
+
+    pub fn playback_rate(&self) -> f64 {
+        self.playback_rate
+    }
+
+    pub fn set_playback_rate(&mut self, playback_rate: f64) -> Result<(), AudioError> {
+        Varispeed::validate_rate(playback_rate)?;
+
+        match self.varispeed.as_mut() {
+            Some(varispeed) => {
+                // What it holds from before playing at the natural rate is stale
+                if self.playback_rate == 1.0 {
+                    varispeed.reset()?;
+                }
+                varispeed.set_rate(playback_rate)?;
+            }
+            None if playback_rate != 1.0 => {
+                self.varispeed = Some(Varispeed::new(
+                    playback_rate,
+                    self.output_channels,
+                    self.resampler_quality,
+                )?);
+            }
+            None => {}
+        }
+        self.playback_rate = playback_rate;
+
+        Ok(())
+    }
+
+    // Called when the output opens, so the varispeed is ready before the first callback
+    pub fn set_output_channels(&mut self, output_channels: u16) -> Result<(), AudioError> {
+        if output_channels != self.output_channels {
+            self.output_channels = output_channels;
+            self.varispeed = None;
+            self.update_monitor_channel_map();
+        }
+        if self.varispeed.is_none() && self.playback_rate != 1.0 {
+            self.varispeed = Some(Varispeed::new(
+                self.playback_rate,
+                output_channels,
+                self.resampler_quality,
+            )?);
+        }
+
+        Ok(())
+    }
+
+    // A jump in the playhead must not play what the varispeed still holds
+    fn reset_varispeed(&mut self) {
+        // The rate was checked when it was set, so a reset can't fail in practice
+        if let Some(varispeed) = self.varispeed.as_mut()
+            && varispeed.reset().is_err()
+        {
+            self.varispeed = None;
+        }
+    }
+
+    pub fn set_clip_playback_rate(
+        &mut self,
+        track_id: TrackId,
+        clip_index: usize,
+        playback_rate: f64,
+    ) -> Result<(), AudioError> {
+        self.get_mut_track(track_id)
+            .ok_or(AudioError::TrackNotFound(track_id))?
+            .get_mut_clip(clip_index)
+            .ok_or(AudioError::ClipNotFound(clip_index))?
+            .set_playback_rate(playback_rate)
+    }
+
+    pub fn get_mut_track(&mut self, track_id: TrackId) -> Option<&mut Track> {
+        self.tracks.iter_mut().find(|t| t.id == track_id)
+    }
+
+    pub fn get_track(&self, track_id: TrackId) -> Option<&Track> {
+        self.tracks.iter().find(|t| t.id == track_id)
+    }
+
+    pub fn analyze_harmony(&self, analyzer: &HarmonyAnalyzer) -> Result<HarmonyReport, AudioError> {
+        let voices = self
+            .audible_tracks()
+            .map(|track| Ok((track.id, track.pitch_track(&analyzer.tracker)?)))
+            .collect::<Result<Vec<(TrackId, Vec<PitchPoint>)>, AudioError>>()?;
+
+        Ok(analyzer.analyze(&voices))
+    }
+
+    fn audible_tracks(&self) -> impl Iterator<Item = &Track> {
+        let soloed_track = self.tracks.iter().find(|t| t.is_soloed());
+
+        self.tracks.iter().filter(move |track| {
+            if let Some(soloed_track) = soloed_track {
+                track.id == soloed_track.id
+            } else {
+                !track.is_muted()
+            }
+        })
+    }
+
+    // Called at the start of every output buffer, or by the engine once the output is closed
+    pub fn apply_commands(&mut self) {
+        while let Some(command) = self.transport.next_command() {
+            let _ = match command {
+                TransportCommand::Play => {
+                    self.play();
+                    Ok(())
+                }
+                TransportCommand::Pause => self.pause(),
+                TransportCommand::Stop => {
+                    self.stop_transport();
+                    Ok(())
+                }
+                TransportCommand::Seek(samples) => self.seek(samples),
+            };
+        }
+    }
+
+    pub fn process<T>(&mut self, buffer: &mut [T], output_channels: u16)
+    where
+        T: FromF64Sample,
+    {
+        self.apply_commands();
+
+        let mut mixed = std::mem::take(&mut self.scratch);
+        mixed.clear();
+        mixed.resize(buffer.len(), 0.0);
+
+        self.render(&mut mixed, output_channels);
+        self.mix_monitor(&mut mixed, output_channels);
+        self.dither
+            .process(&mut mixed, output_channels as usize, T::STEP);
+
+        buffer
+            .iter_mut()
+            .zip(&mixed)
+            .for_each(|(sample, mixed)| *sample = T::from_f64_sample(*mixed));
+        self.scratch = mixed;
+    }
+
+    // Live input goes out with the buffer rendered right after it arrived, at the track volume
+    fn mix_monitor(&mut self, buffer: &mut [f64], output_channels: u16) {
+        let Some(mut monitor) = self.monitor.take() else {
+            return;
+        };
+
+        match self.monitored_track_id().and_then(|id| self.get_track(id)) {
+            Some(track) => monitor.mix_into(buffer, output_channels, track.volume() as f64),
+            None => monitor.clear(),
+        }
+
+        self.monitor = Some(monitor);
+    }
+
+    fn render(&mut self, buffer: &mut [f64], output_channels: u16) {
+        let samples_per_frame = output_channels.max(1) as usize;
+        let declick_frames = self.declick_frames();
+        let position_interval =
+            (Self::POSITION_INTERVAL_SECONDS * self.sample_rate as f64).round() as u64;
+
+        let mut buffer = match self.count_in.as_mut() {
+            Some(count_in) => {
+                let frames = self.metronome.process_count_in(
+                    buffer,
+                    output_channels,
+                    count_in,
+                    self.sample_rate,
+                );
+                if !count_in.is_finished() {
+                    return;
+                }
+                self.count_in = None;
+                &mut buffer[frames * samples_per_frame..]
+            }
+            None => buffer,
+        };
+
+        while !buffer.is_empty() {
+            let available = buffer.len() / samples_per_frame;
+
+            if let Some(tail) = self.transport.take_tail() {
+                let frames = (declick_frames as usize).min(available);
+                let (segment, rest) = buffer.split_at_mut(frames * samples_per_frame);
+                let position = std::mem::replace(&mut self.playhead_position, tail);
+
+                self.roll(segment, output_channels);
+                Transport::apply_fade_out(segment, samples_per_frame);
+
+                self.playhead_position = position;
+                self.reset_varispeed();
+                buffer = rest;
+                continue;
+            }
+
+            if !self.transport.state().is_rolling() {
+                break;
+            }
+
+            let frames = match self.frames_until_end() {
+                Some(0) => {
+                    self.transport.emit(TransportEvent::ReachedEnd);
+                    // Nothing plays past the end, so only the restart needs the declick
+                    if self.end_action == EndAction::Loop && self.playhead_position > 0 {
+                        self.playhead_position = 0;
+                        self.reset_varispeed();
+                        self.transport.fade_in(declick_frames);
+                        continue;
+                    }
+                    self.transport.set_state(TransportState::Stopped);
+                    break;
+                }
+                Some(frames) => (frames as usize).min(available),
+                None => available,
+            };
+
+            let (segment, rest) = buffer.split_at_mut(frames * samples_per_frame);
+            self.roll(segment, output_channels);
+            self.transport
+                .apply_fade_in(segment, samples_per_frame, declick_frames);
+            self.transport
+                .advance(frames as u64, self.playhead_position, position_interval);
+            buffer = rest;
+        }
+    }
+
+    // Output frames left before playback runs off the end, unless recording or headed into
+    // a loop, which it never leaves
+    fn frames_until_end(&self) -> Option<u64> {
+        let looping = self
+            .loop_range
+            .as_ref()
+            .is_some_and(|range| self.playhead_position < range.end);
+        if self.is_recording() || looping {
+            return None;
+        }
+
+        let end = self
+            .tracks
+            .iter()
+            .map(|track| track.duration_in_samples())
+            .max()
+            .unwrap_or(0);
+        let remaining = end.saturating_sub(self.playhead_position) as f64;
+
+        Some((remaining / self.playback_rate).ceil() as u64)
+    }
+
+    // The varispeed is built ahead by `set_playback_rate` and `set_output_channels`, never
+    // here. Without one for this layout the buffer plays at the natural rate.
+    fn roll(&mut self, buffer: &mut [f64], output_channels: u16) {
+        let Some(mut varispeed) = self.varispeed.take().filter(|varispeed| {
+            self.playback_rate != 1.0 && varispeed.channels() == output_channels
+        }) else {
+            self.mix(buffer, output_channels);
+            return;
+        };
+
+        if varispeed
+            .process(buffer, |input| self.mix(input, output_channels))
+            .is_err()
+        {
+            buffer.fill(0.0);
+        }
+        self.varispeed = Some(varispeed);
+    }
+
+    // Applies to the live output when it runs in an integer format
+    pub fn dither_mode(&self) -> DitherMode {
+        self.dither.mode()
+    }
+
+    pub fn set_dither_mode(&mut self, mode: DitherMode) {
+        self.dither.set_mode(mode);
+    }
+
+    // Renders the whole arrangement at its natural rate, without the click
+    pub fn export<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        output_channels: u16,
+        options: ExportOptions,
+    ) -> Result<(), AudioError> {
+        let spec = options.format.spec(output_channels, self.sample_rate);
+        let mut writer = hound::WavWriter::create(path, spec)?;
+        let mut dither = Dither::new(options.dither);
+
+        let playhead_position = self.playhead_position;
+        let duration = self
+            .tracks
+            .iter()
+            .map(|track| track.duration_in_samples())
+            .max()
+            .unwrap_or(0);
+        let mut buffer = vec![0.0f64; Self::EXPORT_FRAMES * output_channels as usize];
+
+        self.playhead_position = 0;
+        while self.playhead_position < duration {
+            let frames = (duration - self.playhead_position).min(Self::EXPORT_FRAMES as u64);
+            let buffer = &mut buffer[..frames as usize * output_channels as usize];
+            self.mix_tracks(buffer, output_channels);
+            dither.process(buffer, output_channels as usize, options.format.step());
+            options.format.write(&mut writer, buffer)?;
+        }
+        self.playhead_position = playhead_position;
+
+        writer.finalize()?;
+
+        Ok(())
+    }
+
+    fn mix<T>(&mut self, buffer: &mut [T], output_channels: u16)
+    where
+        T: FromF64Sample + Default + Clone + AddAssign,
+    {
+        let samples_per_frame = output_channels as usize;
+        let mut buffer = buffer;
+
+        // Render up to the loop end, then carry on from the loop start. Playback that starts
+        // before the loop runs into it the same way.
+        while !buffer.is_empty() {
+            let start = self.playhead_position;
+            let loop_end = self
+                .loop_range
+                .as_ref()
+                .filter(|range| start < range.end)
+                .map(|range| range.end);
+            let frames = loop_end.map_or(buffer.len() / samples_per_frame, |end| {
+                ((end - start) as usize).min(buffer.len() / samples_per_frame)
+            });
+            if frames == 0 {
+                break;
+            }
+
+            let (segment, rest) = buffer.split_at_mut(frames * samples_per_frame);
+            self.mix_tracks(segment, output_channels);
+            self.metronome
+                .process(segment, output_channels, start, &self.tempo_map);
+
+            if let Some(range) = &self.loop_range
+                && loop_end == Some(self.playhead_position)
+            {
+                self.playhead_position = range.start;
+            }
+            buffer = rest;
+        }
+    }
+
+    fn mix_tracks<T>(&mut self, buffer: &mut [T], output_channels: u16)
+    where
+        T: FromF64Sample + Default + Clone + AddAssign,
+    {
+        let samples_per_frame = output_channels as usize;
+        let num_frames = buffer.len() / samples_per_frame;
+
+        buffer.fill(T::default());
+
+        for frame_idx in 0..num_frames {
+            self.audible_tracks()
+                .flat_map(|track| {
+                    track
+                        .clips_at_playhead_position(self.playhead_position)
+                        .map(|(clip, gain)| (clip, (track.volume() as f64 * gain) as f32))
+                })
+                .for_each(|(clip, volume)| {
+                    clip.process_sample(
+                        buffer,
+                        volume,
+                        output_channels,
+                        self.playhead_position,
+                        frame_idx,
+                    )
+                });
+
+            self.playhead_position += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::{Clip, CorrectionTarget, ExportFormat, MusicalScale};
+
+    #[test]
+    fn test_record_multichannel_to_one_clip() -> Result<(), anyhow::Error> {
+        let mut timeline = Timeline::new(44100);
+        let track_id = timeline.new_track();
+
+        assert!(matches!(
+            timeline.start_recording(4),
+            Err(AudioError::NoArmedTrack)
+        ));
+
+        timeline.arm(track_id)?;
+        timeline.start_recording(4)?;
+        timeline.record(&[0.1f32, 0.2, 0.3, 0.4, 0.5, 0.6, 0.7, 0.8]);
+        timeline.stop_recording()?;
+
+        let track = timeline.get_track(track_id).unwrap();
+        assert_eq!(track.clip_count(), 1);
+        assert_eq!(track.clips()[0].channels(), 4);
+        assert_eq!(track.clips()[0].duration_in_samples(), 2);
+        assert!(!timeline.is_recording());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_track_channel_map_applies_to_matching_clips() -> Result<(), anyhow::Error> {
+        let mut timeline = Timeline::new(44100);
+        let track_id = timeline.new_track();
+
+        let mut channel_map = ChannelMap::new(4, 2);
+        channel_map.route(3, 0)?;
+        timeline.set_channel_map(track_id, channel_map.clone())?;
+
+        timeline.arm(track_id)?;
+        timeline.start_recording(4)?;
+        timeline.record(&[0.0f32, 0.0, 0.0, 0.5]);
+        timeline.stop_recording()?;
+
+        let mut buffer = vec![0.0f32; 2];
+        timeline.process(&mut buffer, 2);
+
+        let track = timeline.get_track(track_id).unwrap();
+        assert_eq!(track.clips()[0].channel_map(), Some(&channel_map));
+        assert_eq!(buffer, vec![0.5, 0.0]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_sample_rate_keeps_arrangement() -> Result<(), anyhow::Error> {
+        let mut timeline = Timeline::new(8000);
+        let track_id = timeline.new_track();
+        timeline.add_clip(track_id, "sample-i16-stereo.wav")?;
+        timeline.set_playhead_seconds(0.5);
+
+        let duration = timeline.duration_in_seconds();
+
+        timeline.set_sample_rate(44100)?;
+
+        assert_eq!(timeline.sample_rate(), 44100);
+        assert!((timeline.duration_in_seconds() - duration).abs() < 1.0 / 8000.0);
+        assert!((timeline.playhead_position_seconds() - 0.5).abs() < f64::EPSILON);
+
+        let clip = &timeline.get_track(track_id).unwrap().clips()[0];
+        assert_eq!(clip.sample_rate(), 44100);
+        assert_eq!(clip.source_sample_rate(), 8000);
+
+        assert!(matches!(
+            timeline.set_sample_rate(0),
+            Err(AudioError::InvalidSampleRate(0))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_repeated_rate_changes_keep_positions() -> Result<(), anyhow::Error> {
+        let mut timeline = Timeline::new(44100);
+        let track_id = timeline.new_track();
+        let track = timeline.get_mut_track(track_id).unwrap();
+        track.push_take(Clip::from_samples(vec![0.5; 3000], 1, 44100, 0))?;
+        track.push_take(Clip::from_samples(vec![0.25; 3000], 1, 44100, 0))?;
+        timeline.select_take(track_id, 0, 0, 1001, None)?;
+        timeline.select_take(track_id, 1, 1001, 2003, None)?;
+        timeline.set_loop_range(333, 2011, None)?;
+        timeline.set_punch_range(1001, 1999, None)?;
+        timeline.seek(1237)?;
+
+        for rate in [48000, 22050, 96000, 32000, 44100] {
+            timeline.set_sample_rate(rate)?;
+        }
+
+        let track = timeline.get_track(track_id).unwrap();
+        assert_eq!(
+            track
+                .comp()
+                .iter()
+                .map(|segment| (segment.take, segment.start, segment.end))
+                .collect::<Vec<_>>(),
+            [(0, 0, 1001), (1, 1001, 2003)]
+        );
+        assert_eq!(timeline.loop_range(), Some(333..2011));
+        assert_eq!(timeline.punch_range(), Some(1001..1999));
+        assert_eq!(timeline.playhead_position, 1237);
+
+        // Converted with the same rounding as the clips
+        timeline.set_sample_rate(48000)?;
+        let track = timeline.get_track(track_id).unwrap();
+        assert_eq!(
+            track.comp()[1].end,
+            Resampler::output_frames(2003, 48000, 44100) as u64
+        );
+
+        Ok(())
+    }
+
+    fn push_sine(timeline: &mut Timeline, track_id: TrackId, frequency: f64, seconds: f64) {
+        let sample_rate = timeline.sample_rate();
+        let data = (0..(seconds * sample_rate as f64) as usize)
+            .map(|i| {
+                0.5 * (2.0 * std::f64::consts::PI * frequency * i as f64 / sample_rate as f64).sin()
+            })
+            .collect();
+        let clip = Clip::from_samples(data, 1, sample_rate, 0);
+        timeline
+            .get_mut_track(track_id)
+            .unwrap()
+            .push_clip(clip)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_analyze_harmony() -> Result<(), anyhow::Error> {
+        let mut timeline = Timeline::new(44100);
+        let bass = timeline.new_track();
+        let tenor = timeline.new_track();
+        let muted = timeline.new_track();
+
+        push_sine(&mut timeline, bass, 196.0, 0.5);
+        push_sine(&mut timeline, tenor, 196.0 * 2f64.powf(425.0 / 1200.0), 0.5);
+        push_sine(&mut timeline, muted, 300.0, 0.5);
+        timeline.mute(muted)?;
+
+        let report = timeline.analyze_harmony(&HarmonyAnalyzer::default())?;
+
+        assert!(!report.segments.is_empty());
+        let segment = report
+            .segments
+            .iter()
+            .max_by(|a, b| {
+                (a.end_seconds - a.start_seconds).total_cmp(&(b.end_seconds - b.start_seconds))
+            })
+            .unwrap();
+        assert_eq!(segment.intervals.len(), 1);
+        assert_eq!(segment.intervals[0].lower, bass);
+        assert_eq!(segment.intervals[0].upper, tenor);
+        assert_eq!(segment.intervals[0].semitones, 4);
+        assert!((segment.intervals[0].cents_offset - 25.0).abs() < 3.0);
+        assert!(segment.is_drifting);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_pitch_correction_is_non_destructive() -> Result<(), anyhow::Error> {
+        let mut timeline = Timeline::new(44100);
+        let bass = timeline.new_track();
+        let tenor = timeline.new_track();
+
+        push_sine(&mut timeline, bass, 196.0, 0.5);
+        push_sine(&mut timeline, tenor, 196.0 * 2f64.powf(425.0 / 1200.0), 0.5);
+
+        let correction = PitchCorrection::new(CorrectionTarget::Scale {
+            root: 7,
+            scale: MusicalScale::Major,
+        });
+        timeline.set_pitch_correction(tenor, 0, correction)?;
+
+        let report = timeline.analyze_harmony(&HarmonyAnalyzer::default())?;
+        assert!(report.drifting_segments().next().is_none());
+
+        timeline.play();
+        let mut buffer = vec![0.0f32; 4410];
+        timeline.process(&mut buffer, 1);
+        assert!(buffer.iter().any(|sample| *sample != 0.0));
+
+        timeline.clear_pitch_correction(tenor, 0)?;
+        let report = timeline.analyze_harmony(&HarmonyAnalyzer::default())?;
+        assert!(report.drifting_segments().next().is_some());
+
+        assert!(matches!(
+            timeline.clear_pitch_correction(tenor, 1),
+            Err(AudioError::ClipNotFound(1))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_align_clip() -> Result<(), anyhow::Error> {
+        let mut timeline = Timeline::new(44100);
+        let lead = timeline.new_track();
+        let double = timeline.new_track();
+
+        push_sine(&mut timeline, lead, 220.0, 0.5);
+        let lead_clip = timeline
+            .get_mut_track(lead)
+            .unwrap()
+            .get_mut_clip(0)
+            .unwrap();
+        lead_clip.set_start_time_in_samples(8820);
+        let mut late = vec![0.0; 4410];
+        late.extend(lead_clip.mixdown());
+        let clip = Clip::from_samples(late, 1, 44100, 8820);
+        timeline.get_mut_track(double).unwrap().push_clip(clip)?;
+
+        let alignment = timeline.align_clip(double, 0, lead, 0, &TakeAligner::default())?;
+
+        assert!((alignment.offset_frames + 4410).abs() <= 256);
+        let clip = &timeline.get_track(double).unwrap().clips()[0];
+        assert_eq!(
+            clip.start_time_in_samples() as i64,
+            8820 + alignment.offset_frames
+        );
+        assert_eq!(clip.warp_markers(), alignment.warp_markers.as_slice());
+        assert!(matches!(
+            timeline.align_clip(double, 0, lead, 3, &TakeAligner::default()),
+            Err(AudioError::ClipNotFound(3))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_align_clip_keeps_stretch() -> Result<(), anyhow::Error> {
+        let mut timeline = Timeline::new(44100);
+        let lead = timeline.new_track();
+        let double = timeline.new_track();
+
+        push_sine(&mut timeline, lead, 220.0, 1.0);
+        push_sine(&mut timeline, double, 220.0, 0.5);
+        timeline.set_stretch(double, 0, 2.0)?;
+
+        let alignment = timeline.align_clip(double, 0, lead, 0, &TakeAligner::default())?;
+
+        let clip = &timeline.get_track(double).unwrap().clips()[0];
+        assert_eq!(clip.stretch(), 2.0);
+        assert!(!alignment.warp_markers.is_empty());
+        assert!(clip.duration_in_samples().abs_diff(44100) < 2205);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_stretch_extends_timeline() -> Result<(), anyhow::Error> {
+        let mut timeline = Timeline::new(44100);
+        let track_id = timeline.new_track();
+        push_sine(&mut timeline, track_id, 220.0, 0.5);
+
+        timeline.set_stretch(track_id, 0, 2.0)?;
+
+        assert_eq!(timeline.duration_in_samples(), 44100);
+        assert!(matches!(
+            timeline.set_stretch(track_id, 0, -1.0),
+            Err(AudioError::InvalidStretch(_))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_global_playback_rate() -> Result<(), anyhow::Error> {
+        let mut timeline = Timeline::new(44100);
+        let track_id = timeline.new_track();
+        push_sine(&mut timeline, track_id, 220.0, 0.9);
+
+        timeline.set_playback_rate(0.9)?;
+        assert_eq!(timeline.duration_in_samples(), 39690);
+        assert_eq!(timeline.playback_duration_in_samples(), 44100);
+
+        timeline.play();
+        let mut buffer = vec![0.0f32; 2 * 4410];
+        for _ in 0..5 {
+            timeline.process(&mut buffer, 2);
         }
-    }
+        let expected = 5.0 * 4410.0 * 0.9;
+        assert!((timeline.playhead_position as f64 - expected).abs() < 512.0);
+        assert!(buffer.iter().any(|sample| *sample != 0.0));
 
-    pub fn new_track(&mut self) -> TrackId {
-        let track_id = if let Some(track) = self.tracks.last() {
-            track.id + TrackId(1)
-        } else {
-            TrackId(1)
-        };
+        timeline.set_playback_rate(1.2)?;
+        assert!(matches!(
+            timeline.set_playback_rate(8.0),
+            Err(AudioError::InvalidPlaybackRate(_))
+        ));
+        assert_eq!(timeline.playback_rate(), 1.2);
 
-        let track = Track::new(track_id);
+        Ok(())
+    }
 
-        self.active_track_ids.insert(track.id);
-        self.tracks.push(track);
+    #[test]
+    fn test_varispeed_is_built_ahead_and_kept() -> Result<(), anyhow::Error> {
+        let mut timeline = Timeline::new(44100);
+        let track_id = timeline.new_track();
+        push_sine(&mut timeline, track_id, 220.0, 0.5);
+
+        timeline.set_playback_rate(0.8)?;
+        assert_eq!(
+            timeline.varispeed.as_ref().map(Varispeed::channels),
+            Some(2)
+        );
+
+        timeline.play();
+        let mut buffer = vec![0.0f32; 2 * 1024];
+        timeline.process(&mut buffer, 2);
+        timeline.seek(4410)?;
+        timeline.process(&mut buffer, 2);
+        timeline.stop()?;
+        timeline.process(&mut buffer, 2);
+        assert!(timeline.varispeed.is_some());
+
+        timeline.set_output_channels(1)?;
+        assert_eq!(
+            timeline.varispeed.as_ref().map(Varispeed::channels),
+            Some(1)
+        );
 
-        track_id
+        Ok(())
     }
 
-    pub fn add_clip<P: AsRef<Path>>(
-        &mut self,
-        track_id: TrackId,
-        path: P,
-    ) -> Result<(), AudioError> {
-        let timeline_sample_rate = self.sample_rate;
+    #[test]
+    fn test_clip_playback_rate() -> Result<(), anyhow::Error> {
+        let mut timeline = Timeline::new(44100);
+        let track_id = timeline.new_track();
+        push_sine(&mut timeline, track_id, 220.0, 0.9);
 
-        let track = self
-            .get_mut_track(track_id)
-            .ok_or(AudioError::TrackNotFound(track_id))?;
+        timeline.set_clip_playback_rate(track_id, 0, 0.9)?;
 
-        track.add_clip(path, timeline_sample_rate)?;
+        let clip = &timeline.get_track(track_id).unwrap().clips()[0];
+        assert_eq!(clip.duration_in_samples(), 44100);
+        assert_eq!(timeline.duration_in_samples(), 44100);
+        let pitch_track = clip.pitch_track()?;
+        let frequency = pitch_track
+            .iter()
+            .filter_map(|point| point.frequency)
+            .sum::<f64>()
+            / pitch_track.iter().filter(|p| p.is_voiced()).count() as f64;
+        assert!((frequency - 198.0).abs() < 1.0, "{frequency}");
 
         Ok(())
     }
 
-    pub fn set_volume(&mut self, track_id: TrackId, volume_percent: f32) -> Result<(), AudioError> {
-        let track = self
-            .get_mut_track(track_id)
-            .ok_or(AudioError::TrackNotFound(track_id))?;
-
-        track.set_volume_percent(volume_percent)?;
+    #[test]
+    fn test_move_and_split_on_grid() -> Result<(), anyhow::Error> {
+        let mut timeline = Timeline::new(48000);
+        let track_id = timeline.new_track();
+        push_sine(&mut timeline, track_id, 220.0, 3.0);
+
+        timeline.move_clip(track_id, 0, 50_000, Some(Grid::Bar))?;
+        assert_eq!(
+            timeline.get_track(track_id).unwrap().clips()[0].start_time_in_samples(),
+            96000
+        );
+
+        timeline.split_clip(track_id, 0, 130_000, Some(Grid::Beat))?;
+        let clips = timeline.get_track(track_id).unwrap().clips();
+        assert_eq!(clips.len(), 2);
+        assert_eq!(clips[0].duration_in_samples(), 24000);
+        assert_eq!(clips[1].start_time_in_samples(), 120_000);
+        assert_eq!(clips[1].end_time_in_samples(), 96000 + 144_000);
+
+        assert!(matches!(
+            timeline.split_clip(track_id, 0, 10, None),
+            Err(AudioError::InvalidSplitPosition(10))
+        ));
+
+        timeline.set_playhead_position(MusicalPosition::new(3, 2, 0));
+        assert_eq!(timeline.playhead_position_seconds(), 4.5);
+        assert_eq!(timeline.playhead_position(), MusicalPosition::new(3, 2, 0));
 
         Ok(())
     }
 
-    pub fn set_track_name(
-        &mut self,
-        track_id: TrackId,
-        new_name: String,
-    ) -> Result<(), AudioError> {
-        let track = self
-            .get_mut_track(track_id)
-            .ok_or(AudioError::TrackNotFound(track_id))?;
+    #[test]
+    fn test_metronome_and_count_in() -> Result<(), anyhow::Error> {
+        let mut timeline = Timeline::new(48000);
+        let track_id = timeline.new_track();
+        timeline.metronome_mut().enabled = true;
+        timeline.metronome_mut().count_in_bars = 1;
+
+        // One bar of 4/4 at 120 bpm is 96000 frames
+        timeline.arm(track_id)?;
+        timeline.start_recording(1)?;
+        assert!(timeline.is_counting_in());
+
+        let mut buffer = vec![0.0f32; 90000];
+        timeline.process(&mut buffer, 1);
+        timeline.record(&[0.5f32; 100]);
+        assert!(timeline.is_counting_in());
+        assert_eq!(timeline.playhead_position_seconds(), 0.0);
+        assert!(buffer[..100].iter().any(|sample| *sample != 0.0));
+
+        // The count-in ends part way through, the rest of the buffer is the timeline
+        let mut buffer = vec![0.0f32; 10000];
+        timeline.process(&mut buffer, 1);
+        assert!(!timeline.is_counting_in());
+        assert_eq!(timeline.playhead_position_seconds(), 4000.0 / 48000.0);
+        assert!(buffer[6000..6100].iter().any(|sample| *sample != 0.0));
+
+        timeline.record(&[0.5f32; 100]);
+        timeline.stop_recording()?;
+        let clip = &timeline.get_track(track_id).unwrap().clips()[0];
+        assert_eq!(clip.duration_in_samples(), 100);
+        assert_eq!(clip.start_time_in_samples(), 0);
 
-        track.name = new_name;
+        Ok(())
+    }
+
+    #[test]
+    fn test_export_excludes_click() -> Result<(), anyhow::Error> {
+        let mut timeline = Timeline::new(8000);
+        let track_id = timeline.new_track();
+        let clip = Clip::from_samples(vec![0.25; 8000], 1, 8000, 0);
+        timeline.get_mut_track(track_id).unwrap().push_clip(clip)?;
+        timeline.metronome_mut().enabled = true;
+
+        timeline.play();
+        let mut buffer = vec![0.0f32; 100];
+        timeline.process(&mut buffer, 1);
+        assert!(buffer.iter().any(|sample| *sample != 0.25));
+
+        let path = std::env::temp_dir().join("zari-test-export-excludes-click.wav");
+        timeline.export(&path, 2, ExportOptions::default())?;
+        let samples: Vec<f32> = hound::WavReader::open(&path)?
+            .into_samples()
+            .collect::<Result<_, _>>()?;
+        std::fs::remove_file(&path)?;
+
+        assert_eq!(samples.len(), 16000);
+        assert!(samples.iter().all(|sample| *sample == 0.25));
+        assert_eq!(timeline.playhead_position_seconds(), 100.0 / 8000.0);
 
         Ok(())
     }
 
-    pub fn mute(&mut self, track_id: TrackId) -> Result<(), AudioError> {
-        let track = self
-            .get_mut_track(track_id)
-            .ok_or(AudioError::TrackNotFound(track_id))?;
+    #[test]
+    fn test_dither_quiet_fade_to_16_bit() -> Result<(), anyhow::Error> {
+        let mut timeline = Timeline::new(8000);
+        let track_id = timeline.new_track();
+        // Fades out from a third of a 16-bit step
+        let fade = (0..8000)
+            .map(|i| (8000 - i) as f64 / 8000.0 / 3.0 / i16::MAX as f64)
+            .collect();
+        let clip = Clip::from_samples(fade, 1, 8000, 0);
+        timeline.get_mut_track(track_id).unwrap().push_clip(clip)?;
+
+        let export = |timeline: &mut Timeline, dither| -> Result<Vec<i16>, anyhow::Error> {
+            let path = std::env::temp_dir().join(format!("zari-test-dither-{dither:?}.wav"));
+            let options = ExportOptions {
+                format: ExportFormat::Int16,
+                dither,
+            };
+            timeline.export(&path, 1, options)?;
+            let mut reader = hound::WavReader::open(&path)?;
+            assert_eq!(reader.spec().bits_per_sample, 16);
+            let samples = reader.samples().collect::<Result<_, _>>()?;
+            std::fs::remove_file(&path)?;
+            Ok(samples)
+        };
 
-        if !track.is_muted() {
-            track.mute();
-            track.unsolo();
-            self.active_track_ids.remove(&track_id);
+        assert!(
+            export(&mut timeline, DitherMode::Off)?
+                .iter()
+                .all(|s| *s == 0)
+        );
+        for dither in [DitherMode::Tpdf, DitherMode::NoiseShaped] {
+            let samples = export(&mut timeline, dither)?;
+            assert_eq!(samples.len(), 8000);
+            assert!(samples.iter().any(|s| *s != 0));
+            assert!(samples.iter().all(|s| s.abs() <= 2));
         }
 
+        // Live output dithers only when it is an integer format
+        timeline.set_dither_mode(DitherMode::Tpdf);
+        timeline.play();
+        let mut output = vec![0i16; 4000];
+        timeline.process(&mut output, 1);
+        assert!(output.iter().any(|s| *s != 0));
+        timeline.seek(0)?;
+        let mut output = vec![0.0f32; 4000];
+        timeline.process(&mut output, 1);
+        assert!(output.iter().all(|s| s.abs() < 1.0 / i16::MAX as f32));
+
         Ok(())
     }
 
-    pub fn unmute(&mut self, track_id: TrackId) -> Result<(), AudioError> {
-        let track = self
-            .get_mut_track(track_id)
-            .ok_or(AudioError::TrackNotFound(track_id))?;
+    #[test]
+    fn test_monitor_follows_channel_map() -> Result<(), anyhow::Error> {
+        let mut timeline = Timeline::new(48000);
+        let track_id = timeline.new_track();
+        timeline.set_monitor_mode(track_id, MonitorMode::Always)?;
+        timeline.arm(track_id)?;
+        timeline.start_monitoring(1);
+
+        let monitored = |timeline: &mut Timeline| {
+            timeline.record(&[0.5f32; 64]);
+            let mut buffer = vec![0.0f32; 128];
+            timeline.process(&mut buffer, 2);
+            [buffer[0], buffer[1]]
+        };
+        let panned = monitored(&mut timeline);
+        assert!(panned[0] > 0.0 && panned[0] == panned[1]);
 
-        if track.is_muted() {
-            track.unmute();
-            self.active_track_ids.insert(track_id);
-        }
+        let mut channel_map = ChannelMap::new(1, 2);
+        channel_map.route(0, 1)?;
+        timeline.set_channel_map(track_id, channel_map)?;
+        assert_eq!(monitored(&mut timeline), [0.0, 0.5]);
+
+        timeline.set_output_channels(1)?;
+        timeline.record(&[0.5f32; 64]);
+        let mut buffer = vec![0.0f32; 64];
+        timeline.process(&mut buffer, 1);
+        assert!(buffer[0] > 0.0);
 
         Ok(())
     }
 
-    pub fn is_muted(&self, track_id: TrackId) -> Result<bool, AudioError> {
-        let track = self
-            .get_track(track_id)
-            .ok_or(AudioError::TrackNotFound(track_id))?;
+    #[test]
+    fn test_input_monitoring_modes() -> Result<(), anyhow::Error> {
+        let mut timeline = Timeline::new(48000);
+        let track_id = timeline.new_track();
+        let clip = Clip::from_samples(vec![0.0; 48000], 1, 48000, 0);
+        timeline.get_mut_track(track_id).unwrap().push_clip(clip)?;
+        timeline.arm(track_id)?;
+        timeline.start_monitoring(1);
+
+        let monitored = |timeline: &mut Timeline| {
+            timeline.record(&[0.5f32; 64]);
+            let mut buffer = vec![0.0f32; 128];
+            timeline.process(&mut buffer, 2);
+            buffer[0]
+        };
 
-        Ok(track.is_muted())
+        assert_eq!(monitored(&mut timeline), 0.0);
+
+        timeline.set_monitor_mode(track_id, MonitorMode::Auto)?;
+        assert_eq!(timeline.monitored_track_id(), Some(track_id));
+        assert_eq!(monitored(&mut timeline), 0.5);
+        // Playback of the track takes over while not recording
+        timeline.play();
+        assert_eq!(monitored(&mut timeline), 0.0);
+        timeline.start_recording(1)?;
+        assert_eq!(monitored(&mut timeline), 0.5);
+        timeline.stop_recording()?;
+
+        timeline.set_monitor_mode(track_id, MonitorMode::Always)?;
+        assert_eq!(monitored(&mut timeline), 0.5);
+        timeline.mute(track_id)?;
+        assert_eq!(monitored(&mut timeline), 0.0);
+        timeline.unmute(track_id)?;
+
+        timeline.disarm();
+        assert_eq!(timeline.monitored_track_id(), None);
+        timeline.stop_monitoring();
+        timeline.record(&[0.5f32; 64]);
+        let mut buffer = vec![0.0f32; 128];
+        timeline.process(&mut buffer, 2);
+        assert!(buffer.iter().all(|sample| *sample == 0.0));
+
+        Ok(())
     }
 
-    pub fn solo(&mut self, track_id: TrackId) -> Result<(), AudioError> {
-        let track = self
-            .get_mut_track(track_id)
-            .ok_or(AudioError::TrackNotFound(track_id))?;
+    #[test]
+    fn test_loop_wraps_sample_accurately() -> Result<(), anyhow::Error> {
+        let mut timeline = Timeline::new(48000);
+        let track_id = timeline.new_track();
+        let data = (0..200).map(|i| i as f64 / 1000.0).collect();
+        let clip = Clip::from_samples(data, 1, 48000, 0);
+        timeline.get_mut_track(track_id).unwrap().push_clip(clip)?;
+
+        assert!(matches!(
+            timeline.set_loop_range(100, 100, None),
+            Err(AudioError::InvalidLoopRange { .. })
+        ));
+        timeline.set_loop_range(100, 150, None)?;
+        timeline.set_playhead_seconds(140.0 / 48000.0);
+
+        timeline.play();
+        let mut buffer = vec![0.0f64; 70];
+        timeline.process(&mut buffer, 1);
+
+        let positions: Vec<usize> = buffer
+            .iter()
+            .map(|sample| (sample * 1000.0).round() as usize)
+            .collect();
+        let expected: Vec<usize> = (140..150).chain(100..150).chain(100..110).collect();
+        assert_eq!(positions, expected);
+        assert_eq!(timeline.playhead_position_seconds(), 110.0 / 48000.0);
 
-        if !track.is_soloed() {
-            track.solo();
-            self.tracks
-                .iter_mut()
-                .filter(|t| t.id != track_id)
-                .for_each(|track| {
-                    track.unsolo();
-                    track.mute();
-                });
-            self.active_track_ids.clear();
-            self.active_track_ids.insert(track_id);
-        }
+        // Entering the loop from before its start
+        timeline.set_playhead_seconds(90.0 / 48000.0);
+        timeline.process(&mut buffer, 1);
+
+        let positions: Vec<usize> = buffer
+            .iter()
+            .map(|sample| (sample * 1000.0).round() as usize)
+            .collect();
+        let expected: Vec<usize> = (90..150).chain(100..110).collect();
+        assert_eq!(positions, expected);
+        assert_eq!(timeline.playhead_position_seconds(), 110.0 / 48000.0);
+
+        // Snapped to beats at 120 bpm
+        timeline.set_loop_range(20000, 50000, Some(Grid::Beat))?;
+        assert_eq!(timeline.loop_range(), Some(24000..48000));
 
         Ok(())
     }
 
-    pub fn unsolo(&mut self, track_id: TrackId) -> Result<(), AudioError> {
-        let track = self
-            .get_mut_track(track_id)
-            .ok_or(AudioError::TrackNotFound(track_id))?;
+    #[test]
+    fn test_cycle_recording_creates_takes() -> Result<(), anyhow::Error> {
+        let mut timeline = Timeline::new(48000);
+        let track_id = timeline.new_track();
+        timeline.set_loop_range(1000, 2000, None)?;
+        timeline.set_playhead_seconds(1000.0 / 48000.0);
 
-        if track.is_soloed() {
-            track.unsolo();
-            if track.is_muted() {
-                self.active_track_ids.remove(&track_id);
-            }
+        timeline.arm(track_id)?;
+        timeline.start_recording(1)?;
+        for pass in 1..=3 {
+            timeline.record(&vec![pass as f32 / 10.0; 1000]);
         }
+        timeline.stop_recording()?;
+
+        let track = timeline.get_track(track_id).unwrap();
+        assert_eq!(track.clip_count(), 0);
+        assert_eq!(track.take_count(), 3);
+        assert!(
+            track
+                .takes()
+                .iter()
+                .all(|take| take.start_time_in_samples() == 1000)
+        );
+
+        // The latest pass is the one that plays
+        timeline.set_playhead_seconds(1500.0 / 48000.0);
+        let mut buffer = vec![0.0f32; 1];
+        timeline.process(&mut buffer, 1);
+        assert!((buffer[0] - 0.3).abs() < 1e-6);
 
         Ok(())
     }
 
-    pub fn is_soloed(&self, track_id: TrackId) -> Result<bool, AudioError> {
-        let track = self
-            .get_track(track_id)
-            .ok_or(AudioError::TrackNotFound(track_id))?;
+    #[test]
+    fn test_comp_switches_takes_with_crossfade() -> Result<(), anyhow::Error> {
+        let mut timeline = Timeline::new(48000);
+        let track_id = timeline.new_track();
+        timeline.set_loop_range(0, 4800, None)?;
+
+        timeline.arm(track_id)?;
+        timeline.start_recording(1)?;
+        timeline.record(&[0.1f32; 4800]);
+        timeline.record(&[0.2f32; 4800]);
+        timeline.stop_recording()?;
+        timeline.clear_loop_range()?;
+
+        assert!(matches!(
+            timeline.select_take(track_id, 2, 0, 2400, None),
+            Err(AudioError::TakeNotFound(2))
+        ));
+        timeline.select_take(track_id, 0, 0, 2400, None)?;
+        timeline.select_take(track_id, 1, 2400, 4800, None)?;
+
+        let mut buffer = vec![0.0f32; 4800];
+        timeline.process(&mut buffer, 1);
+
+        // 10 ms crossfade centred on the switch, and a fade at either end
+        assert_eq!(buffer[0], 0.0);
+        assert!((buffer[1000] - 0.1).abs() < 1e-6);
+        assert!((buffer[3000] - 0.2).abs() < 1e-6);
+        let midpoint =
+            0.1 * std::f32::consts::FRAC_1_SQRT_2 + 0.2 * std::f32::consts::FRAC_1_SQRT_2;
+        assert!((buffer[2400] - midpoint).abs() < 1e-6);
+        assert!(buffer[2160..2640].iter().all(|sample| *sample > 0.09));
+        assert!(buffer[4799] < 0.01);
+
+        // Clearing the comp goes back to the newest take
+        timeline.clear_comp(track_id)?;
+        timeline.reset_playhead();
+        timeline.process(&mut buffer, 1);
+        assert!(buffer.iter().all(|sample| (sample - 0.2).abs() < 1e-6));
 
-        Ok(track.is_soloed())
+        Ok(())
     }
 
-    pub fn track_count(&self) -> usize {
-        self.tracks.len()
-    }
+    #[test]
+    fn test_comp_falls_back_where_the_take_is_silent() -> Result<(), anyhow::Error> {
+        let mut timeline = Timeline::new(1000);
+        let track_id = timeline.new_track();
+        timeline.set_comp_crossfade_seconds(0.0);
+        let track = timeline.get_mut_track(track_id).unwrap();
+        track.push_clip(Clip::from_samples(vec![0.25; 1000], 1, 1000, 0))?;
+        track.push_take(Clip::from_samples(vec![0.75; 400], 1, 1000, 0))?;
+        timeline.select_take(track_id, 0, 0, 1000, None)?;
+
+        timeline.play();
+        let mut buffer = vec![0.0f32; 1000];
+        timeline.process(&mut buffer, 1);
+
+        assert!(buffer[100..400].iter().all(|sample| *sample == 0.75));
+        assert!(buffer[400..].iter().all(|sample| *sample == 0.25));
+
+        // The comp keeps its place through a rate change
+        timeline.set_sample_rate(2000)?;
+        let track = timeline.get_track(track_id).unwrap();
+        let (take, _) = track.clips_at_playhead_position(700).next().unwrap();
+        assert_eq!(take.end_time_in_samples(), 800);
+        let (clip, _) = track.clips_at_playhead_position(900).next().unwrap();
+        assert_eq!(clip.end_time_in_samples(), 2000);
 
-    pub fn get_track_ids(&self) -> Vec<TrackId> {
-        self.tracks.iter().map(|t| t.id).collect()
+        Ok(())
     }
 
-    pub fn duration_in_samples(&self) -> u64 {
-        self.tracks
-            .iter()
-            .map(|track| track.duration_in_samples())
-            .max()
-            .unwrap_or(0)
+    #[test]
+    fn test_start_recording_keeps_the_take_in_progress() -> Result<(), anyhow::Error> {
+        let mut timeline = Timeline::new(1000);
+        let track_id = timeline.new_track();
+        timeline.arm(track_id)?;
+        timeline.start_recording(1)?;
+        timeline.record(&[0.5f32; 100]);
+
+        assert!(matches!(
+            timeline.start_recording(1),
+            Err(AudioError::RecordingInProgress)
+        ));
+        timeline.record(&[0.5f32; 100]);
+        timeline.stop_recording()?;
+
+        let track = timeline.get_track(track_id).unwrap();
+        assert_eq!(track.clip_count(), 1);
+        assert_eq!(track.clips()[0].duration_in_samples(), 200);
+
+        Ok(())
     }
 
-    pub fn duration_in_seconds(&self) -> f64 {
-        self.duration_in_samples() as f64 / self.sample_rate as f64
+    #[test]
+    fn test_stop_command_leaves_takes_to_the_caller() -> Result<(), anyhow::Error> {
+        let mut timeline = Timeline::new(1000);
+        let track_id = timeline.new_track();
+        timeline.arm(track_id)?;
+        timeline.start_recording(1)?;
+        timeline.record(&[0.5f32; 100]);
+
+        timeline.transport_sender().send(TransportCommand::Stop)?;
+        let mut buffer = vec![0.0f32; 100];
+        timeline.process(&mut buffer, 1);
+        timeline.record(&[0.5f32; 100]);
+
+        assert!(!timeline.is_recording() && timeline.has_stopped_recording());
+        assert_eq!(timeline.get_track(track_id).unwrap().clip_count(), 0);
+
+        timeline.stop_recording()?;
+        let track = timeline.get_track(track_id).unwrap();
+        assert_eq!(track.clip_count(), 1);
+        assert_eq!(track.clips()[0].duration_in_samples(), 100);
+        assert!(!timeline.has_stopped_recording());
+
+        Ok(())
     }
 
-    pub fn playhead_position_seconds(&self) -> f64 {
-        self.playhead_position as f64 / self.sample_rate as f64
+    #[test]
+    fn test_punch_recording_replaces_range() -> Result<(), anyhow::Error> {
+        let mut timeline = Timeline::new(1000);
+        let track_id = timeline.new_track();
+        let clip = Clip::from_samples(vec![0.25; 1000], 1, 1000, 0);
+        timeline.get_mut_track(track_id).unwrap().push_clip(clip)?;
+
+        assert!(matches!(
+            timeline.set_punch_range(600, 400, None),
+            Err(AudioError::InvalidPunchRange {
+                start: 600,
+                end: 400
+            })
+        ));
+        timeline.set_punch_range(400, 600, None)?;
+        timeline.set_pre_roll_seconds(0.1);
+
+        // Playback starts from the pre-roll, the input stops being taken after the punch out
+        // and the take is made once the recording is stopped
+        timeline.arm(track_id)?;
+        timeline.start_recording(1)?;
+        assert_eq!(timeline.playhead_position_seconds(), 0.3);
+        for _ in 0..4 {
+            assert!(timeline.is_recording());
+            timeline.record(&[0.75f32; 100]);
+        }
+        assert!(!timeline.is_recording() && timeline.has_stopped_recording());
+        assert_eq!(timeline.transport_state(), TransportState::Playing);
+        assert_eq!(timeline.get_track(track_id).unwrap().take_count(), 0);
+        timeline.stop_recording()?;
+
+        let track = timeline.get_track(track_id).unwrap();
+        assert_eq!(track.clip_count(), 1);
+        assert_eq!(track.take_count(), 1);
+        // 5 ms handles either side for the crossfades
+        assert_eq!(track.takes()[0].start_time_in_samples(), 395);
+        assert_eq!(track.takes()[0].duration_in_samples(), 210);
+
+        timeline.reset_playhead();
+        let mut buffer = vec![0.0f32; 1000];
+        timeline.process(&mut buffer, 1);
+
+        let midpoint = (0.75 + 0.25) * std::f32::consts::FRAC_1_SQRT_2;
+        assert_eq!(buffer[300], 0.25);
+        assert_eq!(buffer[395], 0.25);
+        assert!((buffer[400] - midpoint).abs() < 1e-6);
+        assert_eq!(buffer[500], 0.75);
+        assert!((buffer[600] - midpoint).abs() < 1e-6);
+        assert_eq!(buffer[605], 0.25);
+        assert!(buffer[390..610].iter().all(|sample| *sample >= 0.25));
+
+        Ok(())
     }
 
-    pub fn set_playhead_seconds(&mut self, seconds: f64) {
-        self.playhead_position = (seconds * self.sample_rate as f64) as u64;
+    #[test]
+    fn test_punch_over_comp_is_selected() -> Result<(), anyhow::Error> {
+        let mut timeline = Timeline::new(1000);
+        let track_id = timeline.new_track();
+        timeline.set_comp_crossfade_seconds(0.01);
+        let track = timeline.get_mut_track(track_id).unwrap();
+        track.push_clip(Clip::from_samples(vec![0.25; 1000], 1, 1000, 0))?;
+        track.push_take(Clip::from_samples(vec![0.5; 1000], 1, 1000, 0))?;
+        timeline.select_take(track_id, 0, 0, 1000, None)?;
+
+        timeline.set_punch_range(400, 600, None)?;
+        timeline.set_pre_roll_seconds(0.0);
+        timeline.arm(track_id)?;
+        timeline.start_recording(1)?;
+        timeline.record(&[0.75f32; 300]);
+        timeline.stop_recording()?;
+
+        let track = timeline.get_track(track_id).unwrap();
+        assert_eq!(
+            track
+                .comp()
+                .iter()
+                .map(|segment| (segment.take, segment.start, segment.end))
+                .collect::<Vec<_>>(),
+            [(0, 0, 400), (1, 400, 600), (0, 600, 1000)]
+        );
+
+        timeline.clear_punch_range()?;
+        timeline.reset_playhead();
+        timeline.play();
+        let mut buffer = vec![0.0f32; 1000];
+        timeline.process(&mut buffer, 1);
+
+        let midpoint = (0.75 + 0.5) * std::f32::consts::FRAC_1_SQRT_2;
+        assert_eq!(buffer[300], 0.5);
+        assert!((buffer[400] - midpoint).abs() < 1e-6);
+        assert_eq!(buffer[500], 0.75);
+        assert!((buffer[600] - midpoint).abs() < 1e-6);
+        assert_eq!(buffer[700], 0.5);
+
+        Ok(())
     }
 
-    pub fn reset_playhead(&mut self) {
-        self.playhead_position = 0;
+    #[test]
+    fn test_punch_shorter_than_crossfade_is_selected() -> Result<(), anyhow::Error> {
+        let mut timeline = Timeline::new(1000);
+        let track_id = timeline.new_track();
+        timeline.set_comp_crossfade_seconds(0.02);
+        let track = timeline.get_mut_track(track_id).unwrap();
+        track.push_take(Clip::from_samples(vec![0.5; 1000], 1, 1000, 0))?;
+        timeline.select_take(track_id, 0, 0, 1000, None)?;
+
+        // The handle before the punch in is cut short by the start of the timeline
+        timeline.set_punch_range(3, 8, None)?;
+        timeline.set_pre_roll_seconds(0.0);
+        timeline.arm(track_id)?;
+        timeline.start_recording(1)?;
+        timeline.record(&[0.75f32; 100]);
+        timeline.stop_recording()?;
+
+        let track = timeline.get_track(track_id).unwrap();
+        assert_eq!(
+            track
+                .comp()
+                .iter()
+                .map(|segment| (segment.take, segment.start, segment.end))
+                .collect::<Vec<_>>(),
+            [(0, 0, 3), (1, 3, 8), (0, 8, 1000)]
+        );
+
+        timeline.clear_punch_range()?;
+        timeline.reset_playhead();
+        timeline.play();
+        let mut buffer = vec![0.0f32; 1000];
+        timeline.process(&mut buffer, 1);
+
+        assert!(buffer[5] > 0.5);
+        assert_eq!(buffer[300], 0.5);
+
+        Ok(())
     }
 
-    pub fn get_mut_track(&mut self, track_id: TrackId) -> Option<&mut Track> {
-        self.tracks.iter_mut().find(|t| t.id == track_id)
+    #[test]
+    fn test_only_punch_takes_crossfade_over_clips() -> Result<(), anyhow::Error> {
+        let mut timeline = Timeline::new(1000);
+        let track_id = timeline.new_track();
+        timeline.set_comp_crossfade_seconds(0.01);
+        let track = timeline.get_mut_track(track_id).unwrap();
+        track.push_clip(Clip::from_samples(vec![0.25; 1000], 1, 1000, 0))?;
+        track.push_take(Clip::from_samples(vec![0.75; 200], 1, 1000, 400))?;
+
+        timeline.play();
+        let mut buffer = vec![0.0f32; 1000];
+        timeline.process(&mut buffer, 1);
+
+        assert_eq!(buffer[399], 0.25);
+        assert_eq!(buffer[400], 0.75);
+        assert_eq!(buffer[599], 0.75);
+        assert_eq!(buffer[600], 0.25);
+
+        Ok(())
     }
 
-    pub fn get_track(&self, track_id: TrackId) -> Option<&Track> {
-        self.tracks.iter().find(|t| t.id == track_id)
+    #[test]
+    fn test_clips_fill_in_under_comp_edges() -> Result<(), anyhow::Error> {
+        let mut timeline = Timeline::new(1000);
+        let track_id = timeline.new_track();
+        timeline.set_comp_crossfade_seconds(0.1);
+        let track = timeline.get_mut_track(track_id).unwrap();
+        track.push_clip(Clip::from_samples(vec![0.25; 1000], 1, 1000, 0))?;
+        track.push_take(Clip::from_samples(vec![0.75; 1000], 1, 1000, 0))?;
+        timeline.select_take(track_id, 0, 400, 600, None)?;
+
+        timeline.play();
+        let mut buffer = vec![0.0f32; 1000];
+        timeline.process(&mut buffer, 1);
+
+        let halfway = (0.75 + 0.25) * std::f32::consts::FRAC_1_SQRT_2;
+        assert_eq!(buffer[400], 0.25);
+        assert!((buffer[450] - halfway).abs() < 1e-6);
+        assert_eq!(buffer[500], 0.75);
+        assert_eq!(buffer[700], 0.25);
+
+        Ok(())
     }
 
-    pub fn process<T>(&mut self, buffer: &mut [T], output_channels: u16)
-    where
-        T: FromF64Sample + Default + Clone + AddAssign,
-    {
-        let samples_per_frame = output_channels as usize;
-        let num_frames = buffer.len() / samples_per_frame;
+    #[test]
+    fn test_transport_pause_resume_and_end() -> Result<(), anyhow::Error> {
+        let mut timeline = Timeline::new(48000);
+        let track_id = timeline.new_track();
+        let clip = Clip::from_samples(vec![0.5; 4800], 1, 48000, 0);
+        timeline.get_mut_track(track_id).unwrap().push_clip(clip)?;
+        let events = timeline.subscribe();
+
+        // Nothing plays until the transport rolls
+        let mut buffer = vec![0.0f32; 1000];
+        timeline.process(&mut buffer, 1);
+        assert!(buffer.iter().all(|sample| *sample == 0.0));
+        assert_eq!(timeline.playhead_position_seconds(), 0.0);
+
+        timeline.play();
+        timeline.process(&mut buffer, 1);
+        assert!(buffer.iter().all(|sample| *sample == 0.5));
+
+        // Pausing fades out over 5 ms and leaves the playhead where it was
+        timeline.pause()?;
+        timeline.process(&mut buffer, 1);
+        assert!(buffer[..240].windows(2).all(|pair| pair[1] <= pair[0]));
+        assert!(buffer[239..].iter().all(|sample| *sample == 0.0));
+        assert_eq!(timeline.playhead_position_seconds(), 1000.0 / 48000.0);
+
+        timeline.play();
+        timeline.process(&mut buffer, 1);
+        assert_eq!(buffer[0], 0.0);
+        assert!(buffer[..240].windows(2).all(|pair| pair[1] >= pair[0]));
+        assert!(buffer[240..].iter().all(|sample| *sample == 0.5));
+
+        // Playback stops exactly at the end of the last clip
+        let mut buffer = vec![0.0f32; 4000];
+        timeline.process(&mut buffer, 1);
+        assert!(buffer[..2800].iter().all(|sample| *sample == 0.5));
+        assert!(buffer[2800..].iter().all(|sample| *sample == 0.0));
+        assert_eq!(timeline.transport_state(), TransportState::Stopped);
+
+        assert_eq!(
+            events
+                .try_iter()
+                .filter(|event| !matches!(event, TransportEvent::Position(_)))
+                .collect::<Vec<_>>(),
+            [
+                TransportEvent::StateChanged(TransportState::Playing),
+                TransportEvent::StateChanged(TransportState::Paused),
+                TransportEvent::StateChanged(TransportState::Playing),
+                TransportEvent::ReachedEnd,
+                TransportEvent::StateChanged(TransportState::Stopped),
+            ]
+        );
 
-        buffer.fill(T::default());
+        Ok(())
+    }
 
-        for frame_idx in 0..num_frames {
-            let soloed_track = self.tracks.iter().find(|t| t.is_soloed());
+    #[test]
+    fn test_transport_seek_from_another_thread() -> Result<(), anyhow::Error> {
+        let mut timeline = Timeline::new(48000);
+        let track_id = timeline.new_track();
+        let data = (0..48000).map(|i| i as f64 / 48000.0).collect();
+        let clip = Clip::from_samples(data, 1, 48000, 0);
+        timeline.get_mut_track(track_id).unwrap().push_clip(clip)?;
+
+        let transport = timeline.transport_sender();
+        transport.send(TransportCommand::Play)?;
+        let mut buffer = vec![0.0f64; 1000];
+        timeline.process(&mut buffer, 1);
+
+        std::thread::spawn(move || transport.send(TransportCommand::Seek(20000)))
+            .join()
+            .unwrap()?;
+        timeline.process(&mut buffer, 1);
+
+        // The old position fades out, then the new one fades in from the exact frame
+        let declick = 240;
+        assert!((buffer[0] - 1000.0 / 48000.0).abs() < 1e-3);
+        assert!(buffer[declick - 1].abs() < 1e-9);
+        assert_eq!(buffer[declick], 0.0);
+        let expected = (20000 + 500) as f64 / 48000.0;
+        assert!((buffer[declick + 500] - expected).abs() < 1e-12);
+        assert_eq!(
+            timeline.playhead_position_seconds(),
+            (20000 + 1000 - declick) as f64 / 48000.0
+        );
+
+        timeline.arm(track_id)?;
+        timeline.start_recording(1)?;
+        assert!(matches!(
+            timeline.seek(0),
+            Err(AudioError::RecordingInProgress)
+        ));
+        timeline.stop()?;
+        assert_eq!(timeline.transport_state(), TransportState::Stopped);
+        assert_eq!(timeline.playhead_position_seconds(), 0.0);
 
-            self.tracks
-                .iter()
-                .filter(|track| {
-                    if let Some(soloed_track) = soloed_track {
-                        track.id == soloed_track.id
-                    } else {
-                        !track.is_muted()
-                    }
-                })
-                .filter_map(|track| {
-                    track
-                        .find_clip_at_playhead_position(self.playhead_position)
-                        .map(|clip| (clip, track.volume()))
-                })
-                .for_each(|(clip, volume)| {
-                    clip.process_sample(
-                        buffer,
-                        volume,
-                        output_channels,
-                        self.playhead_position,
-                        frame_idx,
-                    )
-                });
+        Ok(())
+    }
 
-            self.playhead_position += 1;
+    #[test]
+    fn test_loop_at_end_and_recording_events() -> Result<(), anyhow::Error> {
+        let mut timeline = Timeline::new(48000);
+        let track_id = timeline.new_track();
+        let clip = Clip::from_samples(vec![0.5; 1000], 1, 48000, 0);
+        timeline.get_mut_track(track_id).unwrap().push_clip(clip)?;
+        let events = timeline.subscribe();
+
+        timeline.set_end_action(EndAction::Loop);
+        timeline.play();
+        let mut buffer = vec![0.0f32; 2500];
+        timeline.process(&mut buffer, 1);
+
+        let declick = timeline.declick_frames() as usize;
+        assert!(buffer[..1000].iter().all(|sample| *sample == 0.5));
+        for pass in buffer[1000..].chunks(1000) {
+            assert_eq!(pass[0], 0.0);
+            assert!(pass[..declick].windows(2).all(|pair| pair[0] < pair[1]));
+            assert!(pass[declick..].iter().all(|sample| *sample == 0.5));
         }
-    }
-}
+        assert_eq!(timeline.transport_state(), TransportState::Playing);
+        assert_eq!(timeline.playhead_position_seconds(), 500.0 / 48000.0);
+
+        timeline.arm(track_id)?;
+        timeline.start_recording(1)?;
+        timeline.stop_recording()?;
+
+        assert_eq!(
+            events.try_iter().collect::<Vec<_>>(),
+            [
+                TransportEvent::StateChanged(TransportState::Playing),
+                TransportEvent::ReachedEnd,
+                TransportEvent::ReachedEnd,
+                TransportEvent::Position(500),
+                TransportEvent::StateChanged(TransportState::Recording),
+                TransportEvent::RecordingStarted(track_id),
+                TransportEvent::StateChanged(TransportState::Playing),
+                TransportEvent::RecordingStopped(track_id),
+            ]
+        );
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+        Ok(())
+    }
 
     #[test]
     fn test_toggle_mute() -> Result<(), anyhow::Error> {
@@ -460,6 +2660,7 @@ mod tests {
         Ok(())
     }
 
+    #[test]
     fn test_with_solo_track_add_new_track() -> Result<(), anyhow::Error> {
         let mut timeline = Timeline::new(44100);
         let track_id = timeline.new_track();
@@ -467,8 +2668,10 @@ mod tests {
 
         let new_track_id = timeline.new_track();
 
-        // New track should be muted, as we have soloed track 
-        assert!(timeline.is_muted(track_id)?);
+        // New track should be muted, as we have soloed track
+        assert!(timeline.is_muted(new_track_id)?);
+        assert!(!timeline.is_muted(track_id)?);
+        assert!(!timeline.active_track_ids.contains(&new_track_id));
 
         Ok(())
     }
@@ -1,5 +1,12 @@
-use crate::engine::{AudioError, Clip};
-use std::{fmt::Display, ops::Add, path::Path};
+use crate::engine::{
+    AudioError, ChannelMap, Clip, Comp, CompSegment, MonitorMode, PitchPoint, PitchTracker,
+    ResamplerQuality,
+};
+use std::{
+    fmt::Display,
+    ops::{Add, Range},
+    path::Path,
+};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct TrackId(pub u32);
@@ -40,8 +47,14 @@ pub struct Track {
     pub name: String,
     volume: f32,
     clips: Vec<Clip>,
+    takes: Vec<Clip>,
+    // Per take, whether it was punched in and so crossfades with what it covers
+    punched: Vec<bool>,
+    comp: Comp,
+    channel_map: Option<ChannelMap>,
     is_muted: bool,
     is_soloed: bool,
+    monitor_mode: MonitorMode,
 }
 
 impl Track {
@@ -57,23 +70,190 @@ impl Track {
         self.clips.len()
     }
 
+    pub fn clips(&self) -> &[Clip] {
+        &self.clips
+    }
+
+    // One take per lane, in the order they were recorded
+    pub fn takes(&self) -> &[Clip] {
+        &self.takes
+    }
+
+    pub fn take_count(&self) -> usize {
+        self.takes.len()
+    }
+
+    pub fn remove_take(&mut self, take: usize) -> Result<Clip, AudioError> {
+        if take >= self.takes.len() {
+            return Err(AudioError::TakeNotFound(take));
+        }
+        self.comp.remove_take(take);
+        self.punched.remove(take);
+        Ok(self.takes.remove(take))
+    }
+
+    pub fn comp(&self) -> &[CompSegment] {
+        self.comp.segments()
+    }
+
+    pub fn select_take(&mut self, take: usize, start: u64, end: u64) -> Result<(), AudioError> {
+        if take >= self.takes.len() {
+            return Err(AudioError::TakeNotFound(take));
+        }
+        if end <= start {
+            return Err(AudioError::InvalidCompRange { start, end });
+        }
+        self.comp.select(take, start, end);
+        Ok(())
+    }
+
+    pub fn clear_comp(&mut self) {
+        self.comp.clear();
+    }
+
+    pub fn set_comp_crossfade(&mut self, frames: u64) {
+        self.comp.set_crossfade_frames(frames);
+    }
+
+    pub fn get_mut_clip(&mut self, index: usize) -> Option<&mut Clip> {
+        self.clips.get_mut(index)
+    }
+
+    pub fn channel_map(&self) -> Option<&ChannelMap> {
+        self.channel_map.as_ref()
+    }
+
+    pub fn set_channel_map(&mut self, channel_map: ChannelMap) -> Result<(), AudioError> {
+        self.clips
+            .iter_mut()
+            .chain(self.takes.iter_mut())
+            .filter(|clip| clip.channels() == channel_map.source_channels())
+            .try_for_each(|clip| clip.set_channel_map(channel_map.clone()))?;
+        self.channel_map = Some(channel_map);
+        Ok(())
+    }
+
+    pub fn clear_channel_map(&mut self) {
+        self.clips
+            .iter_mut()
+            .chain(self.takes.iter_mut())
+            .for_each(|clip| clip.clear_channel_map());
+        self.channel_map = None;
+    }
+
+    // Without a comp the newest take covering the position plays over the older ones
     pub fn find_clip_at_playhead_position(&self, playhead_position: u64) -> Option<&Clip> {
-        self.clips.iter().find(|c| {
-            playhead_position >= c.start_time_in_samples()
-                && playhead_position < c.end_time_in_samples()
-        })
+        self.layers_at(playhead_position)
+            .next()
+            .map(|(clip, _)| clip)
+    }
+
+    // Top first, each with whether it is a punch take
+    fn layers_at(&self, playhead_position: u64) -> impl Iterator<Item = (&Clip, bool)> {
+        let takes = if self.comp.is_empty() {
+            self.takes.len()
+        } else {
+            0
+        };
+
+        self.takes[..takes]
+            .iter()
+            .zip(self.punched.iter().copied())
+            .rev()
+            .chain(self.clips.iter().map(|clip| (clip, false)))
+            .filter(move |(clip, _)| Self::contains(clip, playhead_position))
+    }
+
+    // Where a punch take starts or ends over the layer below it, the two crossfade over the
+    // comp crossfade length. Any other layer on top simply replaces what is below.
+    fn top_wins_at(&self, playhead_position: u64) -> [Option<(&Clip, f64)>; 2] {
+        let mut layers = self.layers_at(playhead_position);
+        let Some((top, punched)) = layers.next() else {
+            return [None, None];
+        };
+        let fade = self.comp.crossfade_frames();
+        let Some((below, _)) = layers.next().filter(|_| punched && fade > 0) else {
+            return [Some((top, 1.0)), None];
+        };
+
+        let mut x: f64 = 1.0;
+        if below.start_time_in_samples() < top.start_time_in_samples() {
+            x = x.min((playhead_position - top.start_time_in_samples()) as f64 / fade as f64);
+        }
+        if below.end_time_in_samples() > top.end_time_in_samples() {
+            x = x.min((top.end_time_in_samples() - playhead_position) as f64 / fade as f64);
+        }
+        if x >= 1.0 {
+            return [Some((top, 1.0)), None];
+        }
+
+        [
+            Some((top, Comp::fade_in(x))),
+            Some((below, Comp::fade_out(x))),
+        ]
+    }
+
+    // Everything that sounds at the position, with its gain. Where the comp selects a take,
+    // that take replaces the other lanes, crossfading at the switch points. Where the selected
+    // take has no audio or fades in from nothing, the clips underneath make up the rest.
+    pub fn clips_at_playhead_position(
+        &self,
+        playhead_position: u64,
+    ) -> impl Iterator<Item = (&Clip, f64)> {
+        let comp = self.comp.sources_at(playhead_position).map(|source| {
+            let (take, gain) = source?;
+            self.takes
+                .get(take)
+                .filter(|clip| Self::contains(clip, playhead_position))
+                .map(|clip| (clip, gain))
+        });
+        // The comp fades are equal power, so the clips below get the power that is left
+        let power: f64 = comp.iter().flatten().map(|(_, gain)| gain * gain).sum();
+        let rest = (1.0 - power).max(0.0).sqrt();
+        let fallback = if rest > 0.0 {
+            self.top_wins_at(playhead_position)
+                .map(|layer| layer.map(|(clip, gain)| (clip, gain * rest)))
+        } else {
+            [None, None]
+        };
+
+        comp.into_iter().chain(fallback).flatten()
+    }
+
+    fn contains(clip: &Clip, playhead_position: u64) -> bool {
+        playhead_position >= clip.start_time_in_samples()
+            && playhead_position < clip.end_time_in_samples()
+    }
+
+    pub fn pitch_track(&self, tracker: &PitchTracker) -> Result<Vec<PitchPoint>, AudioError> {
+        let mut points = Vec::new();
+        for clip in &self.clips {
+            let offset = clip.start_time_in_samples() as f64 / clip.sample_rate() as f64;
+            points.extend(
+                clip.pitch_track_with(tracker)?
+                    .into_iter()
+                    .map(|point| PitchPoint {
+                        time_seconds: point.time_seconds + offset,
+                        ..point
+                    }),
+            );
+        }
+
+        points.sort_by(|a, b| a.time_seconds.total_cmp(&b.time_seconds));
+        Ok(points)
     }
 
     pub fn duration_in_samples(&self) -> u64 {
         self.clips
             .iter()
+            .chain(&self.takes)
             .map(|clip| clip.end_time_in_samples())
             .max()
             .unwrap_or(0)
     }
 
     pub fn is_empty(&self) -> bool {
-        self.clips.is_empty()
+        self.clips.is_empty() && self.takes.is_empty()
     }
 
     pub fn volume(&self) -> f32 {
@@ -92,6 +272,14 @@ impl Track {
         self.is_soloed
     }
 
+    pub fn monitor_mode(&self) -> MonitorMode {
+        self.monitor_mode
+    }
+
+    pub fn set_monitor_mode(&mut self, monitor_mode: MonitorMode) {
+        self.monitor_mode = monitor_mode;
+    }
+
     pub fn unmute(&mut self) {
         self.is_muted = false;
     }
@@ -129,6 +317,7 @@ impl Track {
         &mut self,
         path: P,
         timeline_sample_rate: u32,
+        resampler_quality: ResamplerQuality,
     ) -> Result<(), AudioError> {
         let start_time_in_samples: u64 = if let Some(c) = self.clips.last() {
             c.start_time_in_samples() + c.sample_count() as u64 + 1
@@ -136,11 +325,106 @@ impl Track {
             0
         };
 
-        let clip = Clip::from_path(path, start_time_in_samples, timeline_sample_rate)?;
+        let clip = Clip::from_path(
+            path,
+            start_time_in_samples,
+            timeline_sample_rate,
+            resampler_quality,
+        )?;
+
+        self.push_clip(clip)
+    }
+
+    // `previous_sample_rate` is the rate the track's positions are at now
+    pub fn with_sample_rate(
+        &self,
+        sample_rate: u32,
+        previous_sample_rate: u32,
+        resampler_quality: ResamplerQuality,
+    ) -> Result<Self, AudioError> {
+        let clips = self
+            .clips
+            .iter()
+            .map(|clip| clip.with_sample_rate(sample_rate, resampler_quality))
+            .collect::<Result<Vec<_>, _>>()?;
+        let takes = self
+            .takes
+            .iter()
+            .map(|take| take.with_sample_rate(sample_rate, resampler_quality))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Track {
+            id: self.id,
+            name: self.name.clone(),
+            volume: self.volume,
+            clips,
+            takes,
+            punched: self.punched.clone(),
+            comp: self
+                .comp
+                .with_sample_rate(sample_rate, previous_sample_rate),
+            channel_map: self.channel_map.clone(),
+            is_muted: self.is_muted,
+            is_soloed: self.is_soloed,
+            monitor_mode: self.monitor_mode,
+        })
+    }
+
+    pub fn split_clip(&mut self, index: usize, frame: u64) -> Result<(), AudioError> {
+        let clip = self
+            .clips
+            .get(index)
+            .ok_or(AudioError::ClipNotFound(index))?;
+        let (first, second) = clip.split_at(frame)?;
+
+        self.clips[index] = first;
+        self.clips.insert(index + 1, second);
 
+        Ok(())
+    }
+
+    pub fn push_clip(&mut self, mut clip: Clip) -> Result<(), AudioError> {
+        self.apply_channel_map(&mut clip)?;
         self.clips.push(clip);
         Ok(())
     }
+
+    pub fn push_take(&mut self, mut take: Clip) -> Result<(), AudioError> {
+        self.apply_channel_map(&mut take)?;
+        self.takes.push(take);
+        self.punched.push(false);
+        Ok(())
+    }
+
+    // `punch` is between the punch points; the take runs past them by the crossfade handles.
+    // Once there is a comp, the take is selected over the punch range so it is heard.
+    pub fn push_punch_take(&mut self, take: Clip, punch: Range<u64>) -> Result<(), AudioError> {
+        if punch.end <= punch.start {
+            return Err(AudioError::InvalidPunchRange {
+                start: punch.start,
+                end: punch.end,
+            });
+        }
+
+        self.push_take(take)?;
+        if let Some(punched) = self.punched.last_mut() {
+            *punched = true;
+        }
+        if !self.comp.is_empty() {
+            self.comp
+                .select(self.takes.len() - 1, punch.start, punch.end);
+        }
+        Ok(())
+    }
+
+    fn apply_channel_map(&self, clip: &mut Clip) -> Result<(), AudioError> {
+        match &self.channel_map {
+            Some(channel_map) if channel_map.source_channels() == clip.channels() => {
+                clip.set_channel_map(channel_map.clone())
+            }
+            _ => Ok(()),
+        }
+    }
 }
 
 impl Default for Track {
@@ -149,8 +433,13 @@ impl Default for Track {
             id: TrackId(1),
             volume: 1.0,
             clips: Vec::new(),
+            takes: Vec::new(),
+            punched: Vec::new(),
+            comp: Comp::default(),
+            channel_map: None,
             is_muted: false,
             is_soloed: false,
+            monitor_mode: MonitorMode::default(),
             name: "Default Track".into(),
         }
     }
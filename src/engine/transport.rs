@@ -0,0 +1,242 @@
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender, TrySendError};
+
+use crate::engine::TrackId;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TransportState {
+    #[default]
+    Stopped,
+    Playing,
+    Paused,
+    Recording,
+}
+
+impl TransportState {
+    pub fn is_rolling(self) -> bool {
+        matches!(self, TransportState::Playing | TransportState::Recording)
+    }
+}
+
+// Sent to the timeline from other threads, applied at the start of the next buffer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransportCommand {
+    Play,
+    Pause,
+    Stop,
+    Seek(u64),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TransportEvent {
+    StateChanged(TransportState),
+    // Playhead position in samples, sent every position interval while rolling
+    Position(u64),
+    ReachedEnd,
+    RecordingStarted(TrackId),
+    RecordingStopped(TrackId),
+    // The output callback came late enough that the device must have run dry
+    Xrun,
+    StreamError(String),
+    DeviceDisconnected,
+}
+
+// What happens when playback runs past the last clip
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EndAction {
+    #[default]
+    Stop,
+    Loop,
+}
+
+pub struct Transport {
+    state: TransportState,
+    // Where the audio that has to be faded out continues from
+    tail: Option<u64>,
+    fade_in: u64,
+    since_position: u64,
+    sender: Sender<TransportCommand>,
+    commands: Receiver<TransportCommand>,
+    subscribers: Vec<SyncSender<TransportEvent>>,
+}
+
+impl Transport {
+    // Events a subscriber can fall behind by before newer ones are dropped
+    const EVENT_CAPACITY: usize = 256;
+
+    pub fn state(&self) -> TransportState {
+        self.state
+    }
+
+    pub fn set_state(&mut self, state: TransportState) {
+        if state != self.state {
+            self.state = state;
+            self.emit(TransportEvent::StateChanged(state));
+        }
+    }
+
+    pub fn sender(&self) -> Sender<TransportCommand> {
+        self.sender.clone()
+    }
+
+    // One at a time, so the output callback can drain them without allocating
+    pub fn next_command(&self) -> Option<TransportCommand> {
+        self.commands.try_recv().ok()
+    }
+
+    pub fn subscribe(&mut self) -> Receiver<TransportEvent> {
+        let (sender, receiver) = mpsc::sync_channel(Self::EVENT_CAPACITY);
+        self.subscribers.push(sender);
+        receiver
+    }
+
+    // Never blocks the audio thread: a full subscriber misses the event, a gone one is dropped
+    pub fn emit(&mut self, event: TransportEvent) {
+        self.subscribers.retain(|subscriber| {
+            !matches!(
+                subscriber.try_send(event.clone()),
+                Err(TrySendError::Disconnected(_))
+            )
+        });
+    }
+
+    // Counts rendered frames and reports the position once every `interval` of them
+    pub fn advance(&mut self, frames: u64, position: u64, interval: u64) {
+        self.since_position += frames;
+        if self.since_position >= interval {
+            self.since_position = 0;
+            self.emit(TransportEvent::Position(position));
+        }
+    }
+
+    // Keeps the first position when several jumps land before the fade is rendered
+    pub fn fade_out_from(&mut self, position: u64) {
+        if self.state.is_rolling() && self.tail.is_none() {
+            self.tail = Some(position);
+        }
+    }
+
+    pub fn take_tail(&mut self) -> Option<u64> {
+        self.tail.take()
+    }
+
+    pub fn is_fading_out(&self) -> bool {
+        self.tail.is_some()
+    }
+
+    pub fn fade_in(&mut self, frames: u64) {
+        self.fade_in = frames;
+    }
+
+    // Gain ramp for the frames still fading in, advancing the ramp as it goes
+    pub fn apply_fade_in(&mut self, buffer: &mut [f64], channels: usize, fade_frames: u64) {
+        let frames = (buffer.len() / channels) as u64;
+        let faded = self.fade_in.min(frames);
+
+        for frame in 0..faded {
+            let gain = (fade_frames - self.fade_in + frame) as f64 / fade_frames as f64;
+            let start = frame as usize * channels;
+            buffer[start..start + channels]
+                .iter_mut()
+                .for_each(|sample| *sample *= gain);
+        }
+
+        self.fade_in -= faded;
+    }
+
+    pub fn apply_fade_out(buffer: &mut [f64], channels: usize) {
+        let frames = buffer.len() / channels;
+
+        for (frame, samples) in buffer.chunks_exact_mut(channels).enumerate() {
+            let gain = 1.0 - (frame + 1) as f64 / frames as f64;
+            samples.iter_mut().for_each(|sample| *sample *= gain);
+        }
+    }
+}
+
+impl Default for Transport {
+    fn default() -> Self {
+        let (sender, commands) = mpsc::channel();
+        Transport {
+            state: TransportState::default(),
+            tail: None,
+            fade_in: 0,
+            since_position: 0,
+            sender,
+            commands,
+            subscribers: Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fade_in_spans_buffers() {
+        let mut transport = Transport::default();
+        transport.fade_in(4);
+
+        let mut first = vec![1.0; 2 * 3];
+        transport.apply_fade_in(&mut first, 2, 4);
+        let mut second = vec![1.0; 2 * 3];
+        transport.apply_fade_in(&mut second, 2, 4);
+
+        assert_eq!(first, [0.0, 0.0, 0.25, 0.25, 0.5, 0.5]);
+        assert_eq!(second, [0.75, 0.75, 1.0, 1.0, 1.0, 1.0]);
+    }
+
+    #[test]
+    fn test_events_reach_subscribers() {
+        let mut transport = Transport::default();
+        let events = transport.subscribe();
+        drop(transport.subscribe());
+
+        transport.set_state(TransportState::Playing);
+        transport.set_state(TransportState::Playing);
+        transport.emit(TransportEvent::ReachedEnd);
+
+        assert_eq!(
+            events.try_iter().collect::<Vec<_>>(),
+            [
+                TransportEvent::StateChanged(TransportState::Playing),
+                TransportEvent::ReachedEnd
+            ]
+        );
+        assert_eq!(transport.subscribers.len(), 1);
+    }
+
+    #[test]
+    fn test_full_subscriber_misses_events() {
+        let mut transport = Transport::default();
+        let events = transport.subscribe();
+
+        for position in 0..Transport::EVENT_CAPACITY as u64 + 10 {
+            transport.emit(TransportEvent::Position(position));
+        }
+
+        assert_eq!(transport.subscribers.len(), 1);
+        assert_eq!(events.try_iter().count(), Transport::EVENT_CAPACITY);
+
+        transport.emit(TransportEvent::ReachedEnd);
+        assert_eq!(events.try_recv(), Ok(TransportEvent::ReachedEnd));
+    }
+
+    #[test]
+    fn test_position_every_interval() {
+        let mut transport = Transport::default();
+        let events = transport.subscribe();
+
+        for block in 1..=5 {
+            transport.advance(512, block * 512, 1000);
+        }
+
+        assert_eq!(
+            events.try_iter().collect::<Vec<_>>(),
+            [
+                TransportEvent::Position(1024),
+                TransportEvent::Position(2048)
+            ]
+        );
+    }
+}
@@ -59,8 +59,6 @@ impl Utils {
 
 #[cfg(test)]
 mod tests {
-    use std::i32;
-
     use super::*;
 
     #[test]
@@ -0,0 +1,281 @@
+use std::collections::VecDeque;
+
+use rubato::VecResampler;
+
+use crate::engine::{AudioError, Resampler, ResamplerQuality};
+
+// Tape-style rate change for a live stream: the timeline is rendered at its own pace and
+// pulled through a streaming resampler, so pitch follows the rate
+pub struct Varispeed {
+    resampler: Box<dyn VecResampler<f64>>,
+    channels: u16,
+    rate: f64,
+    input: Vec<Vec<f64>>,
+    output: Vec<Vec<f64>>,
+    interleaved: Vec<f64>,
+    pending: VecDeque<f64>,
+    // The resampler's delay in source frames, and the output frames still to be dropped to
+    // make up for it
+    delay: f64,
+    trim: usize,
+}
+
+impl Varispeed {
+    pub const MIN_RATE: f64 = 0.25;
+    pub const MAX_RATE: f64 = 4.0;
+
+    pub fn new(rate: f64, channels: u16, quality: ResamplerQuality) -> Result<Self, AudioError> {
+        Self::validate_rate(rate)?;
+
+        // The ratio is output over input, so playing faster means fewer output frames
+        let max_relative = Self::MAX_RATE / Self::MIN_RATE;
+        let resampler = Resampler::streaming(1.0 / rate, max_relative, channels, quality)?;
+        let delay = Resampler::streaming_delay(1.0 / rate, max_relative, quality)? as f64 * rate;
+
+        // Sized for the largest chunks at any rate, so processing never allocates
+        let (input_frames, output_frames) =
+            (resampler.input_frames_max(), resampler.output_frames_max());
+        let channels_len = channels as usize;
+
+        let mut varispeed = Varispeed {
+            output: resampler.output_buffer_allocate(true),
+            resampler,
+            channels,
+            rate,
+            input: (0..channels_len)
+                .map(|_| Vec::with_capacity(input_frames))
+                .collect(),
+            interleaved: Vec::with_capacity(input_frames * channels_len),
+            pending: VecDeque::with_capacity(output_frames * channels_len),
+            delay,
+            trim: 0,
+        };
+        // Started from the same state as after a reset, so the same delay applies
+        varispeed.reset()?;
+        Ok(varispeed)
+    }
+
+    fn delay_frames(&self) -> usize {
+        (self.delay / self.rate).round() as usize
+    }
+
+    pub fn validate_rate(rate: f64) -> Result<(), AudioError> {
+        if !(Self::MIN_RATE..=Self::MAX_RATE).contains(&rate) {
+            return Err(AudioError::InvalidPlaybackRate(rate));
+        }
+        Ok(())
+    }
+
+    pub fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    pub fn set_rate(&mut self, rate: f64) -> Result<(), AudioError> {
+        Self::validate_rate(rate)?;
+        self.resampler.set_resample_ratio(1.0 / rate, true)?;
+        self.rate = rate;
+        // Straight after a reset, the delay still to be dropped is at the new rate
+        if self.trim > 0 {
+            self.trim = self.delay_frames();
+        }
+        Ok(())
+    }
+
+    // Drops whatever is buffered, for when playback jumps. The boxed resampler can't be
+    // reset, so its history is flushed with silence instead, without allocating.
+    pub fn reset(&mut self) -> Result<(), AudioError> {
+        self.resampler.set_resample_ratio(1.0 / self.rate, false)?;
+
+        let flush = 2 * self.resampler.output_delay() + 1;
+        let mut flushed = 0;
+        while flushed < flush {
+            let frames = self.resampler.input_frames_next();
+            for input in &mut self.input {
+                input.clear();
+                input.resize(frames, 0.0);
+            }
+            let (_, produced) =
+                self.resampler
+                    .process_into_buffer(&self.input, &mut self.output, None)?;
+            flushed += produced;
+        }
+
+        self.pending.clear();
+        self.trim = self.delay_frames();
+        Ok(())
+    }
+
+    // Fills `output` (interleaved), asking `render` for as many source frames as the
+    // resampler needs along the way
+    pub fn process<F>(&mut self, output: &mut [f64], mut render: F) -> Result<(), AudioError>
+    where
+        F: FnMut(&mut [f64]),
+    {
+        let channels = self.channels as usize;
+        let mut written = 0;
+
+        // Pending output is used up before more is made, so it never holds more than a chunk
+        loop {
+            let samples = self.pending.len().min(output.len() - written);
+            output[written..written + samples]
+                .iter_mut()
+                .zip(self.pending.drain(..samples))
+                .for_each(|(sample, pending)| *sample = pending);
+            written += samples;
+            if written == output.len() {
+                return Ok(());
+            }
+
+            let frames = self.resampler.input_frames_next();
+
+            self.interleaved.clear();
+            self.interleaved.resize(frames * channels, 0.0);
+            render(&mut self.interleaved);
+
+            for (channel, input) in self.input.iter_mut().enumerate() {
+                input.clear();
+                input.extend(self.interleaved.iter().skip(channel).step_by(channels));
+            }
+
+            let (_, produced) =
+                self.resampler
+                    .process_into_buffer(&self.input, &mut self.output, None)?;
+
+            let trimmed = self.trim.min(produced);
+            self.trim -= trimmed;
+            for frame in trimmed..produced {
+                self.pending
+                    .extend(self.output.iter().map(|channel| channel[frame]));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_bounds() {
+        assert!(Varispeed::new(0.9, 2, ResamplerQuality::Balanced).is_ok());
+        assert!(matches!(
+            Varispeed::new(0.1, 2, ResamplerQuality::Balanced),
+            Err(AudioError::InvalidPlaybackRate(_))
+        ));
+        assert!(matches!(
+            Varispeed::new(f64::NAN, 2, ResamplerQuality::Balanced),
+            Err(AudioError::InvalidPlaybackRate(_))
+        ));
+    }
+
+    #[test]
+    fn test_reset_drops_buffered_audio() -> Result<(), AudioError> {
+        let mut varispeed = Varispeed::new(0.75, 1, ResamplerQuality::Balanced)?;
+        let mut output = vec![0.0; 300];
+
+        varispeed.process(&mut output, |input| input.fill(1.0))?;
+        varispeed.reset()?;
+        varispeed.process(&mut output, |input| input.fill(0.0))?;
+
+        assert!(output.iter().all(|sample| sample.abs() < 1e-9));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_consumes_source_at_rate() -> Result<(), AudioError> {
+        for rate in [0.5, 0.9, 1.5] {
+            let mut varispeed = Varispeed::new(rate, 2, ResamplerQuality::Balanced)?;
+            let mut consumed = 0;
+            let mut output = vec![0.0; 512 * 2];
+
+            for _ in 0..200 {
+                varispeed.process(&mut output, |input| {
+                    input.fill(0.5);
+                    consumed += input.len() / 2;
+                })?;
+            }
+
+            let expected = 200.0 * 512.0 * rate;
+            assert!(
+                (consumed as f64 - expected).abs() / expected < 0.02,
+                "{rate}: {consumed} vs {expected}"
+            );
+            assert!(output.iter().all(|sample| (sample - 0.5).abs() < 1e-2));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_output_lines_up_with_source() -> Result<(), AudioError> {
+        for rate in [0.5, 1.5] {
+            let mut varispeed = Varispeed::new(rate, 2, ResamplerQuality::Balanced)?;
+            let capacities = (
+                varispeed.input[0].capacity(),
+                varispeed.interleaved.capacity(),
+                varispeed.pending.capacity(),
+            );
+            let mut output = vec![0.0; 2 * 3000];
+
+            // A ramp of the source frame index, so each output frame says where it came from.
+            // Played from the start, then again after a reset.
+            for _ in 0..2 {
+                let mut position = 0usize;
+                varispeed.process(&mut output, |input| {
+                    for frame in input.chunks_mut(2) {
+                        frame.fill(position as f64);
+                        position += 1;
+                    }
+                })?;
+
+                for frame in [500, 1000, 2000] {
+                    let source = output[2 * frame];
+                    assert!(
+                        (source - frame as f64 * rate).abs() < 1.0,
+                        "{rate}: {source}"
+                    );
+                }
+                varispeed.reset()?;
+            }
+            assert_eq!(
+                capacities,
+                (
+                    varispeed.input[0].capacity(),
+                    varispeed.interleaved.capacity(),
+                    varispeed.pending.capacity(),
+                )
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_pitch_follows_rate() -> Result<(), AudioError> {
+        let sample_rate = 44100.0;
+        let mut varispeed = Varispeed::new(0.5, 1, ResamplerQuality::High)?;
+        let mut position = 0usize;
+        let mut output = vec![0.0; 8192];
+
+        varispeed.process(&mut output, |input| {
+            for sample in input.iter_mut() {
+                *sample =
+                    (2.0 * std::f64::consts::PI * 880.0 * position as f64 / sample_rate).sin();
+                position += 1;
+            }
+        })?;
+
+        // Count upward zero crossings after the resampler has settled
+        let settled = &output[2048..];
+        let crossings = settled
+            .windows(2)
+            .filter(|pair| pair[0] < 0.0 && pair[1] >= 0.0)
+            .count() as f64;
+        let frequency = crossings * sample_rate / settled.len() as f64;
+
+        assert!((frequency - 440.0).abs() < 10.0, "{frequency}");
+
+        Ok(())
+    }
+}
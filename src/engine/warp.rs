@@ -0,0 +1,340 @@
+// Both positions are frames from the start of the clip: where the audio is in the source,
+// and where it should sound on the timeline
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WarpMarker {
+    pub source_frame: u64,
+    pub timeline_frame: u64,
+}
+
+// Piecewise-linear map from source frames to timeline frames. An anchor at the origin is
+// implied unless a marker pins the first source frame, and the audio after the last marker
+// keeps its length. Everything is then scaled by the stretch factor.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WarpMap {
+    anchors: Vec<(f64, f64)>,
+}
+
+impl WarpMap {
+    pub fn new(markers: &[WarpMarker], stretch: f64, source_frames: u64) -> Option<Self> {
+        if !stretch.is_finite() || stretch <= 0.0 {
+            return None;
+        }
+
+        let mut anchors: Vec<(f64, f64)> = Vec::with_capacity(markers.len() + 2);
+        if markers.first().is_none_or(|marker| marker.source_frame > 0) {
+            anchors.push((0.0, 0.0));
+        }
+        anchors.extend(
+            markers
+                .iter()
+                .filter(|marker| marker.source_frame < source_frames)
+                .map(|marker| (marker.source_frame as f64, marker.timeline_frame as f64)),
+        );
+
+        let &(last_source, last_timeline) = anchors.last()?;
+        anchors.push((
+            source_frames as f64,
+            last_timeline + (source_frames as f64 - last_source),
+        ));
+
+        let increasing = anchors
+            .windows(2)
+            .all(|pair| pair[1].0 > pair[0].0 && pair[1].1 > pair[0].1);
+        if !increasing && source_frames > 0 {
+            return None;
+        }
+
+        anchors
+            .iter_mut()
+            .for_each(|(_, timeline)| *timeline *= stretch);
+
+        Some(WarpMap { anchors })
+    }
+
+    pub fn is_identity(&self) -> bool {
+        self.anchors
+            .iter()
+            .all(|(source, timeline)| (source - timeline).abs() < 0.5)
+    }
+
+    pub fn timeline_frames(&self) -> u64 {
+        self.anchors
+            .last()
+            .map_or(0, |(_, timeline)| timeline.round() as u64)
+    }
+
+    // Where a source frame plays on the timeline
+    pub fn timeline_position(&self, source_frame: f64) -> f64 {
+        let index = self
+            .anchors
+            .partition_point(|(source, _)| *source <= source_frame);
+
+        match (
+            index.checked_sub(1).map(|i| self.anchors[i]),
+            self.anchors.get(index),
+        ) {
+            (Some(before), Some(after)) => {
+                let position = (source_frame - before.0) / (after.0 - before.0);
+                before.1 + (after.1 - before.1) * position
+            }
+            (Some(before), None) => before.1 + (source_frame - before.0),
+            (None, _) => source_frame,
+        }
+    }
+
+    // Where in the source a timeline frame comes from, or None before the audio starts
+    pub fn source_position(&self, timeline_frame: f64) -> Option<f64> {
+        let index = self
+            .anchors
+            .partition_point(|(_, timeline)| *timeline <= timeline_frame);
+        let (before, after) = (
+            self.anchors.get(index.checked_sub(1)?)?,
+            self.anchors.get(index),
+        );
+
+        Some(match after {
+            Some(after) => {
+                let position = (timeline_frame - before.1) / (after.1 - before.1);
+                before.0 + (after.0 - before.0) * position
+            }
+            None => before.0 + (timeline_frame - before.1),
+        })
+    }
+}
+
+// WSOLA: overlap-add windows read from wherever the warp map points, each nudged within a
+// small tolerance to the offset that best continues the previous window. Pitch is kept
+// because the windows are played back at their original speed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TimeStretcher {
+    pub window_frames: usize,
+    pub tolerance_frames: usize,
+}
+
+impl Default for TimeStretcher {
+    fn default() -> Self {
+        // Around 20 ms windows at 44.1/48 kHz, long enough for the lowest voices
+        TimeStretcher {
+            window_frames: 1024,
+            tolerance_frames: 256,
+        }
+    }
+}
+
+impl TimeStretcher {
+    pub fn render(&self, data: &[f64], channels: u16, warp_map: &WarpMap) -> Vec<f64> {
+        let channel_count = channels.max(1) as usize;
+        let source_frames = data.len() / channel_count;
+        let output_frames = warp_map.timeline_frames() as usize;
+        let window_frames = self.window_frames.max(4) & !1;
+        let hop = window_frames / 2;
+        let tolerance = self.tolerance_frames as i64;
+
+        let mono: Vec<f64> = data
+            .chunks_exact(channel_count)
+            .map(|frame| frame.iter().sum::<f64>() / channel_count as f64)
+            .collect();
+        let window: Vec<f64> = (0..window_frames)
+            .map(|i| {
+                0.5 - 0.5 * (2.0 * std::f64::consts::PI * i as f64 / window_frames as f64).cos()
+            })
+            .collect();
+
+        let mut output = vec![0.0; output_frames * channel_count];
+        let mut weights = vec![0.0; output_frames];
+        let mut previous: Option<i64> = None;
+
+        // Windows are centred on the timeline frame they render
+        for output_start in (0..output_frames + hop).step_by(hop) {
+            let centre = output_start as f64 - hop as f64 + window_frames as f64 / 2.0;
+            let Some(position) = warp_map.source_position(centre) else {
+                previous = None;
+                continue;
+            };
+            let nominal = (position - window_frames as f64 / 2.0).round() as i64;
+
+            let source_start = match previous {
+                Some(previous) => {
+                    let natural = previous + hop as i64;
+                    (nominal - tolerance..=nominal + tolerance)
+                        .map(|candidate| {
+                            (candidate, Self::similarity(&mono, natural, candidate, hop))
+                        })
+                        .max_by(|a, b| {
+                            a.1.total_cmp(&b.1)
+                                .then_with(|| (b.0 - nominal).abs().cmp(&(a.0 - nominal).abs()))
+                        })
+                        .map_or(nominal, |(candidate, _)| candidate)
+                }
+                None => nominal,
+            };
+            previous = Some(source_start);
+
+            let target_start = output_start as i64 - hop as i64;
+            for (offset, weight) in window.iter().enumerate() {
+                let target = target_start + offset as i64;
+                let source = source_start + offset as i64;
+                if target < 0 || target >= output_frames as i64 {
+                    continue;
+                }
+                let target = target as usize;
+                weights[target] += weight;
+
+                if source < 0 || source >= source_frames as i64 {
+                    continue;
+                }
+                let source = source as usize;
+                for channel in 0..channel_count {
+                    output[target * channel_count + channel] +=
+                        data[source * channel_count + channel] * weight;
+                }
+            }
+        }
+
+        for (frame, weight) in weights.iter().enumerate() {
+            for channel in 0..channel_count {
+                let sample = &mut output[frame * channel_count + channel];
+                *sample = if *weight > 1e-3 {
+                    *sample / weight
+                } else {
+                    0.0
+                };
+            }
+        }
+
+        output
+    }
+
+    // Normalised by the candidate's energy, so an exact continuation always scores highest
+    fn similarity(samples: &[f64], a: i64, b: i64, length: usize) -> f64 {
+        let sample = |position: i64| {
+            usize::try_from(position)
+                .ok()
+                .and_then(|position| samples.get(position))
+                .copied()
+                .unwrap_or_default()
+        };
+
+        let (dot, energy) = (0..length as i64).fold((0.0, 0.0), |(dot, energy), offset| {
+            let candidate = sample(b + offset);
+            (
+                dot + sample(a + offset) * candidate,
+                energy + candidate * candidate,
+            )
+        });
+
+        if energy > 0.0 {
+            dot / energy.sqrt()
+        } else {
+            0.0
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::PitchTracker;
+
+    const SAMPLE_RATE: u32 = 44100;
+
+    fn sine(frequency: f64, frames: usize) -> Vec<f64> {
+        (0..frames)
+            .map(|i| {
+                0.5 * (2.0 * std::f64::consts::PI * frequency * i as f64 / SAMPLE_RATE as f64).sin()
+            })
+            .collect()
+    }
+
+    fn median_frequency(samples: &[f64]) -> f64 {
+        let mut frequencies: Vec<f64> = PitchTracker::default()
+            .track(samples, SAMPLE_RATE)
+            .unwrap()
+            .iter()
+            .filter_map(|point| point.frequency)
+            .collect();
+        frequencies.sort_by(f64::total_cmp);
+        frequencies[frequencies.len() / 2]
+    }
+
+    fn marker(source_frame: u64, timeline_frame: u64) -> WarpMarker {
+        WarpMarker {
+            source_frame,
+            timeline_frame,
+        }
+    }
+
+    #[test]
+    fn test_warp_map_without_markers() {
+        let map = WarpMap::new(&[], 1.0, 1000).unwrap();
+        assert!(map.is_identity());
+        assert_eq!(map.timeline_frames(), 1000);
+
+        let map = WarpMap::new(&[], 1.5, 1000).unwrap();
+        assert!(!map.is_identity());
+        assert_eq!(map.timeline_frames(), 1500);
+        assert_eq!(map.source_position(750.0), Some(500.0));
+    }
+
+    #[test]
+    fn test_warp_map_with_markers() {
+        let map = WarpMap::new(&[marker(100, 200), marker(300, 300)], 1.0, 1000).unwrap();
+
+        assert_eq!(map.timeline_frames(), 1000);
+        assert_eq!(map.source_position(100.0), Some(50.0));
+        assert_eq!(map.source_position(250.0), Some(200.0));
+        assert_eq!(map.source_position(600.0), Some(600.0));
+    }
+
+    #[test]
+    fn test_warp_map_leading_gap() {
+        let map = WarpMap::new(&[marker(0, 100)], 1.0, 1000).unwrap();
+
+        assert_eq!(map.timeline_frames(), 1100);
+        assert_eq!(map.source_position(50.0), None);
+        assert_eq!(map.source_position(150.0), Some(50.0));
+    }
+
+    #[test]
+    fn test_warp_map_rejects_bad_input() {
+        assert!(WarpMap::new(&[marker(100, 200), marker(200, 150)], 1.0, 1000).is_none());
+        assert!(WarpMap::new(&[], 0.0, 1000).is_none());
+        assert!(WarpMap::new(&[], f64::NAN, 1000).is_none());
+    }
+
+    #[test]
+    fn test_stretch_keeps_pitch() {
+        let input = sine(220.0, 22050);
+
+        for stretch in [0.75, 1.5] {
+            let map = WarpMap::new(&[], stretch, input.len() as u64).unwrap();
+            let output = TimeStretcher::default().render(&input, 1, &map);
+
+            assert_eq!(
+                output.len(),
+                (input.len() as f64 * stretch).round() as usize
+            );
+            let frequency = median_frequency(&output);
+            assert!(
+                (1200.0 * (frequency / 220.0).log2()).abs() < 5.0,
+                "{stretch}: {frequency}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_identity_is_transparent() {
+        let input: Vec<f64> = sine(330.0, 8192)
+            .into_iter()
+            .flat_map(|sample| [sample, -sample])
+            .collect();
+        let map = WarpMap::new(&[], 1.0, 8192).unwrap();
+
+        let output = TimeStretcher::default().render(&input, 2, &map);
+
+        assert_eq!(output.len(), input.len());
+        for (a, b) in output.iter().zip(&input) {
+            assert!((a - b).abs() < 1e-9);
+        }
+    }
+}
@@ -1,5 +1,5 @@
 use cpal::{self, SampleFormat, SampleRate};
-use zari::engine::AudioEngine;
+use zari::engine::{AudioEngine, TransportEvent};
 
 fn main() -> Result<(), anyhow::Error> {
     let channels = 2;
@@ -14,13 +14,21 @@ fn main() -> Result<(), anyhow::Error> {
         timeline.add_clip(track_id_1, "sample-i24-stereo.wav")?;
     }
 
-    let duration = {
-        let timeline = timeline.lock().unwrap();
-        timeline.duration_in_seconds()
-    };
-
+    let events = audio_engine.subscribe()?;
     audio_engine.start_playing()?;
-    std::thread::sleep(std::time::Duration::from_secs(duration.ceil() as u64));
+
+    for event in events {
+        match event {
+            TransportEvent::ReachedEnd => break,
+            TransportEvent::DeviceDisconnected => {
+                eprintln!("Output device disconnected");
+                break;
+            }
+            TransportEvent::StreamError(err) => eprintln!("Stream error: {err}"),
+            TransportEvent::Xrun => eprintln!("Buffer underrun"),
+            _ => {}
+        }
+    }
 
     Ok(())
 }